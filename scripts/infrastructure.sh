@@ -57,6 +57,38 @@ deploy_service() {
 	done
 }
 
+# alternative to node_exporter, deploys this binary in agent mode (same service name, port 9100)
+# build first: cargo build --release && cross build --release --target armv7-unknown-linux-gnueabihf
+create_agent_configs() {
+tee config/${MS}.service <<EOF
+[Unit]
+Description=${MS}
+
+[Service]
+ExecStart=/home/${USER}/microservices/node-metrics-tui agent --listen 0.0.0.0:9100
+Restart=Always
+PIDFile=/tmp/${MS}_pid
+EOF
+}
+
+deploy_agent() {
+	for host in "${ARMV7_IP[@]}"; do
+		ssh -i "${PK}" "${USER}@${host}" -t "mkdir -p /home/${USER}/microservices"
+		scp -i "${PK}" "./config/${MS}.service" "${USER}@${host}:/home/${USER}/microservices"
+		scp -i "${PK}" "./target/armv7-unknown-linux-gnueabihf/release/node-metrics-tui" "${USER}@${host}:/home/${USER}/microservices"
+		ssh -i "${PK}" "${USER}@${host}" -t "sudo cp /home/${USER}/microservices/${MS}.service /etc/systemd/system/"
+	done
+
+	for host in "${AMD64_IP[@]}"; do
+		ssh -i "${PK}" "${USER}@${host}" -t "mkdir -p /home/${USER}/microservices"
+		scp -i "${PK}" "./config/${MS}.service" "${USER}@${host}:/home/${USER}/microservices"
+		scp -i "${PK}" "./target/release/node-metrics-tui" "${USER}@${host}:/home/${USER}/microservices"
+		# on fedora ensure SELinux executable has bin_t
+		ssh -i "${PK}" "${USER}@${host}" -t "sudo chcon -t bin-t /home/${USER}/microservices/node-metrics-tui"
+		ssh -i "${PK}" "${USER}@${host}" -t "sudo cp /home/${USER}/microservices/${MS}.service /etc/systemd/system/"
+	done
+}
+
 start_service() {
 	# example 
 	# ssh -i ~/.ssh/id_ed25519-lz lzuccarelli@192.168.1.203 -t "sudo systemctl daemon-reload && sudo systemctl start node-exporter.service"
@@ -1,5 +1,6 @@
 // module schema
-use clap::Parser;
+use crate::config::load::ExportFormat;
+use clap::{Parser, Subcommand};
 
 /// cli struct
 #[derive(Parser, Debug)]
@@ -10,8 +11,77 @@ use clap::Parser;
 #[command(
     help_template = "{author-with-newline} {about-section}Version: {version} \n {usage-heading} {usage} \n {all-args} {tab}"
 )]
+#[command(subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// config file to use
-    #[arg(short, long, value_name = "config")]
-    pub config: String,
+    #[arg(
+        short,
+        long,
+        value_name = "config",
+        required_unless_present = "from_file"
+    )]
+    pub config: Option<String>,
+
+    /// view saved exposition files instead of (or next to) the configured servers, - reads stdin
+    #[arg(short, long, value_name = "file")]
+    pub from_file: Vec<String>,
+
+    /// persist the history in this directory and reload it on start (overrides the config)
+    #[arg(short, long, value_name = "dir")]
+    pub data_dir: Option<String>,
+
+    /// record every raw scrape into a capture file (overrides the config)
+    #[arg(short, long, value_name = "file")]
+    pub record: Option<String>,
+}
+
+/// subcommands, without one the tui is started
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// serve the local /proc and /sys metrics in prometheus text format (node_exporter replacement)
+    Agent {
+        /// address to listen on
+        #[arg(short, long, value_name = "address", default_value = "0.0.0.0:9100")]
+        listen: String,
+
+        /// path the metrics are served on
+        #[arg(short, long, value_name = "path", default_value = "/metrics")]
+        metrics_path: String,
+    },
+
+    /// replay a capture file (see --record) in the tui
+    Replay {
+        /// capture file to replay
+        file: String,
+
+        /// playback speed, 1 is real time and 0 sends every scrape at once
+        #[arg(short, long, value_name = "factor", default_value_t = 1.0)]
+        speed: f64,
+
+        /// advance one scrape at a time with n
+        #[arg(long)]
+        step: bool,
+    },
+
+    /// scrape the configured nodes once and write a snapshot (json, csv or markdown)
+    Export {
+        /// only this node (url or name)
+        #[arg(short, long, value_name = "node")]
+        node: Option<String>,
+
+        /// output format (overrides the config)
+        #[arg(long, value_enum)]
+        format: Option<ExportFormat>,
+
+        /// output directory (overrides the config)
+        #[arg(short, long, value_name = "dir")]
+        output: Option<String>,
+
+        /// seconds between the two scrapes the rates are computed from
+        #[arg(short, long, value_name = "secs", default_value_t = 2)]
+        interval: u64,
+    },
 }
@@ -1,5 +1,8 @@
+use crate::error::generic::GenericError;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
+use std::path::Path;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Parameters {
@@ -7,11 +10,615 @@ pub struct Parameters {
     pub description: String,
     pub log_level: String,
     pub scrape_duration: u64,
-    pub servers: Vec<String>,
+    #[serde(default)]
+    pub servers: Vec<Target>,
+    /// prometheus style file based discovery, merged with `servers`
+    #[serde(default)]
+    pub file_sd_configs: Vec<FileSdConfig>,
+    /// dns SRV / A / AAAA based discovery, merged with `servers`
+    #[serde(default)]
+    pub dns_sd_configs: Vec<DnsSdConfig>,
+    /// read node data from a prometheus server instead of scraping the exporters
+    pub prometheus: Option<PrometheusConfig>,
+    #[serde(default)]
+    pub mode: ScrapeMode,
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    #[serde(default)]
+    pub scrape: ScrapeConfig,
+    /// bounds of the in-memory history of every node
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// where and how the export key writes snapshots
+    #[serde(default)]
+    pub export: ExportConfig,
+    /// directory the history is persisted in (reloaded on start), not persisted when unset
+    pub data_dir: Option<String>,
+    /// capture file every raw scrape is recorded to, for `replay`
+    pub record: Option<String>,
+    /// forward every scrape to a prometheus remote_write endpoint
+    pub remote_write: Option<RemoteWriteConfig>,
+    /// forward every scrape to influxdb (or anything that takes the line protocol)
+    pub influxdb: Option<InfluxConfig>,
+    /// forward every scrape to an opentelemetry collector
+    pub otlp: Option<OtlpConfig>,
+    /// per target overrides of the scrape settings, keyed by server url
+    #[serde(default)]
+    pub target_overrides: HashMap<String, TargetOverride>,
+    /// defaults for every target, overridden per target
+    pub tls_config: Option<TlsConfig>,
+    pub basic_auth: Option<BasicAuth>,
+    pub authorization: Option<Authorization>,
+}
+
+impl Parameters {
+    /// settings used when no config file is given (offline viewing)
+    pub fn offline(servers: Vec<Target>) -> Self {
+        Parameters {
+            name: "offline".to_string(),
+            description: "saved scrapes".to_string(),
+            log_level: "info".to_string(),
+            scrape_duration: 5,
+            servers,
+            file_sd_configs: vec![],
+            dns_sd_configs: vec![],
+            prometheus: None,
+            mode: ScrapeMode::Fleet,
+            concurrency: default_concurrency(),
+            scrape: ScrapeConfig::default(),
+            history: HistoryConfig::default(),
+            export: ExportConfig::default(),
+            data_dir: None,
+            record: None,
+            remote_write: None,
+            influxdb: None,
+            otlp: None,
+            target_overrides: HashMap::new(),
+            tls_config: None,
+            basic_auth: None,
+            authorization: None,
+        }
+    }
+
+    /// overrides for a target, values set on the target itself win over `target_overrides`
+    fn overrides(&self, target: &Target) -> TargetOverride {
+        match self.target_overrides.get(&target.url) {
+            Some(keyed) => target.overrides.or(keyed),
+            None => target.overrides.clone(),
+        }
+    }
+
+    /// resolve the scrape settings for a target (global defaults plus any override)
+    pub fn scrape_config(&self, target: &Target) -> ScrapeConfig {
+        self.scrape.merge(&self.overrides(target))
+    }
+
+    /// resolve the tls and auth settings for a target (per target values win)
+    pub fn security_config(&self, target: &Target) -> SecurityConfig {
+        let o = self.overrides(target);
+        SecurityConfig {
+            tls_config: o.tls_config.or(self.tls_config.clone()),
+            basic_auth: o.basic_auth.or(self.basic_auth.clone()),
+            authorization: o.authorization.or(self.authorization.clone()),
+        }
+    }
+
+    /// enabled targets in config order
+    pub fn enabled_targets(&self) -> Vec<Target> {
+        self.servers.iter().filter(|x| x.enabled).cloned().collect()
+    }
+}
+
+/// a scrape target, the config accepts either a bare url string or the full object
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "TargetEntry")]
+pub struct Target {
+    pub url: String,
+    /// display name (alias) used in the node list
+    pub name: Option<String>,
+    pub metrics_path: String,
+    /// free form labels (site, rack, arch ...) used for grouping and filtering
+    pub labels: BTreeMap<String, String>,
+    /// overrides `scrape_duration` for this target
+    pub scrape_interval: Option<u64>,
+    pub enabled: bool,
+    /// query parameters added to the scrape request, e.g. `match[]` for `/federate`
+    pub params: BTreeMap<String, Vec<String>>,
+    /// split the scraped series into one node per `instance` label (federation)
+    pub split_by_instance: bool,
+    /// set on nodes that are not scraped themselves, the url their data is read through
+    #[serde(skip)]
+    pub via: Option<String>,
+    #[serde(flatten)]
+    pub overrides: TargetOverride,
+}
+
+impl Target {
+    pub fn from_url(url: &str) -> Self {
+        Target {
+            url: url.to_string(),
+            name: None,
+            metrics_path: default_metrics_path(),
+            labels: BTreeMap::new(),
+            scrape_interval: None,
+            enabled: true,
+            params: BTreeMap::new(),
+            split_by_instance: false,
+            via: None,
+            overrides: TargetOverride::default(),
+        }
+    }
+
+    /// alias if set, otherwise the host:port part of the url (the url itself when empty, `local://`)
+    pub fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self
+                .url
+                .split("://")
+                .nth(1)
+                .filter(|x| !x.is_empty())
+                .unwrap_or(&self.url)
+                .to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TargetEntry {
+    Url(String),
+    Full(Box<TargetSpec>),
+}
+
+#[derive(Deserialize)]
+struct TargetSpec {
+    url: String,
+    name: Option<String>,
+    #[serde(default = "default_metrics_path")]
+    metrics_path: String,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    scrape_interval: Option<u64>,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(default)]
+    params: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    split_by_instance: bool,
+    #[serde(flatten)]
+    overrides: TargetOverride,
+}
+
+impl From<TargetEntry> for Target {
+    fn from(entry: TargetEntry) -> Self {
+        match entry {
+            TargetEntry::Url(url) => Target::from_url(&url),
+            TargetEntry::Full(spec) => Target {
+                url: spec.url,
+                name: spec.name,
+                metrics_path: spec.metrics_path,
+                labels: spec.labels,
+                scrape_interval: spec.scrape_interval,
+                enabled: spec.enabled,
+                params: spec.params,
+                split_by_instance: spec.split_by_instance,
+                via: None,
+                overrides: spec.overrides,
+            },
+        }
+    }
+}
+
+fn default_metrics_path() -> String {
+    "/metrics".to_string()
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// modelled on the prometheus `tls_config` block
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TlsConfig {
+    /// pem bundle used to verify the exporter certificate
+    pub ca_file: Option<String>,
+    /// client certificate and pkcs8 key (pem) for mtls
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+/// modelled on the prometheus `basic_auth` block
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: Option<String>,
+    pub password_file: Option<String>,
+}
+
+/// modelled on the prometheus `authorization` block (credentials from the config, a file or an env var)
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Authorization {
+    #[serde(rename = "type", default = "default_auth_type")]
+    pub auth_type: String,
+    pub credentials: Option<String>,
+    pub credentials_file: Option<String>,
+    pub credentials_env: Option<String>,
+}
+
+fn default_auth_type() -> String {
+    "Bearer".to_string()
+}
+
+/// resolved tls and auth settings of a target
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SecurityConfig {
+    pub tls_config: Option<TlsConfig>,
+    pub basic_auth: Option<BasicAuth>,
+    pub authorization: Option<Authorization>,
+}
+
+/// http timeouts, retry and circuit breaker settings
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ScrapeConfig {
+    pub connect_timeout_ms: u64,
+    pub timeout_ms: u64,
+    pub retries: u32,
+    pub backoff_base_ms: u64,
+    pub backoff_max_ms: u64,
+    /// consecutive failures before the breaker opens
+    pub breaker_threshold: u32,
+    /// first open period, doubled after every failed probe up to the max
+    pub breaker_cooldown_secs: u64,
+    pub breaker_max_cooldown_secs: u64,
+}
+
+impl Default for ScrapeConfig {
+    fn default() -> Self {
+        ScrapeConfig {
+            connect_timeout_ms: 2000,
+            timeout_ms: 5000,
+            retries: 2,
+            backoff_base_ms: 250,
+            backoff_max_ms: 2000,
+            breaker_threshold: 3,
+            breaker_cooldown_secs: 30,
+            breaker_max_cooldown_secs: 300,
+        }
+    }
+}
+
+impl ScrapeConfig {
+    fn merge(&self, o: &TargetOverride) -> ScrapeConfig {
+        ScrapeConfig {
+            connect_timeout_ms: o.connect_timeout_ms.unwrap_or(self.connect_timeout_ms),
+            timeout_ms: o.timeout_ms.unwrap_or(self.timeout_ms),
+            retries: o.retries.unwrap_or(self.retries),
+            backoff_base_ms: o.backoff_base_ms.unwrap_or(self.backoff_base_ms),
+            backoff_max_ms: o.backoff_max_ms.unwrap_or(self.backoff_max_ms),
+            breaker_threshold: o.breaker_threshold.unwrap_or(self.breaker_threshold),
+            breaker_cooldown_secs: o
+                .breaker_cooldown_secs
+                .unwrap_or(self.breaker_cooldown_secs),
+            breaker_max_cooldown_secs: o
+                .breaker_max_cooldown_secs
+                .unwrap_or(self.breaker_max_cooldown_secs),
+        }
+    }
+}
+
+/// optional per target values, anything not set falls back to `scrape`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TargetOverride {
+    pub connect_timeout_ms: Option<u64>,
+    pub timeout_ms: Option<u64>,
+    pub retries: Option<u32>,
+    pub backoff_base_ms: Option<u64>,
+    pub backoff_max_ms: Option<u64>,
+    pub breaker_threshold: Option<u32>,
+    pub breaker_cooldown_secs: Option<u64>,
+    pub breaker_max_cooldown_secs: Option<u64>,
+    pub tls_config: Option<TlsConfig>,
+    pub basic_auth: Option<BasicAuth>,
+    pub authorization: Option<Authorization>,
+}
+
+impl TargetOverride {
+    /// field wise merge, values set on self win
+    pub fn or(&self, other: &TargetOverride) -> TargetOverride {
+        TargetOverride {
+            connect_timeout_ms: self.connect_timeout_ms.or(other.connect_timeout_ms),
+            timeout_ms: self.timeout_ms.or(other.timeout_ms),
+            retries: self.retries.or(other.retries),
+            backoff_base_ms: self.backoff_base_ms.or(other.backoff_base_ms),
+            backoff_max_ms: self.backoff_max_ms.or(other.backoff_max_ms),
+            breaker_threshold: self.breaker_threshold.or(other.breaker_threshold),
+            breaker_cooldown_secs: self.breaker_cooldown_secs.or(other.breaker_cooldown_secs),
+            breaker_max_cooldown_secs: self
+                .breaker_max_cooldown_secs
+                .or(other.breaker_max_cooldown_secs),
+            tls_config: self.tls_config.clone().or(other.tls_config.clone()),
+            basic_auth: self.basic_auth.clone().or(other.basic_auth.clone()),
+            authorization: self.authorization.clone().or(other.authorization.clone()),
+        }
+    }
+}
+
+/// file based service discovery source (json, `*` and `?` are allowed in the file name)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FileSdConfig {
+    pub files: Vec<String>,
+    /// files are re-read when they change, and at least this often (seconds)
+    #[serde(default = "default_refresh_interval")]
+    pub refresh_interval: u64,
+}
+
+/// a group in a file_sd file, `[{ "targets": [...], "labels": {...} }]`
+#[derive(Deserialize, Clone, Debug)]
+struct FileSdGroup {
+    targets: Vec<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+fn default_refresh_interval() -> u64 {
+    300
+}
+
+/// dns based service discovery source (prometheus style `dns_sd_configs`)
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DnsSdConfig {
+    pub names: Vec<String>,
+    #[serde(rename = "type", default)]
+    pub record_type: DnsRecordType,
+    /// required for A and AAAA records, SRV records carry their own port
+    pub port: Option<u16>,
+    /// names are re-resolved this often (seconds)
+    #[serde(default = "default_dns_refresh_interval")]
+    pub refresh_interval: u64,
+    /// `host[:port]` of the resolver, defaults to the first nameserver in /etc/resolv.conf
+    pub nameserver: Option<String>,
+    #[serde(default = "default_scheme")]
+    pub scheme: String,
+    #[serde(default = "default_metrics_path")]
+    pub metrics_path: String,
+    /// labels added to every discovered target
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum DnsRecordType {
+    #[default]
+    Srv,
+    A,
+    Aaaa,
+}
+
+/// prometheus http api source, nodes are the `instance` label values
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PrometheusConfig {
+    /// base url of the server, e.g. `http://prometheus:9090` (tls and auth via `target_overrides`)
+    pub url: String,
+    /// extra label matchers added to every query, e.g. `job="node"`
+    #[serde(default)]
+    pub matchers: String,
+    /// history loaded with `query_range` on startup (seconds, 0 disables)
+    #[serde(default = "default_backfill")]
+    pub backfill_secs: u64,
+    /// resolution of the backfill, defaults to `scrape_duration`
+    pub step_secs: Option<u64>,
+}
+
+fn default_backfill() -> u64 {
+    300
+}
+
+fn default_dns_refresh_interval() -> u64 {
+    30
+}
+
+fn default_scheme() -> String {
+    "http".to_string()
+}
+
+/// in-memory history limits, whichever is reached first applies
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HistoryConfig {
+    /// points older than this are dropped (seconds)
+    #[serde(default = "default_retention_secs")]
+    pub retention_secs: u64,
+    /// points kept per series
+    #[serde(default = "default_max_samples")]
+    pub max_samples: usize,
+    /// series kept per node, new series beyond this are not recorded
+    #[serde(default = "default_max_series")]
+    pub max_series: usize,
+    /// approximate memory cap for the whole store, the oldest points go first
+    #[serde(default = "default_max_memory_mb")]
+    pub max_memory_mb: usize,
+    /// downsampled copies kept longer than the raw points, e.g. 1m for a day and 10m for a week
+    #[serde(default)]
+    pub rollups: Vec<RollupConfig>,
+}
+
+/// a rollup tier, one min / max / avg bucket per `resolution_secs` kept for `retention_secs`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RollupConfig {
+    pub resolution_secs: u64,
+    pub retention_secs: u64,
+}
+
+impl HistoryConfig {
+    /// how long anything is kept, raw points or the longest rollup
+    pub fn longest_retention_secs(&self) -> u64 {
+        self.rollups
+            .iter()
+            .map(|x| x.retention_secs)
+            .fold(self.retention_secs, u64::max)
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            retention_secs: default_retention_secs(),
+            max_samples: default_max_samples(),
+            max_series: default_max_series(),
+            max_memory_mb: default_max_memory_mb(),
+            rollups: vec![],
+        }
+    }
+}
+
+fn default_retention_secs() -> u64 {
+    3600
+}
+
+fn default_max_samples() -> usize {
+    3600
+}
+
+fn default_max_series() -> usize {
+    10000
+}
+
+fn default_max_memory_mb() -> usize {
+    64
+}
+
+/// snapshot exports, written to `dir` as `snapshot-<node or all>-<time>.<format>`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExportConfig {
+    #[serde(default = "default_export_dir")]
+    pub dir: String,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        ExportConfig {
+            dir: default_export_dir(),
+            format: ExportFormat::default(),
+        }
+    }
+}
+
+fn default_export_dir() -> String {
+    ".".to_string()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Json,
+    Csv,
+    #[default]
+    Markdown,
+}
+
+/// prometheus remote_write output
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RemoteWriteConfig {
+    /// receiver url, e.g. `http://mimir:9009/api/v1/push` (tls and auth via `target_overrides`)
+    pub url: String,
+    #[serde(flatten)]
+    pub output: OutputConfig,
+}
+
+/// influxdb line protocol output, over http or udp
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InfluxConfig {
+    /// write url with its query, e.g. `http://influx:8086/api/v2/write?org=lab&bucket=nodes`
+    /// (v2, auth `Token` via `target_overrides`) or `http://vm:8428/write?db=nodes` (v1), or
+    /// `udp://host:8089`, timestamps are nanoseconds
+    pub url: String,
+    #[serde(flatten)]
+    pub output: OutputConfig,
+}
+
+/// opentelemetry otlp/http (protobuf) output
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OtlpConfig {
+    /// metrics url of the collector, e.g. `http://otel-collector:4318/v1/metrics`
+    pub url: String,
+    #[serde(flatten)]
+    pub output: OutputConfig,
+}
+
+/// labelling, batching and retry settings every output has
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OutputConfig {
+    /// labels added to every series that doesn't already have them
+    #[serde(default)]
+    pub external_labels: BTreeMap<String, String>,
+    /// `job` label of the forwarded series
+    #[serde(default = "default_job")]
+    pub job: String,
+    /// label renames (influxdb tags, otlp attributes), a label mapped to "" is dropped
+    #[serde(default)]
+    pub label_map: BTreeMap<String, String>,
+    /// samples per request
+    #[serde(default = "default_max_samples_per_send")]
+    pub max_samples_per_send: usize,
+    /// a partial batch is sent after this long (seconds)
+    #[serde(default = "default_batch_send_deadline")]
+    pub batch_send_deadline_secs: u64,
+    /// samples kept while the receiver is unreachable, the oldest are dropped beyond this
+    #[serde(default = "default_queue_capacity")]
+    pub capacity: usize,
+    /// retry backoff of a failed batch, doubled on every failure up to the max
+    #[serde(default = "default_min_backoff_ms")]
+    pub min_backoff_ms: u64,
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+fn default_job() -> String {
+    "node".to_string()
+}
+
+fn default_max_samples_per_send() -> usize {
+    2000
+}
+
+fn default_batch_send_deadline() -> u64 {
+    5
+}
+
+fn default_queue_capacity() -> usize {
+    100000
+}
+
+fn default_min_backoff_ms() -> u64 {
+    500
+}
+
+fn default_max_backoff_ms() -> u64 {
+    30000
+}
+
+/// single scrapes only the highlighted node (saves bandwidth), fleet scrapes every node
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScrapeMode {
+    #[default]
+    Single,
+    Fleet,
+}
+
+fn default_concurrency() -> usize {
+    4
 }
 
 pub trait ConfigInterface {
     fn read(&self, dir: String) -> Result<Parameters, Box<dyn std::error::Error>>;
+    fn read_file_sd(&self, name: &str) -> Result<Vec<Target>, Box<dyn std::error::Error>>;
 }
 
 #[derive(Debug, Clone)]
@@ -23,4 +630,150 @@ impl ConfigInterface for ImplConfigInterface {
         let params = serde_json::from_reader(json_data)?;
         Ok(params)
     }
+
+    /// read a file_sd file, the `__scheme__` and `__metrics_path__` labels are honoured
+    /// and a `name` label is used as the display name
+    fn read_file_sd(&self, name: &str) -> Result<Vec<Target>, Box<dyn std::error::Error>> {
+        if Path::new(name).extension().and_then(|x| x.to_str()) != Some("json") {
+            return Err(Box::new(GenericError::new(format!(
+                "[read_file_sd] {} : expected a .json file",
+                name
+            ))));
+        }
+        let groups: Vec<FileSdGroup> = serde_json::from_reader(File::open(name)?)?;
+        let mut targets = vec![];
+        for group in groups.iter() {
+            let scheme = group
+                .labels
+                .get("__scheme__")
+                .map(|x| x.as_str())
+                .unwrap_or("http");
+            for address in group.targets.iter() {
+                let url = match address.contains("://") {
+                    true => address.clone(),
+                    false => format!("{}://{}", scheme, address),
+                };
+                let mut target = Target::from_url(&url);
+                if let Some(path) = group.labels.get("__metrics_path__") {
+                    target.metrics_path = path.clone();
+                }
+                target.name = group.labels.get("name").cloned();
+                target.labels = group
+                    .labels
+                    .iter()
+                    .filter(|(k, _)| !k.starts_with("__") && *k != "name")
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect();
+                targets.push(target);
+            }
+        }
+        Ok(targets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_sd(name: &str, data: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn servers_as_urls_or_objects() {
+        let params: Parameters = serde_json::from_str(
+            r#"{
+                "name": "lab", "description": "", "log_level": "info", "scrape_duration": 5,
+                "servers": [
+                    "http://10.0.0.1:9100",
+                    {"url": "http://10.0.0.2:9100", "name": "db", "timeout_ms": 900, "retries": 0,
+                     "basic_auth": {"username": "user", "password": "pass"}},
+                    {"url": "http://10.0.0.3:9100", "enabled": false}
+                ],
+                "scrape": {"timeout_ms": 3000},
+                "target_overrides": {"http://10.0.0.2:9100": {"timeout_ms": 100, "backoff_max_ms": 500}}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(params.servers.len(), 3);
+        assert_eq!(params.servers[0], Target::from_url("http://10.0.0.1:9100"));
+        let db = &params.servers[1];
+        assert_eq!(db.name.as_deref(), Some("db"));
+        assert_eq!(db.metrics_path, "/metrics");
+        assert_eq!(db.overrides.timeout_ms, Some(900));
+        assert!(!params.servers[2].enabled);
+        assert_eq!(params.enabled_targets().len(), 2);
+
+        // the target's own values win over `target_overrides`, which win over `scrape`
+        let scrape = params.scrape_config(db);
+        assert_eq!(
+            (scrape.timeout_ms, scrape.retries, scrape.backoff_max_ms),
+            (900, 0, 500)
+        );
+        assert_eq!(scrape.connect_timeout_ms, 2000);
+        assert_eq!(params.scrape_config(&params.servers[0]).timeout_ms, 3000);
+        let security = params.security_config(db);
+        assert_eq!(
+            security.basic_auth.map(|x| x.username).as_deref(),
+            Some("user")
+        );
+        assert_eq!(
+            params.security_config(&params.servers[0]),
+            SecurityConfig::default()
+        );
+
+        // a serialized target reads back as the same target
+        let json = serde_json::to_string(db).unwrap();
+        assert_eq!(&serde_json::from_str::<Target>(&json).unwrap(), db);
+    }
+
+    #[test]
+    fn file_sd_groups() {
+        let path = file_sd(
+            "groups.json",
+            r#"[
+                {"targets": ["10.0.0.1:9100", "https://10.0.0.2:9100"], "labels": {"site": "lab"}},
+                {"targets": ["10.0.0.3:9100"], "labels": {
+                    "__scheme__": "https", "__metrics_path__": "/node", "name": "db", "rack": "r1"
+                }}
+            ]"#,
+        );
+        let targets = ImplConfigInterface {}.read_file_sd(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let urls: Vec<&str> = targets.iter().map(|x| x.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "http://10.0.0.1:9100",
+                "https://10.0.0.2:9100",
+                "https://10.0.0.3:9100"
+            ]
+        );
+        assert_eq!(targets[0].metrics_path, "/metrics");
+        assert_eq!(
+            targets[0].labels.get("site").map(|x| x.as_str()),
+            Some("lab")
+        );
+        assert_eq!(targets[2].metrics_path, "/node");
+        assert_eq!(targets[2].name.as_deref(), Some("db"));
+        // the reserved labels and the name are not kept as labels
+        assert_eq!(targets[2].labels.keys().collect::<Vec<_>>(), ["rack"]);
+    }
+
+    #[test]
+    fn file_sd_is_json_only() {
+        let path = file_sd("groups.yml", "- targets: ['10.0.0.1:9100']\n");
+        let err = ImplConfigInterface {}.read_file_sd(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("expected a .json file"), "{}", err);
+    }
+
+    #[test]
+    fn malformed_file_sd() {
+        let path = file_sd("malformed.json", r#"[{"targets": ["10.0.0.1:9100"]"#);
+        assert!(ImplConfigInterface {}.read_file_sd(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -1 +1,2 @@
 pub mod generic;
+pub mod scrape;
@@ -0,0 +1,75 @@
+use std::error::Error;
+use std::fmt;
+
+/// classified scrape failure, the variant drives the node status shown in the ui
+#[derive(Debug, Clone)]
+pub enum ScrapeError {
+    Down(String),
+    Timeout(String),
+    Http(u16, String),
+    Parse(String),
+    Auth(String),
+    Config(String),
+}
+
+impl ScrapeError {
+    pub fn from_reqwest(err: reqwest::Error) -> ScrapeError {
+        // reqwest hides the useful part (refused, unreachable ...) in the source chain
+        let mut msg = err.to_string();
+        let mut source = err.source();
+        while let Some(inner) = source {
+            msg = format!("{} : {}", msg, inner);
+            source = inner.source();
+        }
+        match err.status() {
+            Some(status) => ScrapeError::from_status(status.as_u16(), msg),
+            None if err.is_timeout() => ScrapeError::Timeout(msg),
+            None if err.is_decode() || err.is_body() => ScrapeError::Parse(msg),
+            None => ScrapeError::Down(msg),
+        }
+    }
+
+    /// an http error status, 401 and 403 mean the credentials were refused
+    pub fn from_status(code: u16, msg: String) -> ScrapeError {
+        match code {
+            401 | 403 => ScrapeError::Auth(msg),
+            code => ScrapeError::Http(code, msg),
+        }
+    }
+}
+
+impl fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScrapeError::Down(msg) => write!(f, "down : {}", msg),
+            ScrapeError::Timeout(msg) => write!(f, "timeout : {}", msg),
+            ScrapeError::Http(code, msg) => write!(f, "http {} : {}", code, msg),
+            ScrapeError::Parse(msg) => write!(f, "parse : {}", msg),
+            ScrapeError::Auth(msg) => write!(f, "auth : {}", msg),
+            ScrapeError::Config(msg) => write!(f, "config : {}", msg),
+        }
+    }
+}
+
+impl Error for ScrapeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refused_credentials_are_auth_errors() {
+        for code in [401, 403] {
+            let err = ScrapeError::from_status(code, "refused".to_string());
+            assert!(matches!(err, ScrapeError::Auth(ref x) if x == "refused"));
+        }
+        assert!(matches!(
+            ScrapeError::from_status(404, String::new()),
+            ScrapeError::Http(404, _)
+        ));
+        assert!(matches!(
+            ScrapeError::from_status(503, String::new()),
+            ScrapeError::Http(503, _)
+        ));
+    }
+}
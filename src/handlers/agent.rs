@@ -0,0 +1,151 @@
+// module agent
+// minimal http server for `agent` mode, serves the local collector in prometheus text format
+use crate::handlers::exposition;
+use crate::handlers::local;
+use custom_logger as log;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// accept scrapes until the process is stopped, every request collects fresh values
+pub async fn serve(listen: &str, metrics_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(listen).await?;
+    log::info!("[serve] listening on {}{}", listen, metrics_path);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(x) => x,
+            Err(err) => {
+                log::warn!("[serve] accept : {}", err);
+                continue;
+            }
+        };
+        let metrics_path = metrics_path.to_string();
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, &metrics_path).await {
+                log::debug!("[serve] {} : {}", peer, err);
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, metrics_path: &str) -> io::Result<()> {
+    // only the request head is read, scrapes have no body
+    let mut buf = vec![0u8; 8192];
+    let mut len = 0;
+    loop {
+        let n = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buf[len..]))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+        if n == 0 {
+            return Ok(());
+        }
+        len += n;
+        if buf[..len].windows(4).any(|x| x == b"\r\n\r\n") {
+            break;
+        }
+        if len == buf.len() {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "").await;
+        }
+    }
+    let head = String::from_utf8_lossy(&buf[..len]);
+    let mut request = head.lines().next().unwrap_or("").split_whitespace();
+    let method = request.next().unwrap_or("");
+    let path = request.next().unwrap_or("").split('?').next().unwrap_or("");
+    log::trace!("[handle] {} {}", method, path);
+    match (method, path) {
+        ("GET", x) if x == metrics_path => match local::collect().await {
+            Ok(families) => respond(&mut stream, "200 OK", &exposition::encode(&families)).await,
+            Err(err) => respond(&mut stream, "500 Internal Server Error", &err.to_string()).await,
+        },
+        ("GET", "/") => {
+            let body = format!(
+                "{} agent, metrics on {}\n",
+                env!("CARGO_PKG_NAME"),
+                metrics_path
+            );
+            respond(&mut stream, "200 OK", &body).await
+        }
+        ("GET", _) => respond(&mut stream, "404 Not Found", "not found\n").await,
+        (&_, _) => respond(&mut stream, "405 Method Not Allowed", "").await,
+    }
+}
+
+pub async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        CONTENT_TYPE,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// send a raw request to a handler on a local port, the whole response is returned
+    async fn exchange(request: &[u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle(stream, "/metrics").await
+        });
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        server.await.unwrap().unwrap();
+        response
+    }
+
+    fn status(response: &str) -> &str {
+        response.lines().next().unwrap_or("")
+    }
+
+    #[tokio::test]
+    async fn metrics_path() {
+        let response = exchange(b"GET /metrics?collect[]=cpu HTTP/1.1\r\nHost: a\r\n\r\n").await;
+        assert_eq!(status(&response), "HTTP/1.1 200 OK");
+        assert!(response.contains(&format!("Content-Type: {}\r\n", CONTENT_TYPE)));
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(body.contains("node_"));
+    }
+
+    #[tokio::test]
+    async fn other_paths_and_methods() {
+        let index = exchange(b"GET / HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status(&index), "HTTP/1.1 200 OK");
+        assert!(index.ends_with("metrics on /metrics\n"));
+        let missing = exchange(b"GET /metricsx HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status(&missing), "HTTP/1.1 404 Not Found");
+        let post = exchange(b"POST /metrics HTTP/1.1\r\nContent-Length: 0\r\n\r\n").await;
+        assert_eq!(status(&post), "HTTP/1.1 405 Method Not Allowed");
+        // no request line at all
+        let empty = exchange(b"\r\n\r\n").await;
+        assert_eq!(status(&empty), "HTTP/1.1 405 Method Not Allowed");
+    }
+
+    #[tokio::test]
+    async fn oversized_and_partial_requests() {
+        // a head that fills the buffer without ending
+        let mut oversized = b"GET /metrics HTTP/1.1\r\nX-Padding: ".to_vec();
+        oversized.resize(8192, b'a');
+        let response = exchange(&oversized).await;
+        assert_eq!(
+            status(&response),
+            "HTTP/1.1 431 Request Header Fields Too Large"
+        );
+        // the client goes away before the head is complete, nothing is answered
+        assert_eq!(exchange(b"GET /metrics HTTP/1.1\r\nHost: a\r\n").await, "");
+    }
+}
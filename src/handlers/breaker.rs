@@ -0,0 +1,153 @@
+// module breaker
+use crate::config::load::ScrapeConfig;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open(Instant),
+    HalfOpen,
+}
+
+/// per node circuit breaker, stops hammering dead targets and probes them with a growing cooldown
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    pub state: BreakerState,
+    failures: u32,
+    threshold: u32,
+    base_cooldown: Duration,
+    max_cooldown: Duration,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(settings: &ScrapeConfig) -> Self {
+        let base_cooldown = Duration::from_secs(settings.breaker_cooldown_secs);
+        CircuitBreaker {
+            state: BreakerState::Closed,
+            failures: 0,
+            threshold: settings.breaker_threshold.max(1),
+            base_cooldown,
+            max_cooldown: Duration::from_secs(settings.breaker_max_cooldown_secs)
+                .max(base_cooldown),
+            cooldown: base_cooldown,
+        }
+    }
+
+    /// true if a scrape may be sent now (an expired open breaker lets a single probe through)
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.state {
+            BreakerState::Closed => true,
+            BreakerState::Open(until) if now >= until => {
+                self.state = BreakerState::HalfOpen;
+                true
+            }
+            BreakerState::Open(_) | BreakerState::HalfOpen => false,
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+        self.cooldown = self.base_cooldown;
+        self.state = BreakerState::Closed;
+    }
+
+    pub fn record_failure(&mut self, now: Instant) {
+        match self.state {
+            BreakerState::HalfOpen => {
+                // failed probe, back off further
+                self.cooldown = (self.cooldown * 2).min(self.max_cooldown);
+                self.state = BreakerState::Open(now + self.cooldown);
+            }
+            _ => {
+                self.failures += 1;
+                if self.failures >= self.threshold {
+                    self.state = BreakerState::Open(now + self.cooldown);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(&ScrapeConfig {
+            breaker_threshold: 3,
+            breaker_cooldown_secs: 30,
+            breaker_max_cooldown_secs: 100,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn opens_after_the_threshold() {
+        let start = Instant::now();
+        let mut breaker = breaker();
+        for _ in 0..2 {
+            breaker.record_failure(start);
+            assert!(breaker.allow(start));
+        }
+        // a success resets the count
+        breaker.record_success();
+        breaker.record_failure(start);
+        breaker.record_failure(start);
+        assert_eq!(breaker.state, BreakerState::Closed);
+        breaker.record_failure(start);
+        assert_eq!(
+            breaker.state,
+            BreakerState::Open(start + Duration::from_secs(30))
+        );
+        assert!(!breaker.allow(start + Duration::from_secs(29)));
+    }
+
+    #[test]
+    fn a_single_probe_after_the_cooldown() {
+        let start = Instant::now();
+        let mut breaker = breaker();
+        for _ in 0..3 {
+            breaker.record_failure(start);
+        }
+        let later = start + Duration::from_secs(30);
+        assert!(breaker.allow(later));
+        assert_eq!(breaker.state, BreakerState::HalfOpen);
+        // nothing else goes out while the probe is in flight
+        assert!(!breaker.allow(later));
+        breaker.record_success();
+        assert_eq!(breaker.state, BreakerState::Closed);
+        assert!(breaker.allow(later));
+    }
+
+    #[test]
+    fn failed_probes_double_the_cooldown_up_to_the_max() {
+        let mut now = Instant::now();
+        let mut breaker = breaker();
+        for _ in 0..3 {
+            breaker.record_failure(now);
+        }
+        let mut cooldowns = vec![];
+        for _ in 0..4 {
+            let BreakerState::Open(until) = breaker.state else {
+                panic!("expected an open breaker, got {:?}", breaker.state);
+            };
+            cooldowns.push((until - now).as_secs());
+            now = until;
+            assert!(breaker.allow(now));
+            breaker.record_failure(now);
+        }
+        assert_eq!(cooldowns, [30, 60, 100, 100]);
+        // a successful probe starts over from the base cooldown
+        now += Duration::from_secs(100);
+        assert!(breaker.allow(now));
+        breaker.record_success();
+        for _ in 0..3 {
+            breaker.record_failure(now);
+        }
+        assert_eq!(
+            breaker.state,
+            BreakerState::Open(now + Duration::from_secs(30))
+        );
+    }
+}
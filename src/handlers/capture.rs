@@ -0,0 +1,473 @@
+// module capture
+// records the raw outcome of every scrape into a capture file and replays it through the
+// same pipeline (decode, federate split, ui updates)
+//
+// the file starts with a header line, each entry is a line
+// `<unix seconds> <length> <split 0|1> <node> body <content type>` or
+// `<unix seconds> <length> <split 0|1> <node> error <kind>` followed by `length` bytes
+// (the raw body or the error message) and a newline, the node is percent-encoded
+//
+// nodes read through the prometheus api have no raw body, their families are recorded in the
+// openmetrics text format
+//
+// entries are written by a task of their own, so the scrape tasks never wait on the disk
+use crate::config::load::Target;
+use crate::error::generic::GenericError;
+use crate::error::scrape::ScrapeError;
+use crate::handlers::discovery::DiscoveryUpdate;
+use crate::handlers::exposition::{self, MetricFamily};
+use crate::handlers::process::Body;
+use crate::handlers::scraper::{ScrapeUpdate, send_split};
+use chrono::Local;
+use custom_logger as log;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+
+const HEADER: &str = "# node-metrics-tui capture v1\n";
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0";
+
+/// the writer of the capture file, set once at startup when recording
+static RECORDER: OnceLock<mpsc::UnboundedSender<Write>> = OnceLock::new();
+
+/// what the writer is asked to do
+enum Write {
+    Entry(Vec<u8>),
+    /// reply once everything sent before is on disk
+    Flush(oneshot::Sender<()>),
+}
+
+/// a recorded scrape
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub ts: f64,
+    pub node: String,
+    /// federated target, the body is split per `instance` on replay
+    pub split: bool,
+    pub result: Result<Body, ScrapeError>,
+}
+
+/// replay settings, `speed` 0 sends every entry without waiting
+#[derive(Debug, Clone)]
+pub struct Replay {
+    pub entries: Vec<Entry>,
+    pub speed: f64,
+    /// wait for a key press before every entry
+    pub step: bool,
+}
+
+/// start recording to `path` (truncated)
+pub async fn start(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = File::create(path)
+        .await
+        .map_err(|e| GenericError::new(format!("[start] {} : {}", path, e)))?;
+    file.write_all(HEADER.as_bytes()).await?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(write_entries(file, rx));
+    let _ = RECORDER.set(tx);
+    Ok(())
+}
+
+/// append a scrape outcome, a no-op unless recording
+pub fn record(node: &str, split: bool, result: &Result<Body, ScrapeError>) {
+    let Some(recorder) = RECORDER.get() else {
+        return;
+    };
+    let ts = Local::now().timestamp_millis() as f64 / 1000.0;
+    let _ = recorder.send(Write::Entry(encode_entry(ts, node, split, result)));
+}
+
+/// wait until the entries recorded so far are written, a no-op unless recording
+pub async fn flush() {
+    let Some(recorder) = RECORDER.get() else {
+        return;
+    };
+    let (tx, rx) = oneshot::channel();
+    if recorder.send(Write::Flush(tx)).is_ok() {
+        let _ = rx.await;
+    }
+}
+
+/// append decoded families (the prometheus api source) as an openmetrics body, a no-op
+/// unless recording
+pub fn record_families(node: &str, result: &Result<Vec<MetricFamily>, ScrapeError>) {
+    if RECORDER.get().is_none() {
+        return;
+    }
+    let result = match result {
+        Ok(families) => Ok(openmetrics_body(families)),
+        Err(err) => Err(err.clone()),
+    };
+    record(node, false, &result);
+}
+
+/// read a capture file, entries are in the order they were recorded
+pub fn read(path: &str) -> Result<Vec<Entry>, Box<dyn std::error::Error>> {
+    let data = fs::read(path).map_err(|e| GenericError::new(format!("[read] {} : {}", path, e)))?;
+    let err = |msg: String| GenericError::new(format!("[read] {} : {}", path, msg));
+    if !data.starts_with(HEADER.as_bytes()) {
+        return Err(Box::new(err("not a capture file".to_string())));
+    }
+    let mut entries = vec![];
+    let mut pos = HEADER.len();
+    while pos < data.len() {
+        let Some(end) = data[pos..].iter().position(|x| *x == b'\n') else {
+            return Err(Box::new(err(format!("truncated entry at byte {}", pos))));
+        };
+        let line = String::from_utf8_lossy(&data[pos..pos + end]).to_string();
+        let fields: Vec<&str> = line.splitn(6, ' ').collect();
+        let [ts, len, split, node, kind, ref rest @ ..] = fields[..] else {
+            return Err(Box::new(err(format!("invalid entry at byte {}", pos))));
+        };
+        let (Ok(ts), Ok(len)) = (ts.parse::<f64>(), len.parse::<usize>()) else {
+            return Err(Box::new(err(format!("invalid entry at byte {}", pos))));
+        };
+        let start = pos + end + 1;
+        // a capture cut short (the tool was killed mid write) keeps the complete entries
+        let Some(bytes) = data.get(start..start + len) else {
+            log::debug!("[read] {} : truncated entry at byte {}", path, pos);
+            break;
+        };
+        let detail = rest.first().copied().unwrap_or("");
+        let result = match kind {
+            "body" => Ok(Body {
+                content_type: detail.to_string(),
+                bytes: bytes.to_vec(),
+            }),
+            "error" => Err(parse_error(detail, &String::from_utf8_lossy(bytes))),
+            x => return Err(Box::new(err(format!("unknown entry kind {}", x)))),
+        };
+        entries.push(Entry {
+            ts,
+            node: decode_node(node),
+            split: split == "1",
+            result,
+        });
+        pos = start + len + 1;
+    }
+    Ok(entries)
+}
+
+/// nodes of a capture in the order they first appear (federated targets list their
+/// instances as they are replayed)
+pub fn targets(entries: &[Entry]) -> Vec<Target> {
+    let mut targets: Vec<Target> = vec![];
+    for entry in entries.iter().filter(|x| !x.split) {
+        if !targets.iter().any(|x| x.url == entry.node) {
+            targets.push(Target::from_url(&entry.node));
+        }
+    }
+    targets
+}
+
+/// send the entries as scrape updates, spaced as recorded (divided by the speed) or one per
+/// step, timestamps are shifted so the capture starts now
+pub async fn replay(
+    replay: Replay,
+    mut step: Option<mpsc::Receiver<()>>,
+    tx: mpsc::Sender<ScrapeUpdate>,
+    discovery_tx: mpsc::Sender<DiscoveryUpdate>,
+) {
+    let Some(first) = replay.entries.first().map(|x| x.ts) else {
+        return;
+    };
+    let shift = Local::now().timestamp_millis() as f64 / 1000.0 - first;
+    let mut previous = first;
+    let mut federated: HashMap<String, Vec<String>> = HashMap::new();
+    for entry in replay.entries {
+        if let Some(rx) = step.as_mut() {
+            if rx.recv().await.is_none() {
+                return;
+            }
+        } else if replay.speed > 0.0 {
+            let wait = (entry.ts - previous).max(0.0) / replay.speed;
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+        }
+        previous = entry.ts;
+        let result = entry.result.and_then(|x| x.decode()).map(|mut families| {
+            for sample in families.iter_mut().flat_map(|x| x.samples.iter_mut()) {
+                sample.timestamp = Some(sample.timestamp.unwrap_or(entry.ts) + shift);
+            }
+            families
+        });
+        if entry.split {
+            let known = federated.remove(&entry.node).unwrap_or_default();
+            let labels = BTreeMap::new();
+            let instances =
+                send_split(&entry.node, &labels, result, known, &tx, &discovery_tx).await;
+            federated.insert(entry.node, instances);
+            continue;
+        }
+        let update = ScrapeUpdate {
+            node: entry.node,
+            result,
+        };
+        if tx.send(update).await.is_err() {
+            return;
+        }
+    }
+}
+
+// utility functions
+
+/// write the entries in the order they were recorded, one write each so they never interleave
+async fn write_entries(mut file: File, mut rx: mpsc::UnboundedReceiver<Write>) {
+    while let Some(write) = rx.recv().await {
+        match write {
+            Write::Entry(entry) => {
+                if let Err(e) = file.write_all(&entry).await {
+                    log::debug!("[write_entries] {}", e);
+                }
+            }
+            Write::Flush(done) => {
+                if let Err(e) = file.flush().await {
+                    log::debug!("[write_entries] {}", e);
+                }
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// the entry line followed by the body or error message
+fn encode_entry(ts: f64, node: &str, split: bool, result: &Result<Body, ScrapeError>) -> Vec<u8> {
+    let (kind, data) = match result {
+        Ok(body) => (format!("body {}", body.content_type), body.bytes.as_slice()),
+        Err(err) => (
+            format!("error {}", error_kind(err)),
+            error_message(err).as_bytes(),
+        ),
+    };
+    // files and stdin have no content type, the line then ends with the kind
+    let line = format!(
+        "{:.3} {} {} {} {}",
+        ts,
+        data.len(),
+        split as u8,
+        encode_node(node),
+        kind
+    );
+    let mut entry = line.trim_end().as_bytes().to_vec();
+    entry.push(b'\n');
+    entry.extend_from_slice(data);
+    entry.push(b'\n');
+    entry
+}
+
+fn openmetrics_body(families: &[MetricFamily]) -> Body {
+    Body {
+        content_type: OPENMETRICS_CONTENT_TYPE.to_string(),
+        bytes: exposition::encode_openmetrics(families).into_bytes(),
+    }
+}
+
+fn error_kind(err: &ScrapeError) -> String {
+    match err {
+        ScrapeError::Down(_) => "down".to_string(),
+        ScrapeError::Timeout(_) => "timeout".to_string(),
+        ScrapeError::Http(code, _) => format!("http {}", code),
+        ScrapeError::Parse(_) => "parse".to_string(),
+        ScrapeError::Auth(_) => "auth".to_string(),
+        ScrapeError::Config(_) => "config".to_string(),
+    }
+}
+
+fn error_message(err: &ScrapeError) -> &str {
+    match err {
+        ScrapeError::Down(msg)
+        | ScrapeError::Timeout(msg)
+        | ScrapeError::Http(_, msg)
+        | ScrapeError::Parse(msg)
+        | ScrapeError::Auth(msg)
+        | ScrapeError::Config(msg) => msg,
+    }
+}
+
+/// the node is a field of the entry line, spaces, newlines and `%` are escaped
+fn encode_node(node: &str) -> String {
+    let mut out = String::with_capacity(node.len());
+    for c in node.chars() {
+        match c {
+            '%' | ' ' | '\n' | '\r' => out.push_str(&format!("%{:02X}", c as u8)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn decode_node(value: &str) -> String {
+    let mut out: Vec<u8> = Vec::with_capacity(value.len());
+    let bytes = value.as_bytes();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let escaped = value
+            .get(pos + 1..pos + 3)
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        match (bytes[pos], escaped) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                pos += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                pos += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+fn parse_error(kind: &str, msg: &str) -> ScrapeError {
+    let msg = msg.to_string();
+    match kind.split_once(' ') {
+        Some(("http", code)) => ScrapeError::Http(code.parse().unwrap_or(0), msg),
+        _ => match kind {
+            "timeout" => ScrapeError::Timeout(msg),
+            "parse" => ScrapeError::Parse(msg),
+            "auth" => ScrapeError::Auth(msg),
+            "config" => ScrapeError::Config(msg),
+            &_ => ScrapeError::Down(msg),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::exposition::{Format, MetricType};
+    use crate::handlers::process::TEXT_CONTENT_TYPE;
+
+    fn body(content_type: &str, bytes: &[u8]) -> Result<Body, ScrapeError> {
+        Ok(Body {
+            content_type: content_type.to_string(),
+            bytes: bytes.to_vec(),
+        })
+    }
+
+    /// a capture file of its own per test, removed once read
+    fn read_back(name: &str, data: &[u8]) -> Result<Vec<Entry>, Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("capture-{}-{}", name, std::process::id()));
+        fs::write(&path, data).unwrap();
+        let entries = read(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        entries
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let mut data = HEADER.as_bytes().to_vec();
+        let text = b"# TYPE up gauge\nup 1\n";
+        data.extend(encode_entry(
+            100.0,
+            "http://a:9100",
+            false,
+            &body(TEXT_CONTENT_TYPE, text),
+        ));
+        // a binary body with newlines, and a node with a space and a percent sign
+        let node = "file:///tmp/my scrape%20.prom";
+        data.extend(encode_entry(100.5, node, false, &body("", b"\n\x00\xff\n")));
+        let error = Err(ScrapeError::Http(
+            503,
+            "service unavailable\nretry".to_string(),
+        ));
+        data.extend(encode_entry(101.25, "http://fed:9090", true, &error));
+        data.extend(encode_entry(
+            102.0,
+            "b",
+            false,
+            &Err(ScrapeError::Timeout("slow".to_string())),
+        ));
+        let entries = read_back("round-trip", &data).unwrap();
+        assert_eq!(entries.len(), 4);
+
+        assert_eq!(entries[0].ts, 100.0);
+        assert_eq!(entries[0].node, "http://a:9100");
+        let first = entries[0].result.as_ref().unwrap();
+        assert_eq!(first.content_type, TEXT_CONTENT_TYPE);
+        assert_eq!(first.bytes, text);
+
+        assert_eq!(entries[1].node, node);
+        let second = entries[1].result.as_ref().unwrap();
+        assert_eq!(second.content_type, "");
+        assert_eq!(second.bytes, b"\n\x00\xff\n");
+
+        assert!(entries[2].split);
+        match &entries[2].result {
+            Err(ScrapeError::Http(503, msg)) => assert_eq!(msg, "service unavailable\nretry"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(&entries[3].result, Err(ScrapeError::Timeout(x)) if x == "slow"));
+        // the federated target is split on replay, only the others are listed
+        let urls: Vec<String> = targets(&entries).into_iter().map(|x| x.url).collect();
+        assert_eq!(urls, ["http://a:9100", node, "b"]);
+    }
+
+    #[test]
+    fn a_capture_cut_short_keeps_the_complete_entries() {
+        let mut data = HEADER.as_bytes().to_vec();
+        data.extend(encode_entry(1.0, "a", false, &body("", b"up 1\n")));
+        let entry = encode_entry(2.0, "a", false, &body("", b"up 1\nup 2\n"));
+        data.extend(&entry[..entry.len() - 4]);
+        assert_eq!(read_back("truncated", &data).unwrap().len(), 1);
+        assert!(read_back("foreign", b"up 1\n").is_err());
+        let mut data = HEADER.as_bytes().to_vec();
+        data.extend(b"1.0 0 0 a nonsense\n\n");
+        assert!(read_back("kind", &data).is_err());
+    }
+
+    #[test]
+    fn node_encoding() {
+        for node in [
+            "http://a:9100",
+            "a b",
+            "100%",
+            "%20",
+            "line\nbreak\r",
+            "ü ü",
+        ] {
+            let encoded = encode_node(node);
+            assert!(!encoded.contains([' ', '\n', '\r']));
+            assert_eq!(decode_node(&encoded), node);
+        }
+        // a stray percent sign is kept
+        assert_eq!(decode_node("a%zz%2"), "a%zz%2");
+    }
+
+    #[test]
+    fn families_are_recorded_as_openmetrics() {
+        let families = exposition::parse(
+            "# TYPE build info\nbuild_info{version=\"1\"} 1 1700000000.5\n# EOF\n",
+            Format::OpenMetrics,
+        )
+        .unwrap();
+        let decoded = openmetrics_body(&families).decode().unwrap();
+        assert_eq!(decoded, families);
+        assert_eq!(decoded[0].metric_type, MetricType::Info);
+    }
+
+    #[tokio::test]
+    async fn entries_are_written_in_order() {
+        let path = std::env::temp_dir().join(format!("capture-writer-{}", std::process::id()));
+        let mut file = File::create(&path).await.unwrap();
+        file.write_all(HEADER.as_bytes()).await.unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(write_entries(file, rx));
+        for ts in 1..=20 {
+            let entry = encode_entry(ts as f64, "a", false, &body("", b"up 1\n"));
+            tx.send(Write::Entry(entry)).unwrap();
+        }
+        let (done_tx, done_rx) = oneshot::channel();
+        tx.send(Write::Flush(done_tx)).unwrap();
+        done_rx.await.unwrap();
+        let entries = read(path.to_str().unwrap()).unwrap();
+        let ts: Vec<f64> = entries.iter().map(|x| x.ts).collect();
+        assert_eq!(ts, (1..=20).map(|x| x as f64).collect::<Vec<f64>>());
+        drop(tx);
+        writer.await.unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
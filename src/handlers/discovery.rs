@@ -0,0 +1,240 @@
+// module discovery
+use crate::config::load::{
+    ConfigInterface, DnsSdConfig, FileSdConfig, ImplConfigInterface, Target,
+};
+use crate::handlers::dns::{self, Record};
+use custom_logger as log;
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
+
+/// targets found by a discovery source, an error keeps the previously discovered targets
+#[derive(Debug)]
+pub struct DiscoveryUpdate {
+    pub source: String,
+    pub result: Result<Vec<Target>, String>,
+}
+
+/// watch the file_sd files (polling the modification time) and send their targets on every change
+pub async fn run_file_sd(config: FileSdConfig, tx: mpsc::Sender<DiscoveryUpdate>) {
+    let impl_config = ImplConfigInterface {};
+    let refresh = Duration::from_secs(config.refresh_interval.max(1));
+    let mut mtimes: HashMap<String, Option<SystemTime>> = HashMap::new();
+    let mut last_refresh = Instant::now();
+    let mut ticker = tokio::time::interval(Duration::from_secs(2));
+
+    loop {
+        ticker.tick().await;
+        let force = last_refresh.elapsed() >= refresh;
+        if force {
+            last_refresh = Instant::now();
+        }
+        let files: Vec<String> = config.files.iter().flat_map(|x| expand(x)).collect();
+        // files matched by a glob that have since disappeared lose their targets
+        let removed: Vec<String> = mtimes
+            .keys()
+            .filter(|x| !files.contains(x))
+            .cloned()
+            .collect();
+        for file in removed {
+            mtimes.remove(&file);
+            let update = DiscoveryUpdate {
+                source: source_name(&file),
+                result: Ok(vec![]),
+            };
+            if tx.send(update).await.is_err() {
+                return;
+            }
+        }
+        for file in files {
+            let mtime = fs::metadata(&file).and_then(|x| x.modified()).ok();
+            if !force && mtimes.get(&file) == Some(&mtime) {
+                continue;
+            }
+            mtimes.insert(file.clone(), mtime);
+            log::debug!("[run_file_sd] reading {}", file);
+            let result = impl_config
+                .read_file_sd(&file)
+                .map_err(|e| format!("{} : {}", file, e));
+            let update = DiscoveryUpdate {
+                source: source_name(&file),
+                result,
+            };
+            if tx.send(update).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// re-resolve the dns_sd names on every refresh interval and send their targets
+pub async fn run_dns_sd(config: DnsSdConfig, tx: mpsc::Sender<DiscoveryUpdate>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.refresh_interval.max(1)));
+    loop {
+        ticker.tick().await;
+        for name in config.names.iter() {
+            let update = DiscoveryUpdate {
+                source: format!("dns_sd:{}", name),
+                result: resolve_targets(&config, name)
+                    .await
+                    .map_err(|e| format!("{} : {}", name, e)),
+            };
+            if tx.send(update).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// utility functions
+
+async fn resolve_targets(config: &DnsSdConfig, name: &str) -> Result<Vec<Target>, String> {
+    let nameserver = match &config.nameserver {
+        Some(x) => dns::parse_nameserver(x),
+        None => dns::system_nameserver(),
+    }
+    .map_err(|e| e.to_string())?;
+    let records = dns::resolve(nameserver, name, config.record_type)
+        .await
+        .map_err(|e| e.to_string())?;
+    log::debug!("[resolve_targets] {} : {:?}", name, records);
+    let mut targets = vec![];
+    for record in records {
+        let address = match (record, config.port) {
+            (Record::Srv { target, port }, _) => format!("{}:{}", target, port),
+            (Record::Address(IpAddr::V4(ip)), Some(port)) => format!("{}:{}", ip, port),
+            (Record::Address(IpAddr::V6(ip)), Some(port)) => format!("[{}]:{}", ip, port),
+            (Record::Address(_), None) => {
+                return Err("port is required for A and AAAA records".to_string());
+            }
+        };
+        let mut target = Target::from_url(&format!("{}://{}", config.scheme, address));
+        target.metrics_path = config.metrics_path.clone();
+        target.labels = config.labels.clone();
+        targets.push(target);
+    }
+    // answers come back in any order (round robin), keep the node list stable
+    targets.sort_by(|a, b| a.url.cmp(&b.url));
+    targets.dedup_by(|a, b| a.url == b.url);
+    Ok(targets)
+}
+
+fn source_name(file: &str) -> String {
+    format!("file_sd:{}", file)
+}
+
+/// expand `*` and `?` wildcards in the file name part of a path (prometheus style)
+fn expand(pattern: &str) -> Vec<String> {
+    let path = Path::new(pattern);
+    let file_name = path.file_name().and_then(|x| x.to_str()).unwrap_or("");
+    if !file_name.contains(['*', '?']) {
+        return vec![pattern.to_string()];
+    }
+    let dir = match path.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x.to_path_buf(),
+        _ => Path::new(".").to_path_buf(),
+    };
+    let mut files: Vec<String> = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|x| x.ok())
+            .filter(|x| {
+                x.file_name()
+                    .to_str()
+                    .map(|name| wildcard_match(file_name, name))
+                    .unwrap_or(false)
+            })
+            .map(|x| x.path().to_string_lossy().to_string())
+            .collect(),
+        Err(_) => vec![],
+    };
+    files.sort();
+    files
+}
+
+/// `*` matches any run of characters, `?` a single one
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // pattern position after the last `*` and the name position it currently stands for
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            // let the last `*` take one more character
+            _ => match star {
+                Some((after, from)) => {
+                    star = Some((after, from + 1));
+                    p = after;
+                    n = from + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*.json", "nodes.json"));
+        assert!(wildcard_match("*.json", ".json"));
+        assert!(wildcard_match("nodes-*.json", "nodes-lab.json"));
+        assert!(wildcard_match("nodes-?.json", "nodes-a.json"));
+        assert!(wildcard_match("*-*-?.json", "a-b-c-d.json"));
+        assert!(wildcard_match("n*s*", "nodes"));
+        assert!(wildcard_match("*", ""));
+        assert!(!wildcard_match("nodes-?.json", "nodes-ab.json"));
+        assert!(!wildcard_match("nodes-?.json", "nodes-.json"));
+        assert!(!wildcard_match("*.json", "nodes.yml"));
+        assert!(!wildcard_match("*.json", "nodes.json.bak"));
+        assert!(!wildcard_match("a*b", "ac"));
+        assert!(!wildcard_match("?", ""));
+    }
+
+    #[test]
+    fn patterns_expand_to_the_matching_files() {
+        let dir = std::env::temp_dir().join(format!("discovery-expand-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "nodes-b.json",
+            "nodes-a.json",
+            "nodes-ab.json",
+            "other.json",
+        ] {
+            fs::write(dir.join(name), "[]").unwrap();
+        }
+        let path = |x: &str| dir.join(x).to_string_lossy().to_string();
+        assert_eq!(
+            expand(&path("nodes-*.json")),
+            [
+                path("nodes-a.json"),
+                path("nodes-ab.json"),
+                path("nodes-b.json")
+            ]
+        );
+        assert_eq!(
+            expand(&path("nodes-?.json")),
+            [path("nodes-a.json"), path("nodes-b.json")]
+        );
+        assert!(expand(&path("*.yml")).is_empty());
+        // a plain name is kept as is, whether it exists or not
+        assert_eq!(expand(&path("missing.json")), [path("missing.json")]);
+        assert!(expand(&format!("{}/*.json", path("missing"))).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,413 @@
+// module dns
+// minimal udp dns client (SRV, A and AAAA queries only), enough for service discovery
+use crate::config::load::DnsRecordType;
+use crate::error::generic::GenericError;
+use custom_logger as log;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const ATTEMPTS: usize = 3;
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// a resolved answer record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    Address(IpAddr),
+    /// priority and weight are not used, every SRV target is scraped
+    Srv {
+        port: u16,
+        target: String,
+    },
+}
+
+/// parse a configured nameserver, `ip`, `ip:port` or `[ipv6]:port` (port 53 when not set)
+pub fn parse_nameserver(value: &str) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    match value.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, 53)),
+        Err(_) => Err(Box::new(GenericError::new(format!(
+            "[parse_nameserver] invalid nameserver {} (expected ip[:port])",
+            value
+        )))),
+    }
+}
+
+/// first nameserver listed in /etc/resolv.conf
+pub fn system_nameserver() -> Result<SocketAddr, Box<dyn std::error::Error>> {
+    let data = fs::read_to_string("/etc/resolv.conf")?;
+    let nameserver = data
+        .lines()
+        .filter_map(|x| x.trim().strip_prefix("nameserver"))
+        .map(|x| x.trim())
+        .find(|x| !x.is_empty());
+    match nameserver {
+        Some(x) => parse_nameserver(x),
+        None => Err(Box::new(GenericError::new(
+            "[system_nameserver] no nameserver found in /etc/resolv.conf",
+        ))),
+    }
+}
+
+/// query the nameserver, a non existent name resolves to no records
+pub async fn resolve(
+    nameserver: SocketAddr,
+    name: &str,
+    record_type: DnsRecordType,
+) -> Result<Vec<Record>, Box<dyn std::error::Error>> {
+    let qtype = match record_type {
+        DnsRecordType::Srv => TYPE_SRV,
+        DnsRecordType::A => TYPE_A,
+        DnsRecordType::Aaaa => TYPE_AAAA,
+    };
+    let bind = match nameserver {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(nameserver).await?;
+    let mut buf = vec![0u8; 4096];
+    for attempt in 1..=ATTEMPTS {
+        let id = fastrand::u16(..);
+        let query = encode_query(id, name, qtype)?;
+        socket.send(&query).await?;
+        // answers to an earlier attempt (or stray packets) are skipped until the timeout
+        let received = tokio::time::timeout(TIMEOUT, async {
+            loop {
+                let len = socket.recv(&mut buf).await?;
+                if len >= 2 && u16::from_be_bytes([buf[0], buf[1]]) == id {
+                    return Ok::<usize, std::io::Error>(len);
+                }
+            }
+        })
+        .await;
+        match received {
+            Ok(len) => {
+                return decode_response(&buf[..len?], qtype)
+                    .map_err(|e| GenericError::new(format!("[resolve] {} : {}", name, e)).into());
+            }
+            Err(_) => log::debug!("[resolve] {} : attempt {} timed out", name, attempt),
+        }
+    }
+    Err(Box::new(GenericError::new(format!(
+        "[resolve] {} : no answer from {}",
+        name, nameserver
+    ))))
+}
+
+// utility functions
+
+fn encode_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut packet = Vec::with_capacity(64);
+    packet.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question, one additional (edns)
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Box::new(GenericError::new(format!(
+                "[encode_query] invalid name {}",
+                name
+            ))));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    // edns0 opt record advertising a 4096 byte payload, so larger SRV sets are not truncated
+    packet.extend_from_slice(&[0, 0, 41, 0x10, 0x00, 0, 0, 0, 0, 0, 0]);
+    Ok(packet)
+}
+
+fn decode_response(packet: &[u8], qtype: u16) -> Result<Vec<Record>, String> {
+    let flags = read_u16(packet, 2)?;
+    if flags & 0x0200 != 0 {
+        return Err("response truncated".to_string());
+    }
+    match flags & 0x000f {
+        0 => {}
+        // nxdomain, every host behind the name is gone
+        3 => return Ok(vec![]),
+        2 => return Err("server failure".to_string()),
+        5 => return Err("query refused".to_string()),
+        rcode => return Err(format!("response code {}", rcode)),
+    }
+    let questions = read_u16(packet, 4)?;
+    let answers = read_u16(packet, 6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(packet, pos)? + 4;
+    }
+    let mut records = vec![];
+    for _ in 0..answers {
+        pos = skip_name(packet, pos)?;
+        let rtype = read_u16(packet, pos)?;
+        let rdlen = read_u16(packet, pos + 8)? as usize;
+        pos += 10;
+        let rdata = packet
+            .get(pos..pos + rdlen)
+            .ok_or("record data out of bounds")?;
+        // cnames and other record types in the answer are skipped
+        match rtype {
+            TYPE_A if qtype == TYPE_A && rdlen == 4 => {
+                let ip = Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]);
+                records.push(Record::Address(IpAddr::V4(ip)));
+            }
+            TYPE_AAAA if qtype == TYPE_AAAA && rdlen == 16 => {
+                let octets: [u8; 16] = rdata.try_into().map_err(|_| "invalid AAAA record")?;
+                records.push(Record::Address(IpAddr::V6(Ipv6Addr::from(octets))));
+            }
+            TYPE_SRV if qtype == TYPE_SRV && rdlen > 6 => records.push(Record::Srv {
+                port: read_u16(packet, pos + 4)?,
+                target: read_name(packet, pos + 6)?,
+            }),
+            _ => {}
+        }
+        pos += rdlen;
+    }
+    Ok(records)
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Result<u16, String> {
+    match packet.get(pos..pos + 2) {
+        Some(x) => Ok(u16::from_be_bytes([x[0], x[1]])),
+        None => Err("short response".to_string()),
+    }
+}
+
+/// position after the (possibly compressed) name at `pos`
+fn skip_name(packet: &[u8], mut pos: usize) -> Result<usize, String> {
+    loop {
+        let len = *packet.get(pos).ok_or("short response")? as usize;
+        match len {
+            0 => return Ok(pos + 1),
+            x if x & 0xc0 == 0xc0 => return Ok(pos + 2),
+            x => pos += x + 1,
+        }
+    }
+}
+
+/// read a (possibly compressed) name, without the trailing dot
+fn read_name(packet: &[u8], mut pos: usize) -> Result<String, String> {
+    let mut labels: Vec<String> = vec![];
+    // guards against pointer loops
+    let mut jumps = 0;
+    loop {
+        let len = *packet.get(pos).ok_or("short response")? as usize;
+        match len {
+            0 => return Ok(labels.join(".")),
+            x if x & 0xc0 == 0xc0 => {
+                jumps += 1;
+                if jumps > 16 {
+                    return Err("name compression loop".to_string());
+                }
+                pos = (read_u16(packet, pos)? & 0x3fff) as usize;
+            }
+            x => {
+                let label = packet.get(pos + 1..pos + 1 + x).ok_or("short response")?;
+                labels.push(String::from_utf8_lossy(label).to_string());
+                pos += x + 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUESTION: &str = "_node._tcp.example.com";
+    /// offset of the `example` label of the question, for compression pointers
+    const EXAMPLE_AT: u16 = 12 + 6 + 5;
+
+    fn name(value: &str) -> Vec<u8> {
+        let mut out = vec![];
+        for label in value.split('.') {
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+        }
+        out.push(0);
+        out
+    }
+
+    /// a response to QUESTION with `flags`, answers are (name, type, rdata)
+    fn response(id: u16, flags: u16, answers: &[(Vec<u8>, u16, Vec<u8>)]) -> Vec<u8> {
+        let mut packet = vec![];
+        packet.extend_from_slice(&id.to_be_bytes());
+        packet.extend_from_slice(&flags.to_be_bytes());
+        packet.extend_from_slice(&1u16.to_be_bytes());
+        packet.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0, 0]);
+        packet.extend_from_slice(&name(QUESTION));
+        packet.extend_from_slice(&TYPE_SRV.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        for (owner, rtype, rdata) in answers.iter() {
+            packet.extend_from_slice(owner);
+            packet.extend_from_slice(&rtype.to_be_bytes());
+            packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+            packet.extend_from_slice(&300u32.to_be_bytes());
+            packet.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            packet.extend_from_slice(rdata);
+        }
+        packet
+    }
+
+    /// the question name as a compression pointer
+    fn pointer(offset: u16) -> Vec<u8> {
+        (0xc000 | offset).to_be_bytes().to_vec()
+    }
+
+    /// SRV rdata, the target is `host` followed by a pointer to `example.com` in the question
+    fn srv(port: u16, host: &str) -> Vec<u8> {
+        let mut rdata = vec![0, 10, 0, 5];
+        rdata.extend_from_slice(&port.to_be_bytes());
+        rdata.push(host.len() as u8);
+        rdata.extend_from_slice(host.as_bytes());
+        rdata.extend_from_slice(&pointer(EXAMPLE_AT));
+        rdata
+    }
+
+    #[test]
+    fn srv_with_name_compression() {
+        let packet = response(
+            1,
+            0x8180,
+            &[
+                (pointer(12), TYPE_SRV, srv(9100, "node1")),
+                (pointer(12), TYPE_SRV, srv(9200, "node2")),
+            ],
+        );
+        assert_eq!(
+            decode_response(&packet, TYPE_SRV).unwrap(),
+            [
+                Record::Srv {
+                    port: 9100,
+                    target: "node1.example.com".to_string()
+                },
+                Record::Srv {
+                    port: 9200,
+                    target: "node2.example.com".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn address_records() {
+        let packet = response(
+            1,
+            0x8180,
+            &[
+                (name("node1.example.com"), TYPE_A, vec![10, 0, 0, 1]),
+                // a cname in the answer is skipped
+                (pointer(12), 5, name("node1.example.com")),
+                (
+                    pointer(12),
+                    TYPE_AAAA,
+                    vec![0xfd, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+                ),
+            ],
+        );
+        assert_eq!(
+            decode_response(&packet, TYPE_A).unwrap(),
+            [Record::Address("10.0.0.1".parse().unwrap())]
+        );
+        assert_eq!(
+            decode_response(&packet, TYPE_AAAA).unwrap(),
+            [Record::Address("fd00::1".parse().unwrap())]
+        );
+    }
+
+    #[test]
+    fn response_codes_and_truncation() {
+        // nxdomain is no records, not an error
+        assert_eq!(
+            decode_response(&response(1, 0x8183, &[]), TYPE_SRV).unwrap(),
+            []
+        );
+        assert!(decode_response(&response(1, 0x8182, &[]), TYPE_SRV).is_err());
+        assert!(decode_response(&response(1, 0x8185, &[]), TYPE_SRV).is_err());
+        assert!(decode_response(&response(1, 0x8184, &[]), TYPE_SRV).is_err());
+        let err = decode_response(&response(1, 0x8380, &[]), TYPE_SRV).unwrap_err();
+        assert!(err.contains("truncated"), "{}", err);
+    }
+
+    #[test]
+    fn malformed_packets_are_errors() {
+        // an SRV target pointing at itself, after the header, question, answer fixed fields
+        // and the priority, weight and port
+        let at = (12 + name(QUESTION).len() + 4 + 12 + 6) as u16;
+        let mut rdata = vec![0, 10, 0, 5, 0x23, 0x8c];
+        rdata.extend_from_slice(&pointer(at));
+        let looped = response(1, 0x8180, &[(pointer(12), TYPE_SRV, rdata)]);
+        let err = decode_response(&looped, TYPE_SRV).unwrap_err();
+        assert!(err.contains("loop"), "{}", err);
+        // a pointer past the end of the packet
+        let mut rdata = vec![0, 10, 0, 5, 0x23, 0x8c];
+        rdata.extend_from_slice(&pointer(0x3fff));
+        let outside = response(1, 0x8180, &[(pointer(12), TYPE_SRV, rdata)]);
+        assert!(decode_response(&outside, TYPE_SRV).is_err());
+        // record data longer than the packet
+        let mut short = response(1, 0x8180, &[(pointer(12), TYPE_A, vec![10, 0, 0, 1])]);
+        short.truncate(short.len() - 2);
+        assert!(decode_response(&short, TYPE_A).is_err());
+        assert!(decode_response(&[0, 1], TYPE_A).is_err());
+    }
+
+    #[test]
+    fn nameservers() {
+        assert_eq!(
+            parse_nameserver("10.0.0.53").unwrap(),
+            "10.0.0.53:53".parse().unwrap()
+        );
+        assert_eq!(
+            parse_nameserver("[::1]:5353").unwrap(),
+            "[::1]:5353".parse().unwrap()
+        );
+        assert!(parse_nameserver("dns.example.com").is_err());
+        assert!(encode_query(1, "bad..name", TYPE_A).is_err());
+    }
+
+    #[tokio::test]
+    async fn resolve_against_a_stub() {
+        let stub = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = stub.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut buf = vec![0u8; 512];
+            let (len, peer) = stub.recv_from(&mut buf).await.unwrap();
+            let query = buf[..len].to_vec();
+            let id = u16::from_be_bytes([query[0], query[1]]);
+            // a stray answer with another id is skipped
+            let stray = response(id.wrapping_add(1), 0x8180, &[]);
+            stub.send_to(&stray, peer).await.unwrap();
+            let answer = response(id, 0x8180, &[(pointer(12), TYPE_SRV, srv(9100, "node1"))]);
+            stub.send_to(&answer, peer).await.unwrap();
+            query
+        });
+        let records = resolve(address, QUESTION, DnsRecordType::Srv)
+            .await
+            .unwrap();
+        assert_eq!(
+            records,
+            [Record::Srv {
+                port: 9100,
+                target: "node1.example.com".to_string()
+            }]
+        );
+        let query = server.await.unwrap();
+        assert_eq!(
+            &query[12..12 + name(QUESTION).len()],
+            name(QUESTION).as_slice()
+        );
+        let qtype = 12 + name(QUESTION).len();
+        assert_eq!(read_u16(&query, qtype).unwrap(), TYPE_SRV);
+    }
+}
@@ -0,0 +1,406 @@
+// module export
+// writes node snapshots as json, csv (one value per row) or a markdown table for tickets
+use crate::config::load::{ExportConfig, ExportFormat, Parameters};
+use crate::error::generic::GenericError;
+use crate::handlers::exposition;
+use crate::handlers::fleet::NodeState;
+use crate::handlers::history::HistoryStore;
+use crate::handlers::retry::scrape_with_retry;
+use crate::handlers::snapshot::{NodeSnapshot, format_bytes, format_rate, snapshot};
+use crate::handlers::target::ScrapeTarget;
+use chrono::Local;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// write the snapshots to `config.dir`, `label` names the node (or "all") in the file name
+pub fn write(
+    snapshots: &[NodeSnapshot],
+    config: &ExportConfig,
+    label: &str,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let extension = match config.format {
+        ExportFormat::Json => "json",
+        ExportFormat::Csv => "csv",
+        ExportFormat::Markdown => "md",
+    };
+    let label: String = label
+        .chars()
+        .map(|x| {
+            if x.is_ascii_alphanumeric() || x == '.' {
+                x
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let name = format!(
+        "snapshot-{}-{}.{}",
+        label.trim_matches('-'),
+        Local::now().format("%Y%m%d-%H%M%S"),
+        extension
+    );
+    fs::create_dir_all(&config.dir)
+        .map_err(|e| GenericError::new(format!("[write] {} : {}", config.dir, e)))?;
+    let path = PathBuf::from(&config.dir).join(name);
+    fs::write(&path, render(snapshots, config.format)?)
+        .map_err(|e| GenericError::new(format!("[write] {} : {}", path.display(), e)))?;
+    Ok(path)
+}
+
+pub fn render(
+    snapshots: &[NodeSnapshot],
+    format: ExportFormat,
+) -> Result<String, Box<dyn std::error::Error>> {
+    match format {
+        ExportFormat::Json => Ok(serde_json::to_string_pretty(snapshots)?),
+        ExportFormat::Csv => Ok(csv(snapshots)),
+        ExportFormat::Markdown => Ok(markdown(snapshots)),
+    }
+}
+
+/// scrape every configured node (or only `node`) twice, `interval` apart so the rates have
+/// two points, and snapshot the result (used by the export subcommand)
+pub async fn collect(
+    params: &Parameters,
+    node: Option<&str>,
+    interval: Duration,
+) -> Result<Vec<NodeSnapshot>, Box<dyn std::error::Error>> {
+    if params.prometheus.is_some() {
+        return Err(Box::new(GenericError::new(
+            "[collect] export reads the exporters, use the export key in the tui for a prometheus source",
+        )));
+    }
+    let matches = |url: &str, name: &str| node.map(|x| x == url || x == name).unwrap_or(true);
+    // federated targets are kept, their instances are matched once split
+    let targets: Vec<ScrapeTarget> = ScrapeTarget::from_params(params)?
+        .into_iter()
+        .filter(|x| x.spec.split_by_instance || matches(&x.url, &x.spec.display_name()))
+        .collect();
+    let mut history = HistoryStore::new(params.history.clone());
+    let mut states: Vec<(String, String, NodeState)> = vec![];
+    for round in 0..2 {
+        if round > 0 {
+            tokio::time::sleep(interval).await;
+        }
+        let results = futures::future::join_all(targets.iter().map(scrape_with_retry)).await;
+        let now = Local::now().timestamp_millis() as f64 / 1000.0;
+        for (target, result) in targets.iter().zip(results) {
+            // a federated target is one node per instance
+            let nodes = match (&result, target.spec.split_by_instance) {
+                (Ok(families), true) => exposition::split_by_label(families, "instance")
+                    .into_iter()
+                    .map(|(k, v)| (k.clone(), k, Ok(v)))
+                    .collect(),
+                _ => vec![(target.url.clone(), target.spec.display_name(), result)],
+            };
+            for (url, name, result) in nodes {
+                if let Ok(families) = &result {
+                    history.ingest(&url, families, now);
+                }
+                let index = match states.iter().position(|x| x.0 == url) {
+                    Some(index) => index,
+                    None => {
+                        states.push((url.clone(), name, NodeState::new()));
+                        states.len() - 1
+                    }
+                };
+                states[index].2.update(result);
+            }
+        }
+    }
+    let now = Local::now().timestamp_millis() as f64 / 1000.0;
+    let snapshots: Vec<NodeSnapshot> = states
+        .iter()
+        .filter(|(url, name, _)| matches(url, name))
+        .map(|(url, name, state)| snapshot(&history, url, name, &state.status.to_string(), now))
+        .collect();
+    if snapshots.is_empty() {
+        return Err(Box::new(GenericError::new(format!(
+            "[collect] no node matches {}",
+            node.unwrap_or("the config")
+        ))));
+    }
+    Ok(snapshots)
+}
+
+// utility functions
+
+/// long format, one `node,name,timestamp,metric,labels,value` row per value
+fn csv(snapshots: &[NodeSnapshot]) -> String {
+    let mut out = String::from("node,name,timestamp,metric,labels,value\n");
+    for snap in snapshots.iter() {
+        let ts = snap
+            .timestamp
+            .map(|x| format!("{:.3}", x))
+            .unwrap_or_default();
+        let mut row = |metric: &str, labels: String, value: String| {
+            let fields = [&snap.node, &snap.name, &ts, metric, &labels, &value];
+            let fields: Vec<String> = fields.iter().map(|x| csv_field(x)).collect();
+            out.push_str(&fields.join(","));
+            out.push('\n');
+        };
+        row("status", String::new(), snap.status.clone());
+        for (metric, value) in [
+            ("hostname", &snap.hostname),
+            ("kernel", &snap.kernel),
+            ("machine", &snap.machine),
+        ] {
+            if let Some(value) = value {
+                row(metric, String::new(), value.clone());
+            }
+        }
+        row("cpu_cores", String::new(), snap.cpu_cores.to_string());
+        for (metric, value) in [
+            ("cpu_percent", snap.cpu_percent),
+            ("memory_total_bytes", snap.memory_total_bytes),
+            ("memory_available_bytes", snap.memory_available_bytes),
+            ("memory_used_percent", snap.memory_used_percent),
+            ("load1", snap.load1),
+            ("load5", snap.load5),
+            ("load15", snap.load15),
+            ("temperature_celsius", snap.temperature_celsius),
+            (
+                "network_rx_bytes_per_second",
+                snap.network_rx_bytes_per_second,
+            ),
+            (
+                "network_tx_bytes_per_second",
+                snap.network_tx_bytes_per_second,
+            ),
+            (
+                "disk_read_bytes_per_second",
+                snap.disk_read_bytes_per_second,
+            ),
+            (
+                "disk_write_bytes_per_second",
+                snap.disk_write_bytes_per_second,
+            ),
+        ] {
+            if let Some(value) = value {
+                row(metric, String::new(), format!("{:.2}", value));
+            }
+        }
+        for fs in snap.filesystems.iter() {
+            let labels = format!(
+                "device={} mountpoint={} fstype={}",
+                fs.device, fs.mountpoint, fs.fstype
+            );
+            row(
+                "filesystem_size_bytes",
+                labels.clone(),
+                format!("{:.0}", fs.size_bytes),
+            );
+            row(
+                "filesystem_avail_bytes",
+                labels.clone(),
+                format!("{:.0}", fs.avail_bytes),
+            );
+            row(
+                "filesystem_used_percent",
+                labels,
+                format!("{:.2}", fs.used_percent),
+            );
+        }
+        for interface in snap.interfaces.iter() {
+            let labels = format!("device={}", interface.device);
+            let (rx, tx) = (interface.rx_bytes_total, interface.tx_bytes_total);
+            row(
+                "network_rx_bytes_total",
+                labels.clone(),
+                format!("{:.0}", rx),
+            );
+            row("network_tx_bytes_total", labels, format!("{:.0}", tx));
+        }
+    }
+    out
+}
+
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+/// a summary row per node, then the filesystems and interfaces of each node
+fn markdown(snapshots: &[NodeSnapshot]) -> String {
+    let mut out = format!(
+        "# node snapshot {}\n\n| node | status | cpu | memory | load 1/5/15 | temp | rx/s | tx/s | fullest filesystem |\n|---|---|---:|---:|---|---:|---:|---:|---|\n",
+        Local::now().format("%Y-%m-%d %H:%M:%S")
+    );
+    for snap in snapshots.iter() {
+        let load = match (snap.load1, snap.load5, snap.load15) {
+            (Some(a), Some(b), Some(c)) => format!("{:.2} {:.2} {:.2}", a, b, c),
+            _ => "-".to_string(),
+        };
+        let fullest = snap
+            .fullest_filesystem()
+            .map(|x| format!("{} {:.1}%", x.mountpoint, x.used_percent))
+            .unwrap_or("-".to_string());
+        out.push_str(&format!(
+            "| {} | {} | {} | {} | {} | {} | {} | {} | {} |\n",
+            md_cell(&snap.name),
+            md_cell(&snap.status),
+            percent(snap.cpu_percent),
+            percent(snap.memory_used_percent),
+            load,
+            snap.temperature_celsius
+                .map(|x| format!("{:.1}°C", x))
+                .unwrap_or("-".to_string()),
+            format_rate(snap.network_rx_bytes_per_second),
+            format_rate(snap.network_tx_bytes_per_second),
+            md_cell(&fullest),
+        ));
+    }
+    for snap in snapshots.iter() {
+        out.push_str(&format!("\n## {}\n\n", md_cell(&snap.name)));
+        let host = [&snap.hostname, &snap.kernel, &snap.machine]
+            .iter()
+            .filter_map(|x| x.as_deref())
+            .collect::<Vec<&str>>()
+            .join(" ");
+        out.push_str(&format!(
+            "{} ({}), {} cores, memory {} of {} available\n",
+            md_cell(&snap.node),
+            if host.is_empty() {
+                "-".to_string()
+            } else {
+                md_cell(&host)
+            },
+            snap.cpu_cores,
+            snap.memory_available_bytes
+                .map(format_bytes)
+                .unwrap_or("-".to_string()),
+            snap.memory_total_bytes
+                .map(format_bytes)
+                .unwrap_or("-".to_string()),
+        ));
+        if !snap.filesystems.is_empty() {
+            out.push_str("\n| mountpoint | device | fstype | size | available | used |\n|---|---|---|---:|---:|---:|\n");
+            for fs in snap.filesystems.iter() {
+                out.push_str(&format!(
+                    "| {} | {} | {} | {} | {} | {:.1}% |\n",
+                    md_cell(&fs.mountpoint),
+                    md_cell(&fs.device),
+                    md_cell(&fs.fstype),
+                    format_bytes(fs.size_bytes),
+                    format_bytes(fs.avail_bytes),
+                    fs.used_percent
+                ));
+            }
+        }
+        if !snap.interfaces.is_empty() {
+            out.push_str("\n| interface | received | transmitted |\n|---|---:|---:|\n");
+            for interface in snap.interfaces.iter() {
+                out.push_str(&format!(
+                    "| {} | {} | {} |\n",
+                    md_cell(&interface.device),
+                    format_bytes(interface.rx_bytes_total),
+                    format_bytes(interface.tx_bytes_total)
+                ));
+            }
+        }
+    }
+    out
+}
+
+/// a cell is one line, pipes would end it early
+fn md_cell(value: &str) -> String {
+    value.replace('|', "\\|").replace(['\r', '\n'], " ")
+}
+
+fn percent(value: Option<f64>) -> String {
+    value
+        .map(|x| format!("{:.1}%", x))
+        .unwrap_or("-".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::snapshot::{FilesystemSnapshot, InterfaceSnapshot};
+
+    fn node() -> NodeSnapshot {
+        NodeSnapshot {
+            node: "http://db,1:9100".to_string(),
+            name: "db \"primary\"".to_string(),
+            status: "up".to_string(),
+            timestamp: Some(1700000000.0),
+            kernel: Some("6.1\nrc".to_string()),
+            cpu_cores: 4,
+            cpu_percent: Some(12.345),
+            memory_total_bytes: Some(8.0 * 1024.0 * 1024.0 * 1024.0),
+            memory_available_bytes: Some(1536.0 * 1024.0 * 1024.0),
+            network_rx_bytes_per_second: Some(2048.0),
+            filesystems: vec![FilesystemSnapshot {
+                device: "/dev/sda1".to_string(),
+                mountpoint: "/mnt/a|b".to_string(),
+                fstype: "ext4".to_string(),
+                size_bytes: 1000.0,
+                avail_bytes: 250.0,
+                used_percent: 75.0,
+            }],
+            interfaces: vec![InterfaceSnapshot {
+                device: "eth0".to_string(),
+                rx_bytes_total: 512.0,
+                tx_bytes_total: 3.0 * 1024.0 * 1024.0,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn csv_fields_are_quoted() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+    }
+
+    #[test]
+    fn csv_has_a_row_per_value() {
+        let out = csv(&[node()]);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "node,name,timestamp,metric,labels,value");
+        assert_eq!(
+            lines[1],
+            "\"http://db,1:9100\",\"db \"\"primary\"\"\",1700000000.000,status,,up"
+        );
+        assert!(out.contains(",kernel,,\"6.1\nrc\"\n"));
+        assert!(out.contains(",cpu_percent,,12.35\n"));
+        assert!(out.contains(
+            ",filesystem_used_percent,device=/dev/sda1 mountpoint=/mnt/a|b fstype=ext4,75.00\n"
+        ));
+        assert!(out.contains(",network_tx_bytes_total,device=eth0,3145728\n"));
+        // values that are not known are left out
+        assert!(!out.contains("load1"));
+    }
+
+    #[test]
+    fn markdown_cells_stay_in_their_column() {
+        assert_eq!(md_cell("a|b"), "a\\|b");
+        assert_eq!(md_cell("two\r\nlines"), "two  lines");
+        let out = markdown(&[node()]);
+        assert!(out.contains(
+            "| db \"primary\" | up | 12.3% | - | - | - | 2.0 KiB/s | - | /mnt/a\\|b 75.0% |\n"
+        ));
+        assert!(
+            out.contains(
+                "http://db,1:9100 (6.1 rc), 4 cores, memory 1.5 GiB of 8.0 GiB available\n"
+            )
+        );
+        assert!(out.contains("| /mnt/a\\|b | /dev/sda1 | ext4 | 1000 B | 250 B | 75.0% |\n"));
+        assert!(out.contains("| eth0 | 512 B | 3.0 MiB |\n"));
+    }
+
+    #[test]
+    fn json_keeps_every_field() {
+        let out = render(&[node()], ExportFormat::Json).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(value[0]["name"], "db \"primary\"");
+        assert_eq!(value[0]["filesystems"][0]["mountpoint"], "/mnt/a|b");
+        assert!(value[0]["load1"].is_null());
+    }
+}
@@ -0,0 +1,643 @@
+// module exposition
+use crate::error::generic::GenericError;
+use crate::handlers::protobuf;
+use std::collections::BTreeMap;
+
+/// accept header used for content negotiation (protobuf preferred, then openmetrics, prometheus text as fallback)
+pub const ACCEPT_HEADER: &str = "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited,application/openmetrics-text;version=1.0.0;q=0.8,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+
+/// exposition formats understood by the parser
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Prometheus,
+    OpenMetrics,
+    Protobuf,
+}
+
+impl Format {
+    /// select the format from a response content type header
+    pub fn from_content_type(content_type: &str) -> Self {
+        match content_type {
+            x if x.starts_with("application/openmetrics-text") => Format::OpenMetrics,
+            x if x.starts_with("application/vnd.google.protobuf")
+                && x.contains("io.prometheus.client.MetricFamily") =>
+            {
+                Format::Protobuf
+            }
+            &_ => Format::Prometheus,
+        }
+    }
+
+    /// guess the format of a saved exposition (files and stdin have no content type)
+    pub fn sniff(body: &[u8]) -> Self {
+        match std::str::from_utf8(body) {
+            Err(_) => Format::Protobuf,
+            Ok(text) if text.trim_end().ends_with("# EOF") => Format::OpenMetrics,
+            Ok(_) => Format::Prometheus,
+        }
+    }
+}
+
+/// metric family types as declared in the `# TYPE` line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+    GaugeHistogram,
+    Summary,
+    Info,
+    StateSet,
+    #[default]
+    Unknown,
+}
+
+impl MetricType {
+    fn from_str(value: &str) -> Self {
+        match value {
+            "counter" => MetricType::Counter,
+            "gauge" => MetricType::Gauge,
+            "histogram" => MetricType::Histogram,
+            "gaugehistogram" => MetricType::GaugeHistogram,
+            "summary" => MetricType::Summary,
+            "info" => MetricType::Info,
+            "stateset" => MetricType::StateSet,
+            &_ => MetricType::Unknown,
+        }
+    }
+
+    /// sample name suffixes allowed for a family of this type
+    fn suffixes(&self) -> &'static [&'static str] {
+        match self {
+            MetricType::Counter => &["_total", "_created"],
+            MetricType::Histogram => &["_bucket", "_count", "_sum", "_created"],
+            MetricType::GaugeHistogram => &["_gbucket", "_gcount", "_gsum"],
+            MetricType::Summary => &["_count", "_sum", "_created"],
+            MetricType::Info => &["_info"],
+            &_ => &[],
+        }
+    }
+}
+
+/// exemplar attached to a sample (openmetrics only)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exemplar {
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+    pub timestamp: Option<f64>,
+}
+
+/// a single sample, timestamps are normalised to seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+    pub timestamp: Option<f64>,
+    pub exemplar: Option<Exemplar>,
+}
+
+impl Sample {
+    pub fn label(&self, key: &str) -> Option<&str> {
+        self.labels.get(key).map(|x| x.as_str())
+    }
+}
+
+/// a metric family with its metadata and samples
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricFamily {
+    pub name: String,
+    pub help: String,
+    pub unit: String,
+    pub metric_type: MetricType,
+    pub samples: Vec<Sample>,
+}
+
+impl MetricFamily {
+    fn new(name: &str) -> Self {
+        MetricFamily {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn owns(&self, sample_name: &str) -> bool {
+        if sample_name == self.name {
+            return true;
+        }
+        match sample_name.strip_prefix(self.name.as_str()) {
+            Some(suffix) => self.metric_type.suffixes().contains(&suffix),
+            None => false,
+        }
+    }
+}
+
+/// iterate over all samples with the given name across families
+pub fn samples<'a>(
+    families: &'a [MetricFamily],
+    name: &'a str,
+) -> impl Iterator<Item = &'a Sample> {
+    families
+        .iter()
+        .flat_map(|f| f.samples.iter())
+        .filter(move |s| s.name == name)
+}
+
+/// split families by the value of a label (e.g. a `/federate` response by `instance`),
+/// samples without the label are dropped
+pub fn split_by_label(
+    families: &[MetricFamily],
+    label: &str,
+) -> BTreeMap<String, Vec<MetricFamily>> {
+    let mut nodes: BTreeMap<String, Vec<MetricFamily>> = BTreeMap::new();
+    for family in families.iter() {
+        let mut parts: BTreeMap<&str, Vec<Sample>> = BTreeMap::new();
+        for sample in family.samples.iter() {
+            if let Some(value) = sample.label(label) {
+                parts.entry(value).or_default().push(sample.clone());
+            }
+        }
+        for (value, samples) in parts {
+            nodes
+                .entry(value.to_string())
+                .or_default()
+                .push(MetricFamily {
+                    name: family.name.clone(),
+                    help: family.help.clone(),
+                    unit: family.unit.clone(),
+                    metric_type: family.metric_type,
+                    samples,
+                });
+        }
+    }
+    nodes
+}
+
+/// decode a raw response body in the given format
+pub fn decode(
+    body: &[u8],
+    format: Format,
+) -> Result<Vec<MetricFamily>, Box<dyn std::error::Error>> {
+    match format {
+        Format::Protobuf => protobuf::decode(body),
+        _ => parse(std::str::from_utf8(body)?, format),
+    }
+}
+
+/// parse a text exposition body in the given format
+pub fn parse(body: &str, format: Format) -> Result<Vec<MetricFamily>, Box<dyn std::error::Error>> {
+    let mut families: Vec<MetricFamily> = vec![];
+    for (index, raw) in body.lines().enumerate() {
+        let line = raw.trim_end_matches('\r');
+        let err = |msg: &str| GenericError::new(format!("[parse] line {} : {}", index + 1, msg));
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(comment) = line.strip_prefix('#') {
+            let comment = comment.trim_start();
+            if format == Format::OpenMetrics && comment == "EOF" {
+                break;
+            }
+            let mut parts = comment.splitn(3, ' ');
+            let keyword = parts.next().unwrap_or("");
+            if !matches!(keyword, "HELP" | "TYPE" | "UNIT") {
+                continue;
+            }
+            let name = parts.next().ok_or_else(|| err("missing metric name"))?;
+            let text = parts.next().unwrap_or("");
+            let family = family_for(&mut families, name);
+            match keyword {
+                "HELP" => family.help = unescape(text),
+                "TYPE" => family.metric_type = MetricType::from_str(text.trim()),
+                &_ => family.unit = text.trim().to_string(),
+            }
+            continue;
+        }
+        let sample = parse_sample(line, format).map_err(|e| err(&e))?;
+        match families.last_mut() {
+            Some(family) if family.owns(&sample.name) => family.samples.push(sample),
+            _ => {
+                let mut family = MetricFamily::new(&sample.name);
+                family.samples.push(sample);
+                families.push(family);
+            }
+        }
+    }
+    Ok(families)
+}
+
+/// encode families in the prometheus text format (version 0.0.4)
+pub fn encode(families: &[MetricFamily]) -> String {
+    encode_text(families, Format::Prometheus)
+}
+
+/// encode families in the openmetrics text format, which keeps the info, stateset and gauge
+/// histogram types, units and exemplars the prometheus text format has no room for
+pub fn encode_openmetrics(families: &[MetricFamily]) -> String {
+    let mut out = encode_text(families, Format::OpenMetrics);
+    out.push_str("# EOF\n");
+    out
+}
+
+// utility functions
+
+fn encode_text(families: &[MetricFamily], format: Format) -> String {
+    let openmetrics = format == Format::OpenMetrics;
+    let mut out = String::new();
+    for family in families.iter() {
+        if !family.help.is_empty() {
+            let mut help = family.help.replace('\\', "\\\\").replace('\n', "\\n");
+            if openmetrics {
+                help = help.replace('"', "\\\"");
+            }
+            out.push_str(&format!("# HELP {} {}\n", family.name, help));
+        }
+        let kind = match (family.metric_type, openmetrics) {
+            (MetricType::Counter, _) => "counter",
+            (MetricType::Gauge, _) => "gauge",
+            (MetricType::Histogram, _) => "histogram",
+            (MetricType::Summary, _) => "summary",
+            (MetricType::GaugeHistogram, true) => "gaugehistogram",
+            (MetricType::Info, true) => "info",
+            (MetricType::StateSet, true) => "stateset",
+            (_, true) => "unknown",
+            // the text format has no info, stateset or gauge histogram types
+            (_, false) => "untyped",
+        };
+        out.push_str(&format!("# TYPE {} {}\n", family.name, kind));
+        if openmetrics && !family.unit.is_empty() {
+            out.push_str(&format!("# UNIT {} {}\n", family.name, family.unit));
+        }
+        for sample in family.samples.iter() {
+            out.push_str(&sample.name);
+            out.push_str(&format_labels(&sample.labels));
+            out.push_str(&format!(" {}", format_float(sample.value)));
+            match (sample.timestamp, openmetrics) {
+                (Some(ts), true) => out.push_str(&format!(" {}", ts)),
+                (Some(ts), false) => out.push_str(&format!(" {}", (ts * 1000.0) as i64)),
+                (None, _) => {}
+            }
+            if let (Some(exemplar), true) = (&sample.exemplar, openmetrics) {
+                out.push_str(&format!(
+                    " # {} {}",
+                    format_labels(&exemplar.labels),
+                    format_float(exemplar.value)
+                ));
+                if let Some(ts) = exemplar.timestamp {
+                    out.push_str(&format!(" {}", ts));
+                }
+            }
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// `{k="v",...}`, empty without labels
+fn format_labels(labels: &BTreeMap<String, String>) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape_label(v)))
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+fn family_for<'a>(families: &'a mut Vec<MetricFamily>, name: &str) -> &'a mut MetricFamily {
+    let current = families.last().map(|f| f.name == name).unwrap_or(false);
+    if !current {
+        families.push(MetricFamily::new(name));
+    }
+    families.last_mut().unwrap()
+}
+
+fn parse_sample(line: &str, format: Format) -> Result<Sample, String> {
+    let name_end = line
+        .find(|c: char| c == '{' || c.is_whitespace())
+        .ok_or("missing value")?;
+    let name = line[..name_end].to_string();
+    if name.is_empty() {
+        return Err("missing metric name".to_string());
+    }
+    let mut rest = &line[name_end..];
+    let labels = if rest.starts_with('{') {
+        let (labels, remaining) = parse_labels(rest)?;
+        rest = remaining;
+        labels
+    } else {
+        BTreeMap::new()
+    };
+    let (values, exemplar) = match (format, rest.find(" # ")) {
+        (Format::OpenMetrics, Some(pos)) => (&rest[..pos], Some(parse_exemplar(&rest[pos + 3..])?)),
+        _ => (rest, None),
+    };
+    let mut fields = values.split_whitespace();
+    let value = parse_float(fields.next().ok_or("missing value")?)?;
+    let timestamp = match fields.next() {
+        Some(ts) => Some(parse_timestamp(ts, format)?),
+        None => None,
+    };
+    Ok(Sample {
+        name,
+        labels,
+        value,
+        timestamp,
+        exemplar,
+    })
+}
+
+fn parse_exemplar(text: &str) -> Result<Exemplar, String> {
+    let text = text.trim_start();
+    if !text.starts_with('{') {
+        return Err("exemplar without labels".to_string());
+    }
+    let (labels, rest) = parse_labels(text)?;
+    let mut fields = rest.split_whitespace();
+    let value = parse_float(fields.next().ok_or("missing exemplar value")?)?;
+    let timestamp = match fields.next() {
+        Some(ts) => Some(parse_float(ts)?),
+        None => None,
+    };
+    Ok(Exemplar {
+        labels,
+        value,
+        timestamp,
+    })
+}
+
+/// parse a `{k="v",...}` label set, returns the labels and the remaining text
+fn parse_labels(text: &str) -> Result<(BTreeMap<String, String>, &str), String> {
+    let mut labels = BTreeMap::new();
+    let mut chars = text.char_indices().skip(1).peekable();
+    loop {
+        while let Some((_, c)) = chars.peek() {
+            if *c == ',' || c.is_whitespace() {
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        let (start, c) = chars.next().ok_or("unterminated label set")?;
+        if c == '}' {
+            return Ok((labels, &text[start + 1..]));
+        }
+        let mut key = String::from(c);
+        loop {
+            match chars.next() {
+                Some((_, '=')) => break,
+                Some((_, c)) if !c.is_whitespace() => key.push(c),
+                Some(_) => {}
+                None => return Err("unterminated label name".to_string()),
+            }
+        }
+        match chars.next() {
+            Some((_, '"')) => {}
+            _ => return Err(format!("label {} value not quoted", key)),
+        }
+        let mut value = String::new();
+        loop {
+            match chars.next() {
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, c)) => value.push(c),
+                    None => return Err("unterminated escape".to_string()),
+                },
+                Some((_, '"')) => break,
+                Some((_, c)) => value.push(c),
+                None => return Err(format!("label {} value not terminated", key)),
+            }
+        }
+        labels.insert(key, value);
+    }
+}
+
+fn parse_float(value: &str) -> Result<f64, String> {
+    value
+        .parse::<f64>()
+        .map_err(|_| format!("invalid value {}", value))
+}
+
+fn parse_timestamp(value: &str, format: Format) -> Result<f64, String> {
+    let ts = parse_float(value)?;
+    match format {
+        // openmetrics timestamps are in seconds, prometheus text in milliseconds
+        Format::OpenMetrics => Ok(ts),
+        _ => Ok(ts / 1000.0),
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_float(value: f64) -> String {
+    match value {
+        x if x.is_nan() => "NaN".to_string(),
+        x if x == f64::INFINITY => "+Inf".to_string(),
+        x if x == f64::NEG_INFINITY => "-Inf".to_string(),
+        x => x.to_string(),
+    }
+}
+
+/// help text escapes (`\\`, `\n` and openmetrics' `\"`) in one left to right pass, so `\\n`
+/// stays a backslash followed by `n`, any other backslash is kept as is
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some(c @ ('\\' | '"')) => out.push(c),
+            Some(c) => {
+                out.push('\\');
+                out.push(c);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROMETHEUS: &str = r#"# HELP node_cpu_seconds_total Seconds the CPUs spent in each mode.
+# TYPE node_cpu_seconds_total counter
+node_cpu_seconds_total{cpu="0",mode="idle"} 1234.5
+node_cpu_seconds_total{cpu="0",mode="user"} 56.25 1700000000000
+# TYPE http_request_duration_seconds histogram
+http_request_duration_seconds_bucket{le="0.5"} 3
+http_request_duration_seconds_bucket{le="+Inf"} 5
+http_request_duration_seconds_sum 2.5
+http_request_duration_seconds_count 5
+node_untyped_thing 7
+"#;
+
+    #[test]
+    fn parse_prometheus_families() {
+        let families = parse(PROMETHEUS, Format::Prometheus).unwrap();
+        let names: Vec<&str> = families.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "node_cpu_seconds_total",
+                "http_request_duration_seconds",
+                "node_untyped_thing"
+            ]
+        );
+        let cpu = &families[0];
+        assert_eq!(cpu.metric_type, MetricType::Counter);
+        assert_eq!(cpu.help, "Seconds the CPUs spent in each mode.");
+        assert_eq!(cpu.samples.len(), 2);
+        assert_eq!(cpu.samples[0].label("mode"), Some("idle"));
+        assert_eq!(cpu.samples[0].timestamp, None);
+        // prometheus text timestamps are milliseconds
+        assert_eq!(cpu.samples[1].timestamp, Some(1_700_000_000.0));
+        let histogram = &families[1];
+        assert_eq!(histogram.metric_type, MetricType::Histogram);
+        assert_eq!(histogram.samples.len(), 4);
+        assert_eq!(histogram.samples[1].label("le"), Some("+Inf"));
+        assert_eq!(families[2].metric_type, MetricType::Unknown);
+    }
+
+    #[test]
+    fn parse_openmetrics() {
+        let body = r#"# TYPE build info
+build_info{version="1.2"} 1
+# TYPE queue gaugehistogram
+queue_gbucket{le="+Inf"} 4
+queue_gcount 4
+queue_gsum 10
+# TYPE requests counter
+requests_total 10 1700000000.5 # {trace_id="abc"} 1.0 1700000000
+# EOF
+ignored_after_eof 1
+"#;
+        let families = parse(body, Format::OpenMetrics).unwrap();
+        assert_eq!(families.len(), 3);
+        assert_eq!(families[0].metric_type, MetricType::Info);
+        assert_eq!(families[1].metric_type, MetricType::GaugeHistogram);
+        assert_eq!(families[1].samples.len(), 3);
+        let sample = &families[2].samples[0];
+        // openmetrics timestamps are seconds
+        assert_eq!(sample.timestamp, Some(1_700_000_000.5));
+        let exemplar = sample.exemplar.as_ref().unwrap();
+        assert_eq!(
+            exemplar.labels.get("trace_id").map(|x| x.as_str()),
+            Some("abc")
+        );
+        assert_eq!(exemplar.value, 1.0);
+        assert_eq!(exemplar.timestamp, Some(1_700_000_000.0));
+    }
+
+    #[test]
+    fn parse_escapes() {
+        let body = concat!(
+            "# HELP a backslash \\\\n not a newline, a real \\n newline\n",
+            "a{path=\"C:\\\\dir\",quote=\"say \\\"hi\\\"\",text=\"one\\ntwo\"} 1\n",
+        );
+        let families = parse(body, Format::Prometheus).unwrap();
+        assert_eq!(
+            families[0].help,
+            "backslash \\n not a newline, a real \n newline"
+        );
+        let sample = &families[0].samples[0];
+        assert_eq!(sample.label("path"), Some("C:\\dir"));
+        assert_eq!(sample.label("quote"), Some("say \"hi\""));
+        assert_eq!(sample.label("text"), Some("one\ntwo"));
+    }
+
+    #[test]
+    fn parse_special_values() {
+        let families = parse("a NaN\nb +Inf\nc -Inf\n", Format::Prometheus).unwrap();
+        assert!(families[0].samples[0].value.is_nan());
+        assert_eq!(families[1].samples[0].value, f64::INFINITY);
+        assert_eq!(families[2].samples[0].value, f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn parse_errors_name_the_line() {
+        let err = parse("ok 1\nbad{a=\"1\" 2\n", Format::Prometheus).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+        assert!(parse("novalue\n", Format::Prometheus).is_err());
+        assert!(parse("a{b=c} 1\n", Format::Prometheus).is_err());
+    }
+
+    #[test]
+    fn encode_openmetrics_keeps_types() {
+        let body = r#"# TYPE build info
+build_info{version="1.2"} 1
+# TYPE power stateset
+power{power="on"} 1
+# TYPE queue gaugehistogram
+queue_gbucket{le="+Inf"} 4 1700000000.25
+queue_gcount 4
+# TYPE temp gauge
+# UNIT temp celsius
+temp 41.5
+# TYPE requests counter
+requests_total 10 # {trace_id="abc"} 1 1700000000
+# EOF
+"#;
+        let mut families = parse(body, Format::OpenMetrics).unwrap();
+        families[0].help = "with \\ \"quotes\"\nand a newline".to_string();
+        let encoded = encode_openmetrics(&families);
+        assert!(encoded.ends_with("# EOF\n"));
+        assert_eq!(Format::sniff(encoded.as_bytes()), Format::OpenMetrics);
+        assert_eq!(parse(&encoded, Format::OpenMetrics).unwrap(), families);
+        // the prometheus text format has no room for them
+        let prometheus = parse(&encode(&families), Format::Prometheus).unwrap();
+        assert_eq!(prometheus[0].metric_type, MetricType::Unknown);
+        assert!(prometheus.iter().any(|x| x.name == "queue_gcount"));
+    }
+
+    #[test]
+    fn format_detection() {
+        assert_eq!(
+            Format::from_content_type("application/openmetrics-text; version=1.0.0"),
+            Format::OpenMetrics
+        );
+        assert_eq!(
+            Format::from_content_type(
+                "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited"
+            ),
+            Format::Protobuf
+        );
+        assert_eq!(Format::from_content_type("text/plain"), Format::Prometheus);
+        assert_eq!(Format::sniff(b"a 1\n# EOF\n"), Format::OpenMetrics);
+        assert_eq!(Format::sniff(b"a 1\n"), Format::Prometheus);
+        assert_eq!(Format::sniff(&[0x0a, 0xff, 0xfe]), Format::Protobuf);
+    }
+
+    #[test]
+    fn split_federated_families() {
+        let body = "# TYPE up gauge\nup{instance=\"a:9100\"} 1\nup{instance=\"b:9100\"} 0\nup 1\n";
+        let families = parse(body, Format::Prometheus).unwrap();
+        let nodes = split_by_label(&families, "instance");
+        assert_eq!(nodes.keys().collect::<Vec<_>>(), ["a:9100", "b:9100"]);
+        assert_eq!(nodes["b:9100"][0].metric_type, MetricType::Gauge);
+        assert_eq!(nodes["b:9100"][0].samples[0].value, 0.0);
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let families = parse(PROMETHEUS, Format::Prometheus).unwrap();
+        let mut families = families;
+        families[0].help = "with \\ and\nnewline".to_string();
+        families[0].samples[0]
+            .labels
+            .insert("odd".to_string(), "q\"\\\n".to_string());
+        let again = parse(&encode(&families), Format::Prometheus).unwrap();
+        assert_eq!(again, families);
+    }
+}
@@ -0,0 +1,123 @@
+// module fleet
+use crate::error::scrape::ScrapeError;
+use crate::handlers::exposition::MetricFamily;
+use crate::handlers::process::{MetricsData, MetricsInterface, Service};
+use chrono::{DateTime, Local};
+use custom_logger as log;
+use std::fmt;
+use std::time::Instant;
+
+/// scrape status of a node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    Unknown,
+    Up,
+    Down,
+    Timeout,
+    HttpError(u16),
+    ParseError,
+    AuthError,
+    ConfigError,
+}
+
+impl fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NodeStatus::Unknown => write!(f, "unknown"),
+            NodeStatus::Up => write!(f, "up"),
+            NodeStatus::Down => write!(f, "down"),
+            NodeStatus::Timeout => write!(f, "timeout"),
+            NodeStatus::HttpError(code) => write!(f, "http {}", code),
+            NodeStatus::ParseError => write!(f, "parse error"),
+            NodeStatus::AuthError => write!(f, "auth error"),
+            NodeStatus::ConfigError => write!(f, "config error"),
+        }
+    }
+}
+
+/// per node scrape state (each node keeps its own cpu counters)
+pub struct NodeState {
+    pub service: Service,
+    pub data: Option<MetricsData>,
+    pub last_scrape: Option<Instant>,
+    pub last_success: Option<DateTime<Local>>,
+    pub last_error: Option<String>,
+    pub status: NodeStatus,
+}
+
+impl NodeState {
+    pub fn new() -> Self {
+        NodeState {
+            service: Service::new(),
+            data: None,
+            last_scrape: None,
+            last_success: None,
+            last_error: None,
+            status: NodeStatus::Unknown,
+        }
+    }
+
+    /// apply a scrape result to this node
+    pub fn update(&mut self, result: Result<Vec<MetricFamily>, ScrapeError>) {
+        self.last_scrape = Some(Instant::now());
+        let families = match result {
+            Ok(families) => families,
+            Err(err) => {
+                log::debug!("[update] {}", err);
+                self.status = match err {
+                    ScrapeError::Down(_) => NodeStatus::Down,
+                    ScrapeError::Timeout(_) => NodeStatus::Timeout,
+                    ScrapeError::Http(code, _) => NodeStatus::HttpError(code),
+                    ScrapeError::Parse(_) => NodeStatus::ParseError,
+                    ScrapeError::Auth(_) => NodeStatus::AuthError,
+                    ScrapeError::Config(_) => NodeStatus::ConfigError,
+                };
+                self.last_error = Some(err.to_string());
+                return;
+            }
+        };
+        match self.service.get_all_metrics(&families) {
+            Ok(data) => {
+                self.data = Some(data);
+                self.status = NodeStatus::Up;
+                self.last_success = Some(Local::now());
+                self.last_error = None;
+            }
+            Err(err) => {
+                log::debug!("[update] {}", err);
+                self.status = NodeStatus::ParseError;
+                self.last_error = Some(err.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::exposition::{self, Format};
+
+    #[test]
+    fn a_failed_scrape_keeps_the_last_data() {
+        let mut state = NodeState::new();
+        assert_eq!(state.status, NodeStatus::Unknown);
+        let body = "# TYPE node_load1 gauge\nnode_load1 0.5\n";
+        state.update(Ok(exposition::parse(body, Format::Prometheus).unwrap()));
+        assert_eq!(state.status, NodeStatus::Up);
+        assert!(state.data.is_some());
+        assert!(state.last_success.is_some());
+
+        state.update(Err(ScrapeError::Http(503, "busy".to_string())));
+        assert_eq!(state.status, NodeStatus::HttpError(503));
+        assert_eq!(state.status.to_string(), "http 503");
+        assert_eq!(state.last_error.as_deref(), Some("http 503 : busy"));
+        assert!(state.data.is_some());
+
+        state.update(Err(ScrapeError::Timeout("5s".to_string())));
+        assert_eq!(state.status, NodeStatus::Timeout);
+        // the next good scrape clears the error
+        state.update(Ok(exposition::parse(body, Format::Prometheus).unwrap()));
+        assert_eq!(state.status, NodeStatus::Up);
+        assert_eq!(state.last_error, None);
+    }
+}
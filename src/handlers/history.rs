@@ -0,0 +1,674 @@
+// module history
+// bounded in-memory time series store, fed by every scrape and read by the views
+// besides the raw points every series keeps the configured rollups, a min / max / avg bucket
+// per interval, so older ranges can still be charted once the raw points are gone
+use crate::config::load::{HistoryConfig, RollupConfig};
+use crate::handlers::exposition::{MetricFamily, MetricType, Sample};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::mem::size_of;
+
+/// a series is identified by its sample name and labels
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SeriesKey {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl SeriesKey {
+    fn from_sample(sample: &Sample) -> Self {
+        SeriesKey {
+            name: sample.name.clone(),
+            labels: sample.labels.clone(),
+        }
+    }
+
+    /// rough heap size, used for the memory cap
+    fn size(&self) -> usize {
+        self.name.len()
+            + self
+                .labels
+                .iter()
+                .map(|(k, v)| k.len() + v.len() + 48)
+                .sum::<usize>()
+            + SERIES_OVERHEAD
+    }
+}
+
+/// a point in time, timestamps are unix seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub ts: f64,
+    pub value: f64,
+}
+
+/// the samples of a series within one rollup interval, `ts` is where the interval starts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub ts: f64,
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    pub count: u32,
+    /// time of the first sample
+    pub first_ts: f64,
+    /// the last sample and its time, counters are read from these
+    pub last: f64,
+    pub last_ts: f64,
+}
+
+impl Bucket {
+    fn new(ts: f64, point: Point) -> Self {
+        Bucket {
+            ts,
+            min: point.value,
+            max: point.value,
+            sum: point.value,
+            count: 1,
+            first_ts: point.ts,
+            last: point.value,
+            last_ts: point.ts,
+        }
+    }
+
+    fn add(&mut self, point: Point) {
+        self.min = self.min.min(point.value);
+        self.max = self.max.max(point.value);
+        self.sum += point.value;
+        self.count += 1;
+        self.last = point.value;
+        self.last_ts = point.ts;
+    }
+
+    pub fn avg(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/// where a range is read from, the raw points or a rollup (index into the configured rollups,
+/// finest first)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Raw,
+    Rollup { tier: usize, secs: u64 },
+}
+
+/// the value a gauge reads from a bucket (counters always read the last one)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregate {
+    Avg,
+    Min,
+    Max,
+}
+
+struct Series {
+    metric_type: MetricType,
+    points: VecDeque<Point>,
+    /// one ring per rollup, same order as the config
+    rollups: Vec<VecDeque<Bucket>>,
+}
+
+/// map entry, ring buffer and bookkeeping per series
+const SERIES_OVERHEAD: usize = 128;
+/// most points a chart reads per series, a coarser rollup is used past it
+const MAX_CHART_POINTS: f64 = 2000.0;
+
+/// points per node and series, bounded by retention, count per series, series per node and memory
+pub struct HistoryStore {
+    config: HistoryConfig,
+    nodes: HashMap<String, BTreeMap<SeriesKey, Series>>,
+    bytes: usize,
+    points: usize,
+    buckets: usize,
+    /// series not recorded because their node hit `max_series`
+    dropped: HashSet<(String, SeriesKey)>,
+}
+
+impl HistoryStore {
+    pub fn new(mut config: HistoryConfig) -> Self {
+        config.rollups.retain(|x| x.resolution_secs > 0);
+        config.rollups.sort_by_key(|x| x.resolution_secs);
+        HistoryStore {
+            config,
+            nodes: HashMap::new(),
+            bytes: 0,
+            points: 0,
+            buckets: 0,
+            dropped: HashSet::new(),
+        }
+    }
+
+    /// record a scrape, samples without a timestamp are taken at `now` (out of order points are skipped)
+    pub fn ingest(&mut self, node: &str, families: &[MetricFamily], now: f64) {
+        let max_series = self.config.max_series;
+        let max_samples = self.config.max_samples.max(1);
+        let rollups = &self.config.rollups;
+        let series = self.nodes.entry(node.to_string()).or_default();
+        for family in families.iter() {
+            for sample in family.samples.iter() {
+                let ts = sample.timestamp.unwrap_or(now);
+                let key = SeriesKey::from_sample(sample);
+                if !series.contains_key(&key) {
+                    if series.len() >= max_series {
+                        self.dropped.insert((node.to_string(), key));
+                        continue;
+                    }
+                    self.bytes += key.size();
+                    let entry = Series {
+                        metric_type: family.metric_type,
+                        points: VecDeque::new(),
+                        rollups: vec![VecDeque::new(); rollups.len()],
+                    };
+                    series.insert(key.clone(), entry);
+                }
+                let Some(entry) = series.get_mut(&key) else {
+                    continue;
+                };
+                let newest = entry
+                    .points
+                    .back()
+                    .map(|x| x.ts)
+                    .into_iter()
+                    .chain(
+                        entry
+                            .rollups
+                            .iter()
+                            .filter_map(|x| x.back())
+                            .map(|x| x.last_ts),
+                    )
+                    .reduce(f64::max);
+                if newest.map(|x| ts <= x).unwrap_or(false) {
+                    continue;
+                }
+                let point = Point {
+                    ts,
+                    value: sample.value,
+                };
+                entry.points.push_back(point);
+                self.points += 1;
+                if entry.points.len() > max_samples {
+                    entry.points.pop_front();
+                    self.points -= 1;
+                }
+                for (buckets, rollup) in entry.rollups.iter_mut().zip(rollups.iter()) {
+                    let start = bucket_start(ts, rollup);
+                    match buckets.back_mut() {
+                        Some(bucket) if bucket.ts == start => bucket.add(point),
+                        _ => {
+                            buckets.push_back(Bucket::new(start, point));
+                            self.buckets += 1;
+                        }
+                    }
+                }
+            }
+        }
+        self.expire(node, now);
+        self.enforce_memory();
+    }
+
+    /// forget nodes that are no longer listed
+    pub fn retain_nodes(&mut self, keep: impl Fn(&str) -> bool) {
+        let removed: Vec<String> = self
+            .nodes
+            .keys()
+            .filter(|x| !keep(x.as_str()))
+            .cloned()
+            .collect();
+        self.dropped.retain(|(node, _)| keep(node.as_str()));
+        for node in removed {
+            if let Some(series) = self.nodes.remove(&node) {
+                for (key, entry) in series.iter() {
+                    self.bytes -= key.size();
+                    self.points -= entry.points.len();
+                    self.buckets -= entry.rollups.iter().map(|x| x.len()).sum::<usize>();
+                }
+            }
+        }
+    }
+
+    /// every series of a node with the given sample name
+    pub fn series(&self, node: &str, name: &str) -> Vec<(&SeriesKey, &VecDeque<Point>)> {
+        match self.nodes.get(node) {
+            Some(series) => series
+                .iter()
+                .filter(|(key, _)| key.name == name)
+                .map(|(key, entry)| (key, &entry.points))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// points of every series named `name` within `[from, to]` at `resolution`, a bucket reads
+    /// as a point at its last sample, with the last value for counters and `aggregate` otherwise
+    pub fn points(
+        &self,
+        node: &str,
+        name: &str,
+        resolution: Resolution,
+        aggregate: Aggregate,
+        from: f64,
+        to: f64,
+    ) -> Vec<(&SeriesKey, Vec<Point>)> {
+        let Some(series) = self.nodes.get(node) else {
+            return vec![];
+        };
+        series
+            .iter()
+            .filter(|(key, _)| key.name == name)
+            .map(|(key, entry)| {
+                // points and buckets are in time order
+                let points = match resolution {
+                    Resolution::Raw => {
+                        let start = entry.points.partition_point(|x| x.ts < from);
+                        let end = entry.points.partition_point(|x| x.ts <= to);
+                        entry.points.range(start..end.max(start)).copied().collect()
+                    }
+                    Resolution::Rollup { tier, .. } => entry
+                        .rollups
+                        .get(tier)
+                        .into_iter()
+                        .flat_map(|buckets| {
+                            let start = buckets.partition_point(|x| x.last_ts < from);
+                            let end = buckets.partition_point(|x| x.last_ts <= to);
+                            buckets.range(start..end.max(start))
+                        })
+                        .map(|x| Point {
+                            ts: x.last_ts,
+                            value: match (entry.metric_type, aggregate) {
+                                (MetricType::Counter, _) => x.last,
+                                (_, Aggregate::Avg) => x.avg(),
+                                (_, Aggregate::Min) => x.min,
+                                (_, Aggregate::Max) => x.max,
+                            },
+                        })
+                        .collect(),
+                };
+                (key, points)
+            })
+            .collect()
+    }
+
+    /// the finest resolution that holds `[from, to]` for a node: raw when the raw points reach
+    /// back to its start, else the first rollup that does without going past `MAX_CHART_POINTS`,
+    /// else the coarsest rollup (it reaches back the furthest)
+    pub fn resolution(&self, node: &str, from: f64, to: f64) -> Resolution {
+        let Some(series) = self.nodes.get(node) else {
+            return Resolution::Raw;
+        };
+        // nothing before the oldest point to wait for, and a few missing scrapes at the edge
+        // of what is held are fine
+        let held = self.span(node).map(|x| x.0.max(from)).unwrap_or(from);
+        let start = held + (to - held).max(0.0) * 0.05;
+        let raw = series
+            .values()
+            .filter_map(|x| x.points.front())
+            .map(|x| x.ts);
+        if raw.reduce(f64::min).map(|x| x <= start).unwrap_or(false) {
+            return Resolution::Raw;
+        }
+        let mut result = Resolution::Raw;
+        for (tier, rollup) in self.config.rollups.iter().enumerate() {
+            result = Resolution::Rollup {
+                tier,
+                secs: rollup.resolution_secs,
+            };
+            let oldest = series
+                .values()
+                .filter_map(|x| x.rollups[tier].front())
+                .map(|x| x.first_ts)
+                .reduce(f64::min);
+            let count = (to - from) / rollup.resolution_secs as f64;
+            if oldest.map(|x| x <= start).unwrap_or(false) && count <= MAX_CHART_POINTS {
+                break;
+            }
+        }
+        result
+    }
+
+    /// the node as it was at `ts`, the last point at or before it of every series
+    pub fn at(&self, node: &str, ts: f64) -> Vec<MetricFamily> {
+        let mut families: Vec<MetricFamily> = vec![];
+        let Some(series) = self.nodes.get(node) else {
+            return families;
+        };
+        for (key, entry) in series.iter() {
+            let Some(point) = last_at(&entry.points, ts) else {
+                continue;
+            };
+            let sample = Sample {
+                name: key.name.clone(),
+                labels: key.labels.clone(),
+                value: point.value,
+                timestamp: Some(point.ts),
+                exemplar: None,
+            };
+            match families.iter_mut().find(|x| x.name == key.name) {
+                Some(family) => family.samples.push(sample),
+                None => families.push(MetricFamily {
+                    name: key.name.clone(),
+                    metric_type: entry.metric_type,
+                    samples: vec![sample],
+                    ..Default::default()
+                }),
+            }
+        }
+        families
+    }
+
+    /// time of the newest point at or before `ts` of any series of a node (its scrape then)
+    pub fn newest_at(&self, node: &str, ts: f64) -> Option<f64> {
+        self.nodes
+            .get(node)?
+            .values()
+            .filter_map(|x| last_at(&x.points, ts))
+            .map(|x| x.ts)
+            .reduce(f64::max)
+    }
+
+    /// every distinct timestamp held for a node (one per scrape), oldest first
+    pub fn steps(&self, node: &str) -> Vec<f64> {
+        let Some(series) = self.nodes.get(node) else {
+            return vec![];
+        };
+        let mut steps: Vec<f64> = series
+            .values()
+            .flat_map(|x| x.points.iter().map(|p| p.ts))
+            .collect();
+        steps.sort_by(|a, b| a.total_cmp(b));
+        steps.dedup();
+        steps
+    }
+
+    /// oldest and newest timestamp held for a node, rollups included
+    pub fn span(&self, node: &str) -> Option<(f64, f64)> {
+        let series = self.nodes.get(node)?;
+        let first = series
+            .values()
+            .flat_map(|x| {
+                let buckets = x
+                    .rollups
+                    .iter()
+                    .filter_map(|b| b.front())
+                    .map(|b| b.first_ts);
+                x.points.front().map(|p| p.ts).into_iter().chain(buckets)
+            })
+            .reduce(f64::min)?;
+        let last = series
+            .values()
+            .flat_map(|x| {
+                let buckets = x.rollups.iter().filter_map(|b| b.back()).map(|b| b.last_ts);
+                x.points.back().map(|p| p.ts).into_iter().chain(buckets)
+            })
+            .reduce(f64::max)?;
+        Some((first, last))
+    }
+
+    /// distinct series not recorded because their node hit `max_series`
+    pub fn dropped_series(&self) -> usize {
+        self.dropped.len()
+    }
+
+    /// (series, points and buckets, approximate bytes) across all nodes
+    pub fn stats(&self) -> (usize, usize, usize) {
+        let series = self.nodes.values().map(|x| x.len()).sum();
+        (series, self.points + self.buckets, self.size())
+    }
+
+    /// drop points and buckets of a node that are past their retention, and series left empty
+    fn expire(&mut self, node: &str, now: f64) {
+        let cutoff = now - self.config.retention_secs as f64;
+        let rollups = &self.config.rollups;
+        let Some(series) = self.nodes.get_mut(node) else {
+            return;
+        };
+        series.retain(|key, entry| {
+            while entry.points.front().map(|x| x.ts < cutoff).unwrap_or(false) {
+                entry.points.pop_front();
+                self.points -= 1;
+            }
+            for (buckets, rollup) in entry.rollups.iter_mut().zip(rollups.iter()) {
+                let cutoff = now - rollup.retention_secs as f64;
+                while buckets.front().map(|x| x.last_ts < cutoff).unwrap_or(false) {
+                    buckets.pop_front();
+                    self.buckets -= 1;
+                }
+            }
+            if entry.points.is_empty() && entry.rollups.iter().all(|x| x.is_empty()) {
+                self.bytes -= key.size();
+                return false;
+            }
+            true
+        });
+    }
+
+    /// trim the oldest tenth of every series and rollup until the store fits the memory cap
+    fn enforce_memory(&mut self) {
+        let cap = self.config.max_memory_mb * 1024 * 1024;
+        while self.size() > cap && self.points + self.buckets > 0 {
+            for series in self.nodes.values_mut() {
+                for entry in series.values_mut() {
+                    let trim = entry.points.len().div_ceil(10);
+                    entry.points.drain(..trim);
+                    self.points -= trim;
+                    for buckets in entry.rollups.iter_mut() {
+                        let trim = buckets.len().div_ceil(10);
+                        buckets.drain(..trim);
+                        self.buckets -= trim;
+                    }
+                }
+            }
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.bytes + self.points * size_of::<Point>() + self.buckets * size_of::<Bucket>()
+    }
+}
+
+// utility functions
+
+/// the last point at or before `ts`, points are in time order
+fn last_at(points: &VecDeque<Point>, ts: f64) -> Option<&Point> {
+    match points.partition_point(|x| x.ts <= ts) {
+        0 => None,
+        end => points.get(end - 1),
+    }
+}
+
+/// start of the rollup interval holding `ts`
+fn bucket_start(ts: f64, rollup: &RollupConfig) -> f64 {
+    let secs = rollup.resolution_secs as f64;
+    (ts / secs).floor() * secs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gauge(name: &str, series: &[(&str, f64)]) -> MetricFamily {
+        MetricFamily {
+            name: name.to_string(),
+            metric_type: MetricType::Gauge,
+            samples: series
+                .iter()
+                .map(|(device, value)| Sample {
+                    name: name.to_string(),
+                    labels: BTreeMap::from([("device".to_string(), device.to_string())]),
+                    value: *value,
+                    timestamp: None,
+                    exemplar: None,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn values(store: &HistoryStore, node: &str, name: &str) -> Vec<(f64, f64)> {
+        store
+            .series(node, name)
+            .iter()
+            .flat_map(|(_, points)| points.iter().map(|x| (x.ts, x.value)))
+            .collect()
+    }
+
+    #[test]
+    fn series_past_the_cap_are_dropped_and_counted() {
+        let mut store = HistoryStore::new(HistoryConfig {
+            max_series: 2,
+            ..Default::default()
+        });
+        let families = [gauge("g", &[("a", 1.0), ("b", 2.0), ("c", 3.0)])];
+        store.ingest("n", &families, 10.0);
+        store.ingest("n", &families, 20.0);
+        assert_eq!(store.stats().0, 2);
+        assert_eq!(store.dropped_series(), 1);
+    }
+
+    #[test]
+    fn points_are_bounded_by_count_and_retention() {
+        let mut store = HistoryStore::new(HistoryConfig {
+            max_samples: 3,
+            retention_secs: 100,
+            ..Default::default()
+        });
+        for ts in [10.0, 20.0, 30.0, 40.0] {
+            store.ingest("n", &[gauge("g", &[("a", ts)])], ts);
+        }
+        assert_eq!(
+            values(&store, "n", "g"),
+            [(20.0, 20.0), (30.0, 30.0), (40.0, 40.0)]
+        );
+        // retention is checked against the time of the last ingest
+        store.ingest("n", &[gauge("g", &[("a", 135.0)])], 135.0);
+        assert_eq!(values(&store, "n", "g"), [(40.0, 40.0), (135.0, 135.0)]);
+        assert_eq!(store.stats().1, 2);
+    }
+
+    #[test]
+    fn out_of_order_points_are_skipped() {
+        let mut store = HistoryStore::new(HistoryConfig::default());
+        store.ingest("n", &[gauge("g", &[("a", 1.0)])], 20.0);
+        store.ingest("n", &[gauge("g", &[("a", 2.0)])], 10.0);
+        store.ingest("n", &[gauge("g", &[("a", 3.0)])], 20.0);
+        assert_eq!(values(&store, "n", "g"), [(20.0, 1.0)]);
+    }
+
+    #[test]
+    fn node_as_of_a_time() {
+        let mut store = HistoryStore::new(HistoryConfig::default());
+        store.ingest("n", &[gauge("g", &[("a", 1.0), ("b", 5.0)])], 10.0);
+        store.ingest("n", &[gauge("g", &[("a", 2.0)])], 20.0);
+        assert_eq!(store.steps("n"), [10.0, 20.0]);
+        assert_eq!(store.span("n"), Some((10.0, 20.0)));
+        let families = store.at("n", 15.0);
+        assert_eq!(families.len(), 1);
+        let at: Vec<f64> = families[0].samples.iter().map(|x| x.value).collect();
+        assert_eq!(at, [1.0, 5.0]);
+        let at: Vec<f64> = store.at("n", 25.0)[0]
+            .samples
+            .iter()
+            .map(|x| x.value)
+            .collect();
+        assert_eq!(at, [2.0, 5.0]);
+        assert!(store.at("n", 5.0).is_empty());
+        assert_eq!(store.newest_at("n", 15.0), Some(10.0));
+        assert_eq!(store.newest_at("n", 20.0), Some(20.0));
+        assert_eq!(store.newest_at("n", 5.0), None);
+        assert_eq!(store.newest_at("other", 20.0), None);
+    }
+
+    #[test]
+    fn removed_nodes_free_their_points() {
+        let mut store = HistoryStore::new(HistoryConfig::default());
+        store.ingest("keep", &[gauge("g", &[("a", 1.0)])], 10.0);
+        store.ingest("drop", &[gauge("g", &[("a", 1.0), ("b", 1.0)])], 10.0);
+        let before = store.stats().2;
+        store.retain_nodes(|x| x == "keep");
+        assert_eq!(store.stats().0, 1);
+        assert_eq!(store.stats().1, 1);
+        assert!(store.stats().2 < before);
+        assert!(store.steps("drop").is_empty());
+    }
+
+    #[test]
+    fn memory_cap_trims_the_oldest_points() {
+        let mut store = HistoryStore::new(HistoryConfig {
+            max_memory_mb: 1,
+            max_samples: 1_000_000,
+            retention_secs: 1_000_000,
+            ..Default::default()
+        });
+        for ts in 0..100_000 {
+            store.ingest("n", &[gauge("g", &[("a", ts as f64)])], ts as f64);
+        }
+        assert!(store.stats().2 <= 1024 * 1024);
+        let points = values(&store, "n", "g");
+        assert_eq!(points.last(), Some(&(99_999.0, 99_999.0)));
+        assert!(points[0].0 > 0.0);
+    }
+
+    fn rollup_store() -> HistoryStore {
+        let mut store = HistoryStore::new(HistoryConfig {
+            retention_secs: 100,
+            rollups: vec![
+                RollupConfig {
+                    resolution_secs: 600,
+                    retention_secs: 86400,
+                },
+                RollupConfig {
+                    resolution_secs: 60,
+                    retention_secs: 3600,
+                },
+            ],
+            ..Default::default()
+        });
+        for ts in (0..=1000).step_by(10) {
+            let ts = ts as f64;
+            let mut counter = gauge("c", &[("a", ts * 2.0)]);
+            counter.metric_type = MetricType::Counter;
+            store.ingest("n", &[gauge("g", &[("a", ts)]), counter], ts);
+        }
+        store
+    }
+
+    #[test]
+    fn rollup_buckets_aggregate_each_interval() {
+        let store = rollup_store();
+        // the rollups are sorted finest first
+        let tier = Resolution::Rollup { tier: 0, secs: 60 };
+        let read = |name: &str, aggregate: Aggregate| -> Vec<(f64, f64)> {
+            store
+                .points("n", name, tier, aggregate, 0.0, 60.0)
+                .iter()
+                .flat_map(|(_, points)| points.iter().map(|x| (x.ts, x.value)))
+                .collect()
+        };
+        // the first interval holds 0..=50, read at its last sample
+        assert_eq!(read("g", Aggregate::Avg), [(50.0, 25.0)]);
+        assert_eq!(read("g", Aggregate::Min), [(50.0, 0.0)]);
+        assert_eq!(read("g", Aggregate::Max), [(50.0, 50.0)]);
+        assert_eq!(read("c", Aggregate::Min), [(50.0, 100.0)]);
+    }
+
+    #[test]
+    fn raw_points_expire_before_the_rollups() {
+        let store = rollup_store();
+        assert_eq!(values(&store, "n", "g").first(), Some(&(900.0, 900.0)));
+        assert_eq!(store.span("n"), Some((0.0, 1000.0)));
+    }
+
+    #[test]
+    fn resolution_follows_the_range() {
+        let store = rollup_store();
+        assert_eq!(store.resolution("n", 900.0, 1000.0), Resolution::Raw);
+        assert_eq!(
+            store.resolution("n", 0.0, 1000.0),
+            Resolution::Rollup { tier: 0, secs: 60 }
+        );
+        // too many 1m points for the chart
+        assert_eq!(
+            store.resolution("n", -200_000.0, 1000.0),
+            Resolution::Rollup { tier: 1, secs: 600 }
+        );
+        assert_eq!(store.resolution("other", 0.0, 1000.0), Resolution::Raw);
+    }
+}
@@ -0,0 +1,210 @@
+// module influx
+// influxdb line protocol output, posted to a v1 or v2 write url or sent as udp datagrams
+//
+// a point is `<metric name>,<labels as tags> value=<value> <unix nanoseconds>`
+use crate::handlers::sink::{self, Point, SendError};
+use crate::handlers::target::ScrapeTarget;
+use tokio::net::UdpSocket;
+
+/// datagrams stay under a typical mtu, a longer line is sent on its own
+const MAX_DATAGRAM: usize = 1400;
+
+/// write a batch, over udp for a `udp://host:port` url
+pub async fn send(server: &ScrapeTarget, points: &[Point]) -> Result<(), SendError> {
+    let lines = encode(points);
+    if let Some(address) = server.url.strip_prefix("udp://") {
+        return send_udp(address, &lines).await;
+    }
+    let req = server
+        .post(server.url.clone())
+        .map_err(|e| SendError::Retry(e.to_string()))?
+        .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(
+            reqwest::header::USER_AGENT,
+            format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        )
+        .body(lines.join("\n"));
+    sink::check(req).await
+}
+
+/// one line per point, the line protocol has no NaN or infinity so those are skipped
+pub fn encode(points: &[Point]) -> Vec<String> {
+    points
+        .iter()
+        .filter(|x| x.value.is_finite())
+        .map(|point| {
+            let mut line = escape(&point.name, ", ");
+            // the line protocol has no empty tag values
+            for (k, v) in point.all_labels().iter().filter(|(_, v)| !v.is_empty()) {
+                line.push_str(&format!(",{}={}", escape(k, ",= "), escape(v, ",= ")));
+            }
+            line.push_str(&format!(" value={} {}", point.value, point.unix_nanos()));
+            line
+        })
+        .collect()
+}
+
+// utility functions
+
+async fn send_udp(address: &str, lines: &[String]) -> Result<(), SendError> {
+    let socket = UdpSocket::bind("0.0.0.0:0")
+        .await
+        .map_err(|e| SendError::Retry(format!("udp : {}", e)))?;
+    socket
+        .connect(address)
+        .await
+        .map_err(|e| SendError::Retry(format!("udp {} : {}", address, e)))?;
+    let mut datagram = String::new();
+    for line in lines.iter() {
+        if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM {
+            send_datagram(&socket, address, &datagram).await?;
+            datagram.clear();
+        }
+        datagram.push_str(line);
+        datagram.push('\n');
+    }
+    if !datagram.is_empty() {
+        send_datagram(&socket, address, &datagram).await?;
+    }
+    Ok(())
+}
+
+async fn send_datagram(socket: &UdpSocket, address: &str, datagram: &str) -> Result<(), SendError> {
+    socket
+        .send(datagram.as_bytes())
+        .await
+        .map(|_| ())
+        .map_err(|e| SendError::Retry(format!("udp {} : {}", address, e)))
+}
+
+/// backslash before each of `special`, and before backslashes
+fn escape(value: &str, special: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || special.contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::load::{Parameters, Target};
+    use crate::handlers::receiver;
+    use std::collections::BTreeMap;
+
+    fn point(name: &str, labels: &[(&str, &str)], value: f64) -> Point {
+        Point {
+            name: name.to_string(),
+            resource: BTreeMap::from([("job".to_string(), "node".to_string())]),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            value,
+            ts: 1700000000.123,
+            monotonic: false,
+        }
+    }
+
+    fn server(url: &str) -> ScrapeTarget {
+        ScrapeTarget::new(&Target::from_url(url), &Parameters::offline(vec![])).unwrap()
+    }
+
+    #[test]
+    fn names_and_tags_are_escaped() {
+        let lines = encode(&[
+            point("node_load1", &[], 0.5),
+            point(
+                "odd name,x=y",
+                &[("mount point", "/mnt/a,b=c d"), ("back\\slash", "v\\")],
+                1.0,
+            ),
+        ]);
+        assert_eq!(
+            lines[0],
+            "node_load1,job=node value=0.5 1700000000123000000"
+        );
+        // the measurement escapes commas and spaces, tag keys and values also equal signs
+        assert_eq!(
+            lines[1],
+            "odd\\ name\\,x=y,back\\\\slash=v\\\\,job=node,mount\\ point=/mnt/a\\,b\\=c\\ d value=1 1700000000123000000"
+        );
+    }
+
+    #[test]
+    fn values_the_line_protocol_cannot_hold_are_skipped() {
+        let lines = encode(&[
+            point("a", &[], f64::NAN),
+            point("b", &[], f64::INFINITY),
+            point("c", &[], f64::NEG_INFINITY),
+            point("d", &[("empty", "")], -2.5),
+        ]);
+        assert_eq!(lines, ["d,job=node value=-2.5 1700000000123000000"]);
+    }
+
+    #[tokio::test]
+    async fn http_writes_a_line_per_point() {
+        let (address, mut requests) = receiver::serve(vec![]).await;
+        let url = format!("http://{}/api/v2/write?org=lab&bucket=nodes", address);
+        let points = [point("a", &[], 1.0), point("b", &[], 2.0)];
+        assert!(send(&server(&url), &points).await.is_ok());
+        let request = requests.recv().await.unwrap();
+        assert_eq!(
+            request.line(),
+            "POST /api/v2/write?org=lab&bucket=nodes HTTP/1.1"
+        );
+        assert_eq!(
+            request.header("content-type"),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(
+            String::from_utf8(request.body).unwrap(),
+            "a,job=node value=1 1700000000123000000\nb,job=node value=2 1700000000123000000"
+        );
+    }
+
+    #[tokio::test]
+    async fn udp_datagrams_stay_under_the_limit() {
+        let (address, mut datagrams) = receiver::serve_udp().await;
+        let mut points: Vec<Point> = (0..60)
+            .map(|x| {
+                point(
+                    "node_network_receive_bytes_total",
+                    &[("device", &format!("eth{}", x))],
+                    1.0,
+                )
+            })
+            .collect();
+        // a line longer than a datagram goes on its own
+        let long = "x".repeat(MAX_DATAGRAM);
+        points.insert(30, point("long", &[("value", &long)], 1.0));
+        let lines = encode(&points);
+        assert!(
+            send(&server(&format!("udp://{}", address)), &points)
+                .await
+                .is_ok()
+        );
+
+        let mut received: Vec<String> = vec![];
+        let mut sizes: Vec<usize> = vec![];
+        while received.len() < lines.len() {
+            let datagram =
+                tokio::time::timeout(std::time::Duration::from_secs(5), datagrams.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+            sizes.push(datagram.len());
+            let text = String::from_utf8(datagram).unwrap();
+            // every datagram ends with a complete line
+            assert!(text.ends_with('\n'));
+            received.extend(text.lines().map(|x| x.to_string()));
+        }
+        assert_eq!(received, lines);
+        assert!(sizes.len() >= 4);
+        assert_eq!(sizes.iter().filter(|x| **x > MAX_DATAGRAM).count(), 1);
+    }
+}
@@ -0,0 +1,673 @@
+// module local
+// built-in collector for the local host, reads /proc, /sys and statvfs directly and
+// produces the same series as node_exporter (so every panel works without an exporter)
+use crate::error::scrape::ScrapeError;
+use crate::handlers::exposition::{MetricFamily, MetricType, Sample};
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub const LOCAL_URL: &str = "local://";
+
+/// pseudo and virtual filesystems left out of the filesystem series
+const IGNORED_FS: &[&str] = &[
+    "autofs",
+    "binfmt_misc",
+    "bpf",
+    "cgroup",
+    "cgroup2",
+    "configfs",
+    "debugfs",
+    "devpts",
+    "devtmpfs",
+    "fusectl",
+    "hugetlbfs",
+    "mqueue",
+    "nsfs",
+    "proc",
+    "pstore",
+    "rpc_pipefs",
+    "securityfs",
+    "selinuxfs",
+    "squashfs",
+    "sysfs",
+    "tracefs",
+];
+
+/// network filesystems are left out too, statvfs blocks for as long as their server is gone
+const NETWORK_FS: &[&str] = &[
+    "9p",
+    "afs",
+    "ceph",
+    "cifs",
+    "fuse.sshfs",
+    "glusterfs",
+    "lustre",
+    "ncpfs",
+    "nfs",
+    "nfs4",
+    "smb3",
+    "smbfs",
+];
+
+/// cpu modes in /proc/stat column order
+const CPU_MODES: &[&str] = &[
+    "user", "nice", "system", "idle", "iowait", "irq", "softirq", "steal",
+];
+
+/// families in the order they are first added
+#[derive(Default)]
+struct Families(Vec<MetricFamily>);
+
+impl Families {
+    fn push(&mut self, name: &str, metric_type: MetricType, labels: &[(&str, &str)], value: f64) {
+        let sample = Sample {
+            name: name.to_string(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            value,
+            timestamp: None,
+            exemplar: None,
+        };
+        match self.0.iter_mut().find(|x| x.name == name) {
+            Some(family) => family.samples.push(sample),
+            None => self.0.push(MetricFamily {
+                name: name.to_string(),
+                metric_type,
+                samples: vec![sample],
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+/// collect the local host series (blocking reads run off the async workers)
+pub async fn collect() -> Result<Vec<MetricFamily>, ScrapeError> {
+    tokio::task::spawn_blocking(read_all)
+        .await
+        .map_err(|e| ScrapeError::Down(format!("local : {}", e)))?
+        .map_err(|e| ScrapeError::Down(format!("local : {}", e)))
+}
+
+fn read_all() -> io::Result<Vec<MetricFamily>> {
+    let mut families = Families::default();
+    // cpu times are in clock ticks
+    let ticks = match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        x if x > 0 => x as f64,
+        _ => 100.0,
+    };
+    parse_stat(&mut families, &fs::read_to_string("/proc/stat")?, ticks);
+    parse_meminfo(&mut families, &fs::read_to_string("/proc/meminfo")?);
+    parse_loadavg(&mut families, &fs::read_to_string("/proc/loadavg")?);
+    parse_net_dev(&mut families, &fs::read_to_string("/proc/net/dev")?);
+    parse_diskstats(&mut families, &fs::read_to_string("/proc/diskstats")?);
+    read_filesystems(&mut families, &fs::read_to_string("/proc/mounts")?);
+    read_thermal(&mut families);
+    read_uname(&mut families);
+    Ok(families.0)
+}
+
+/// /proc/stat, cpu times are divided by `ticks` (clock ticks per second)
+fn parse_stat(families: &mut Families, data: &str, ticks: f64) {
+    for line in data.lines() {
+        let mut fields = line.split_whitespace();
+        let key = fields.next().unwrap_or("");
+        let values: Vec<f64> = fields.filter_map(|x| x.parse::<f64>().ok()).collect();
+        let first = values.first().copied().unwrap_or(0.0);
+        match key {
+            // the aggregate "cpu" line is left out, node_exporter only exposes per cpu series
+            "cpu" => {}
+            x if x.starts_with("cpu") => {
+                let cpu = &x[3..];
+                for (mode, value) in CPU_MODES.iter().zip(values.iter()) {
+                    families.push(
+                        "node_cpu_seconds_total",
+                        MetricType::Counter,
+                        &[("cpu", cpu), ("mode", mode)],
+                        value / ticks,
+                    );
+                }
+            }
+            "ctxt" => families.push(
+                "node_context_switches_total",
+                MetricType::Counter,
+                &[],
+                first,
+            ),
+            "btime" => families.push("node_boot_time_seconds", MetricType::Gauge, &[], first),
+            "processes" => families.push("node_forks_total", MetricType::Counter, &[], first),
+            "procs_running" => families.push("node_procs_running", MetricType::Gauge, &[], first),
+            "procs_blocked" => families.push("node_procs_blocked", MetricType::Gauge, &[], first),
+            &_ => {}
+        }
+    }
+}
+
+fn parse_meminfo(families: &mut Families, data: &str) {
+    for line in data.lines() {
+        let Some((key, rest)) = line.split_once(':') else {
+            continue;
+        };
+        let mut fields = rest.split_whitespace();
+        let Some(Ok(value)) = fields.next().map(|x| x.parse::<f64>()) else {
+            continue;
+        };
+        // `Active(anon)` becomes `Active_anon`, like node_exporter
+        let key = key.replace('(', "_").replace(')', "");
+        match fields.next() {
+            Some("kB") => families.push(
+                &format!("node_memory_{}_bytes", key),
+                MetricType::Gauge,
+                &[],
+                value * 1024.0,
+            ),
+            _ => families.push(
+                &format!("node_memory_{}", key),
+                MetricType::Gauge,
+                &[],
+                value,
+            ),
+        }
+    }
+}
+
+fn parse_loadavg(families: &mut Families, data: &str) {
+    for (name, value) in ["node_load1", "node_load5", "node_load15"]
+        .iter()
+        .zip(data.split_whitespace())
+    {
+        if let Ok(value) = value.parse::<f64>() {
+            families.push(name, MetricType::Gauge, &[], value);
+        }
+    }
+}
+
+fn parse_net_dev(families: &mut Families, data: &str) {
+    // receive and transmit columns (8 each), only the first four of each are exposed
+    let columns = [
+        (0, "node_network_receive_bytes_total"),
+        (1, "node_network_receive_packets_total"),
+        (2, "node_network_receive_errs_total"),
+        (3, "node_network_receive_drop_total"),
+        (8, "node_network_transmit_bytes_total"),
+        (9, "node_network_transmit_packets_total"),
+        (10, "node_network_transmit_errs_total"),
+        (11, "node_network_transmit_drop_total"),
+    ];
+    for line in data.lines().skip(2) {
+        let Some((device, rest)) = line.split_once(':') else {
+            continue;
+        };
+        let values: Vec<f64> = rest
+            .split_whitespace()
+            .filter_map(|x| x.parse::<f64>().ok())
+            .collect();
+        for (index, name) in columns.iter() {
+            if let Some(value) = values.get(*index) {
+                families.push(
+                    name,
+                    MetricType::Counter,
+                    &[("device", device.trim())],
+                    *value,
+                );
+            }
+        }
+    }
+}
+
+fn parse_diskstats(families: &mut Families, data: &str) {
+    // (column after the device name, name, scale) sectors are 512 bytes, times are in ms
+    let columns = [
+        (0, "node_disk_reads_completed_total", 1.0),
+        (2, "node_disk_read_bytes_total", 512.0),
+        (3, "node_disk_read_time_seconds_total", 0.001),
+        (4, "node_disk_writes_completed_total", 1.0),
+        (6, "node_disk_written_bytes_total", 512.0),
+        (7, "node_disk_write_time_seconds_total", 0.001),
+        (8, "node_disk_io_now", 1.0),
+        (9, "node_disk_io_time_seconds_total", 0.001),
+    ];
+    for line in data.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let Some(device) = fields.get(2) else {
+            continue;
+        };
+        if device.starts_with("loop") || device.starts_with("ram") {
+            continue;
+        }
+        let values: Vec<f64> = fields[3..]
+            .iter()
+            .filter_map(|x| x.parse::<f64>().ok())
+            .collect();
+        for (index, name, scale) in columns.iter() {
+            if let Some(value) = values.get(*index) {
+                let metric_type = match *index {
+                    8 => MetricType::Gauge,
+                    _ => MetricType::Counter,
+                };
+                families.push(name, metric_type, &[("device", device)], value * scale);
+            }
+        }
+    }
+}
+
+/// size and usage of every mount in `mounts` (the /proc/mounts text)
+fn read_filesystems(families: &mut Families, mounts: &str) {
+    for (device, mountpoint, fstype) in parse_mounts(mounts) {
+        // a mount that can't be read (permissions, gone since) is skipped
+        let Ok(path) = CString::new(mountpoint.as_str()) else {
+            continue;
+        };
+        let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut st) } != 0 || st.f_blocks == 0 {
+            continue;
+        }
+        let block = st.f_frsize as f64;
+        let labels = [
+            ("device", device),
+            ("mountpoint", mountpoint.as_str()),
+            ("fstype", fstype),
+        ];
+        for (name, value) in [
+            ("node_filesystem_size_bytes", st.f_blocks as f64 * block),
+            ("node_filesystem_free_bytes", st.f_bfree as f64 * block),
+            ("node_filesystem_avail_bytes", st.f_bavail as f64 * block),
+            ("node_filesystem_files", st.f_files as f64),
+            ("node_filesystem_files_free", st.f_ffree as f64),
+        ] {
+            families.push(name, MetricType::Gauge, &labels, value);
+        }
+    }
+}
+
+/// (device, mountpoint, fstype) of the mounts worth a statvfs, the first mount of a
+/// mountpoint wins
+fn parse_mounts(data: &str) -> Vec<(&str, String, &str)> {
+    let mut mounts: Vec<(&str, String, &str)> = vec![];
+    for line in data.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [device, mountpoint, fstype, ..] = fields[..] else {
+            continue;
+        };
+        let mountpoint = unescape_mount(mountpoint);
+        if IGNORED_FS.contains(&fstype)
+            || NETWORK_FS.contains(&fstype)
+            || mounts.iter().any(|x| x.1 == mountpoint)
+        {
+            continue;
+        }
+        mounts.push((device, mountpoint, fstype));
+    }
+    mounts
+}
+
+/// thermal zones (soc temperature on the pis), most vms have none
+fn read_thermal(families: &mut Families) {
+    let Ok(entries) = fs::read_dir("/sys/class/thermal") else {
+        return;
+    };
+    let mut zones: Vec<PathBuf> = entries
+        .filter_map(|x| x.ok())
+        .map(|x| x.path())
+        .filter(|x| {
+            x.file_name()
+                .map(|name| name.to_string_lossy().starts_with("thermal_zone"))
+                .unwrap_or(false)
+        })
+        .collect();
+    zones.sort();
+    for path in zones {
+        let Ok(Ok(temp)) = fs::read_to_string(path.join("temp")).map(|x| x.trim().parse::<f64>())
+        else {
+            continue;
+        };
+        let kind = fs::read_to_string(path.join("type")).unwrap_or_default();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let zone = name.trim_start_matches("thermal_zone");
+        // millidegrees
+        families.push(
+            "node_thermal_zone_temp",
+            MetricType::Gauge,
+            &[("type", kind.trim()), ("zone", zone)],
+            temp / 1000.0,
+        );
+    }
+}
+
+fn read_uname(families: &mut Families) {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return;
+    }
+    let field = |x: &[libc::c_char]| {
+        unsafe { CStr::from_ptr(x.as_ptr()) }
+            .to_string_lossy()
+            .to_string()
+    };
+    let (sysname, release, version, machine, nodename, domainname) = (
+        field(&uts.sysname),
+        field(&uts.release),
+        field(&uts.version),
+        field(&uts.machine),
+        field(&uts.nodename),
+        field(&uts.domainname),
+    );
+    families.push(
+        "node_uname_info",
+        MetricType::Gauge,
+        &[
+            ("domainname", &domainname),
+            ("machine", &machine),
+            ("nodename", &nodename),
+            ("release", &release),
+            ("sysname", &sysname),
+            ("version", &version),
+        ],
+        1.0,
+    );
+}
+
+// utility functions
+
+/// /proc/mounts escapes spaces, tabs and backslashes as octal (`\040`)
+fn unescape_mount(value: &str) -> String {
+    let mut result = String::new();
+    let mut rest = value;
+    while let Some(pos) = rest.find('\\') {
+        result.push_str(&rest[..pos]);
+        match rest
+            .get(pos + 1..pos + 4)
+            .and_then(|x| u8::from_str_radix(x, 8).ok())
+        {
+            Some(byte) => {
+                result.push(byte as char);
+                rest = &rest[pos + 4..];
+            }
+            None => {
+                result.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_escapes_are_decoded() {
+        assert_eq!(unescape_mount("/mnt/my\\040disk"), "/mnt/my disk");
+        assert_eq!(unescape_mount("/mnt/a\\011b"), "/mnt/a\tb");
+        assert_eq!(unescape_mount("/mnt/back\\134slash"), "/mnt/back\\slash");
+        assert_eq!(unescape_mount("/mnt/\\040\\040"), "/mnt/  ");
+        assert_eq!(unescape_mount("/"), "/");
+    }
+
+    #[test]
+    fn invalid_mount_escapes_keep_the_backslash() {
+        assert_eq!(unescape_mount("a\\x"), "a\\x");
+        assert_eq!(unescape_mount("a\\09"), "a\\09");
+        assert_eq!(unescape_mount("trailing\\"), "trailing\\");
+    }
+
+    #[test]
+    fn samples_are_grouped_by_family() {
+        let mut families = Families::default();
+        families.push("node_load1", MetricType::Gauge, &[], 0.5);
+        families.push(
+            "node_cpu_seconds_total",
+            MetricType::Counter,
+            &[("cpu", "0")],
+            1.0,
+        );
+        families.push(
+            "node_cpu_seconds_total",
+            MetricType::Counter,
+            &[("cpu", "1")],
+            2.0,
+        );
+        let names: Vec<&str> = families.0.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, ["node_load1", "node_cpu_seconds_total"]);
+        let cpu = &families.0[1];
+        assert_eq!(cpu.metric_type, MetricType::Counter);
+        assert_eq!(cpu.samples.len(), 2);
+        assert_eq!(
+            cpu.samples[1].labels.get("cpu").map(|x| x.as_str()),
+            Some("1")
+        );
+    }
+
+    /// value of the sample with exactly these labels
+    fn value(families: &Families, name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+        let family = families.0.iter().find(|x| x.name == name)?;
+        let sample = family.samples.iter().find(|x| {
+            x.labels.len() == labels.len()
+                && labels
+                    .iter()
+                    .all(|(k, v)| x.labels.get(*k).map(|l| l.as_str()) == Some(*v))
+        })?;
+        Some(sample.value)
+    }
+
+    #[test]
+    fn stat_cpu_times_and_counters() {
+        let data = "\
+cpu  300 0 200 1000 10 0 5 0 0 0
+cpu0 100 0 100 500 5 0 5 0 0 0
+cpu1 200 0 100 500 5 0 0 0 0 0
+intr 12345 1 2 3
+ctxt 9876
+btime 1700000000
+processes 4242
+procs_running 3
+procs_blocked 1
+";
+        let mut families = Families::default();
+        parse_stat(&mut families, data, 100.0);
+        let cpu = families
+            .0
+            .iter()
+            .find(|x| x.name == "node_cpu_seconds_total")
+            .unwrap();
+        // the aggregate line is left out, eight modes per cpu
+        assert_eq!(cpu.samples.len(), 16);
+        assert_eq!(
+            value(
+                &families,
+                "node_cpu_seconds_total",
+                &[("cpu", "1"), ("mode", "user")]
+            ),
+            Some(2.0)
+        );
+        assert_eq!(
+            value(
+                &families,
+                "node_cpu_seconds_total",
+                &[("cpu", "0"), ("mode", "idle")]
+            ),
+            Some(5.0)
+        );
+        assert_eq!(
+            value(
+                &families,
+                "node_cpu_seconds_total",
+                &[("cpu", "0"), ("mode", "softirq")]
+            ),
+            Some(0.05)
+        );
+        assert_eq!(
+            value(&families, "node_context_switches_total", &[]),
+            Some(9876.0)
+        );
+        assert_eq!(
+            value(&families, "node_boot_time_seconds", &[]),
+            Some(1700000000.0)
+        );
+        assert_eq!(value(&families, "node_forks_total", &[]), Some(4242.0));
+        assert_eq!(value(&families, "node_procs_running", &[]), Some(3.0));
+        assert_eq!(value(&families, "node_procs_blocked", &[]), Some(1.0));
+        assert!(value(&families, "node_intr_total", &[]).is_none());
+    }
+
+    #[test]
+    fn meminfo_in_bytes() {
+        let data = "\
+MemTotal:        8000000 kB
+MemAvailable:    6000000 kB
+Active(anon):     100000 kB
+HugePages_Total:       0
+broken line
+";
+        let mut families = Families::default();
+        parse_meminfo(&mut families, data);
+        assert_eq!(
+            value(&families, "node_memory_MemTotal_bytes", &[]),
+            Some(8_192_000_000.0)
+        );
+        assert_eq!(
+            value(&families, "node_memory_MemAvailable_bytes", &[]),
+            Some(6_144_000_000.0)
+        );
+        assert_eq!(
+            value(&families, "node_memory_Active_anon_bytes", &[]),
+            Some(102_400_000.0)
+        );
+        // counts have no unit
+        assert_eq!(
+            value(&families, "node_memory_HugePages_Total", &[]),
+            Some(0.0)
+        );
+        assert_eq!(families.0.len(), 4);
+    }
+
+    #[test]
+    fn loadavg() {
+        let mut families = Families::default();
+        parse_loadavg(&mut families, "0.52 0.58 0.59 2/1234 56789\n");
+        assert_eq!(value(&families, "node_load1", &[]), Some(0.52));
+        assert_eq!(value(&families, "node_load5", &[]), Some(0.58));
+        assert_eq!(value(&families, "node_load15", &[]), Some(0.59));
+        assert_eq!(families.0.len(), 3);
+    }
+
+    #[test]
+    fn net_dev_receive_and_transmit() {
+        let data = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:    1000      10    0    0    0     0          0         0     1000      10    0    0    0     0       0          0
+  eth0: 5000000    4000    1    2    0     0          0        30  2000000    3000    3    4    0     0       0          0
+";
+        let mut families = Families::default();
+        parse_net_dev(&mut families, data);
+        let eth0 = [("device", "eth0")];
+        assert_eq!(
+            value(&families, "node_network_receive_bytes_total", &eth0),
+            Some(5000000.0)
+        );
+        assert_eq!(
+            value(&families, "node_network_receive_packets_total", &eth0),
+            Some(4000.0)
+        );
+        assert_eq!(
+            value(&families, "node_network_receive_errs_total", &eth0),
+            Some(1.0)
+        );
+        assert_eq!(
+            value(&families, "node_network_receive_drop_total", &eth0),
+            Some(2.0)
+        );
+        assert_eq!(
+            value(&families, "node_network_transmit_bytes_total", &eth0),
+            Some(2000000.0)
+        );
+        assert_eq!(
+            value(&families, "node_network_transmit_drop_total", &eth0),
+            Some(4.0)
+        );
+        assert_eq!(
+            value(
+                &families,
+                "node_network_receive_bytes_total",
+                &[("device", "lo")]
+            ),
+            Some(1000.0)
+        );
+        assert_eq!(families.0.len(), 8);
+    }
+
+    #[test]
+    fn diskstats_in_bytes_and_seconds() {
+        let data = "\
+   7       0 loop0 100 0 200 10 0 0 0 0 0 10 10 0 0 0 0 0 0
+   8       0 sda 1000 50 20000 3000 500 20 8000 4000 2 5000 7000 0 0 0 0 0 0
+   8       1 sda1 900 50 18000 2900 480 20 7800 3900 0 4900 6800
+ 259       0 short
+";
+        let mut families = Families::default();
+        parse_diskstats(&mut families, data);
+        let sda = [("device", "sda")];
+        assert_eq!(
+            value(&families, "node_disk_reads_completed_total", &sda),
+            Some(1000.0)
+        );
+        assert_eq!(
+            value(&families, "node_disk_read_bytes_total", &sda),
+            Some(20000.0 * 512.0)
+        );
+        assert_eq!(
+            value(&families, "node_disk_read_time_seconds_total", &sda),
+            Some(3.0)
+        );
+        assert_eq!(
+            value(&families, "node_disk_written_bytes_total", &sda),
+            Some(8000.0 * 512.0)
+        );
+        assert_eq!(value(&families, "node_disk_io_now", &sda), Some(2.0));
+        assert_eq!(
+            value(&families, "node_disk_io_time_seconds_total", &sda),
+            Some(5.0)
+        );
+        let io_now = families
+            .0
+            .iter()
+            .find(|x| x.name == "node_disk_io_now")
+            .unwrap();
+        assert_eq!(io_now.metric_type, MetricType::Gauge);
+        // loop devices are left out, partitions are kept
+        let devices: Vec<&str> = io_now
+            .samples
+            .iter()
+            .map(|x| x.labels["device"].as_str())
+            .collect();
+        assert_eq!(devices, ["sda", "sda1"]);
+    }
+
+    #[test]
+    fn mounts_worth_a_statvfs() {
+        let data = "\
+sysfs /sys sysfs rw,nosuid 0 0
+/dev/sda1 / ext4 rw,relatime 0 0
+/dev/sdb1 /mnt/my\\040disk xfs rw 0 0
+nas:/export /mnt/nas nfs4 rw 0 0
+//nas/share /mnt/share cifs rw 0 0
+/dev/sda1 / ext4 rw,relatime 0 0
+tmpfs /run tmpfs rw 0 0
+";
+        let mounts = parse_mounts(data);
+        assert_eq!(
+            mounts,
+            [
+                ("/dev/sda1", "/".to_string(), "ext4"),
+                ("/dev/sdb1", "/mnt/my disk".to_string(), "xfs"),
+                ("tmpfs", "/run".to_string(), "tmpfs"),
+            ]
+        );
+    }
+}
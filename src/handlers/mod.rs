@@ -1 +1,26 @@
+pub mod agent;
+pub mod breaker;
+pub mod capture;
+pub mod discovery;
+pub mod dns;
+pub mod export;
+pub mod exposition;
+pub mod fleet;
+pub mod history;
+pub mod influx;
+pub mod local;
+pub mod offline;
+pub mod otlp;
 pub mod process;
+pub mod prometheus;
+pub mod protobuf;
+#[cfg(test)]
+pub mod receiver;
+pub mod remote_write;
+pub mod retry;
+pub mod scraper;
+pub mod sink;
+pub mod snapshot;
+pub mod storage;
+pub mod target;
+pub mod trends;
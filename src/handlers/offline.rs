@@ -0,0 +1,152 @@
+// module offline
+// file:// and stdin:// targets, saved scrapes are viewed like live ones
+use crate::config::load::Target;
+use crate::error::scrape::ScrapeError;
+use crate::handlers::process::{Body, TEXT_CONTENT_TYPE};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::OnceLock;
+
+pub const STDIN_URL: &str = "stdin://";
+
+/// content types of saved scrapes by file extension, other files and stdin are sniffed
+const EXTENSIONS: &[(&str, &str)] = &[
+    (
+        "pb",
+        "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited",
+    ),
+    ("om", "application/openmetrics-text; version=1.0.0"),
+    ("prom", TEXT_CONTENT_TYPE),
+    ("txt", TEXT_CONTENT_TYPE),
+];
+
+/// stdin is read once at startup, before the ui takes over the terminal
+static STDIN: OnceLock<Vec<u8>> = OnceLock::new();
+
+pub fn capture_stdin() -> Result<(), Box<dyn std::error::Error>> {
+    let mut buf = vec![];
+    io::stdin().read_to_end(&mut buf)?;
+    let _ = STDIN.set(buf);
+    Ok(())
+}
+
+/// target for a `--from-file` argument (`-` is stdin)
+pub fn target(path: &str) -> Target {
+    let (url, name) = match path {
+        "-" => (STDIN_URL.to_string(), "stdin".to_string()),
+        _ => (
+            format!("file://{}", path),
+            Path::new(path)
+                .file_name()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or(path.to_string()),
+        ),
+    };
+    let mut target = Target::from_url(&url);
+    target.name = Some(name);
+    target
+}
+
+/// read the body of an offline target (files are re-read on every scrape), none for network targets
+pub fn read(url: &str) -> Option<Result<Body, ScrapeError>> {
+    let body = match url.strip_prefix("file://") {
+        Some(path) => fs::read(path)
+            .map(|bytes| Body {
+                content_type: content_type(path).to_string(),
+                bytes,
+            })
+            .map_err(|e| ScrapeError::Down(format!("{} : {}", path, e))),
+        None if url == STDIN_URL => STDIN
+            .get()
+            .map(|bytes| Body {
+                content_type: String::new(),
+                bytes: bytes.clone(),
+            })
+            .ok_or(ScrapeError::Down("stdin was not read".to_string())),
+        None => return None,
+    };
+    Some(body)
+}
+
+// utility functions
+
+fn content_type(path: &str) -> &'static str {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|x| x.to_str())
+        .unwrap_or("");
+    EXTENSIONS
+        .iter()
+        .find(|(x, _)| *x == extension)
+        .map(|(_, content_type)| *content_type)
+        .unwrap_or("")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::exposition::Format;
+
+    #[test]
+    fn target_names() {
+        let stdin = target("-");
+        assert_eq!(stdin.url, STDIN_URL);
+        assert_eq!(stdin.name.as_deref(), Some("stdin"));
+        let file = target("/var/tmp/saved/node1.prom");
+        assert_eq!(file.url, "file:///var/tmp/saved/node1.prom");
+        assert_eq!(file.name.as_deref(), Some("node1.prom"));
+        assert_eq!(file.display_name(), "node1.prom");
+        assert_eq!(target("/").name.as_deref(), Some("/"));
+    }
+
+    #[test]
+    fn files_are_read_with_the_content_type_of_their_extension() {
+        let dir = std::env::temp_dir().join(format!("offline-read-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let text = "# TYPE up gauge\nup 1 1700000000\n# EOF\n";
+        let read_as = |name: &str| {
+            let path = dir.join(name);
+            fs::write(&path, text).unwrap();
+            read(&format!("file://{}", path.display()))
+                .unwrap()
+                .unwrap()
+        };
+        let prom = read_as("node.prom");
+        assert_eq!(prom.content_type, TEXT_CONTENT_TYPE);
+        assert_eq!(prom.bytes, text.as_bytes());
+        // prometheus text, so the timestamp is in milliseconds
+        assert_eq!(
+            prom.decode().unwrap()[0].samples[0].timestamp,
+            Some(1_700_000_000.0 / 1000.0)
+        );
+        assert_eq!(
+            Format::from_content_type(&read_as("node.om").content_type),
+            Format::OpenMetrics
+        );
+        assert_eq!(
+            Format::from_content_type(&read_as("node.pb").content_type),
+            Format::Protobuf
+        );
+        // anything else is sniffed, the `# EOF` makes it openmetrics
+        let saved = read_as("node.out");
+        assert_eq!(saved.content_type, "");
+        assert_eq!(
+            saved.decode().unwrap()[0].samples[0].timestamp,
+            Some(1_700_000_000.0)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+
+        let missing = read(&format!("file://{}", dir.join("node.prom").display())).unwrap();
+        assert!(matches!(missing, Err(ScrapeError::Down(_))));
+    }
+
+    #[test]
+    fn only_offline_urls_are_read() {
+        // nodes of a replayed capture and live targets are not files
+        assert!(read("http://10.0.0.1:9100").is_none());
+        assert!(read("local://").is_none());
+        // stdin is only there when it was captured at startup
+        assert!(matches!(read(STDIN_URL), Some(Err(ScrapeError::Down(_)))));
+    }
+}
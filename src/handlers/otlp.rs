@@ -0,0 +1,291 @@
+// module otlp
+// opentelemetry metrics output over otlp/http (protobuf), a resource per node with the job,
+// instance, target and external labels as attributes, counters become cumulative sums and
+// everything else gauges
+#[cfg(test)]
+use crate::error::generic::GenericError;
+use crate::handlers::sink::{self, Point, SendError};
+use crate::handlers::target::ScrapeTarget;
+use prost::Message;
+use std::collections::BTreeMap;
+
+const AGGREGATION_TEMPORALITY_CUMULATIVE: i32 = 2;
+
+// opentelemetry.proto.collector.metrics.v1 messages, only the fields we send (the oneofs are
+// optional fields, which encode the same)
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExportMetricsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ResourceMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    pub scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    pub attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ScopeMetrics {
+    #[prost(message, optional, tag = "1")]
+    pub scope: Option<InstrumentationScope>,
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct InstrumentationScope {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub version: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metric {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(message, optional, tag = "5")]
+    pub gauge: Option<Gauge>,
+    #[prost(message, optional, tag = "7")]
+    pub sum: Option<Sum>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Gauge {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<NumberDataPoint>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sum {
+    #[prost(message, repeated, tag = "1")]
+    pub data_points: Vec<NumberDataPoint>,
+    #[prost(int32, tag = "2")]
+    pub aggregation_temporality: i32,
+    #[prost(bool, tag = "3")]
+    pub is_monotonic: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct NumberDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    pub attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    pub time_unix_nano: u64,
+    #[prost(double, optional, tag = "4")]
+    pub as_double: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct KeyValue {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(message, optional, tag = "2")]
+    pub value: Option<AnyValue>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct AnyValue {
+    #[prost(string, optional, tag = "1")]
+    pub string_value: Option<String>,
+}
+
+/// post a batch, points are grouped by resource and metric name
+pub async fn send(server: &ScrapeTarget, points: &[Point]) -> Result<(), SendError> {
+    let body = encode(points).encode_to_vec();
+    let req = server
+        .post(server.url.clone())
+        .map_err(|e| SendError::Retry(e.to_string()))?
+        .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+        .header(
+            reqwest::header::USER_AGENT,
+            format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        )
+        .body(body);
+    sink::check(req).await
+}
+
+pub fn encode(points: &[Point]) -> ExportMetricsServiceRequest {
+    let mut resources: BTreeMap<&BTreeMap<String, String>, BTreeMap<&str, Metric>> =
+        BTreeMap::new();
+    for point in points.iter() {
+        let metric = resources
+            .entry(&point.resource)
+            .or_default()
+            .entry(&point.name)
+            .or_insert_with(|| match point.monotonic {
+                true => Metric {
+                    name: point.name.clone(),
+                    gauge: None,
+                    sum: Some(Sum {
+                        data_points: vec![],
+                        aggregation_temporality: AGGREGATION_TEMPORALITY_CUMULATIVE,
+                        is_monotonic: true,
+                    }),
+                },
+                false => Metric {
+                    name: point.name.clone(),
+                    gauge: Some(Gauge {
+                        data_points: vec![],
+                    }),
+                    sum: None,
+                },
+            });
+        let data_point = NumberDataPoint {
+            attributes: attributes(&point.labels),
+            time_unix_nano: point.unix_nanos() as u64,
+            as_double: Some(point.value),
+        };
+        match (&mut metric.sum, &mut metric.gauge) {
+            (Some(sum), _) => sum.data_points.push(data_point),
+            (_, Some(gauge)) => gauge.data_points.push(data_point),
+            _ => {}
+        }
+    }
+    ExportMetricsServiceRequest {
+        resource_metrics: resources
+            .into_iter()
+            .map(|(resource, metrics)| ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: attributes(resource),
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(InstrumentationScope {
+                        name: env!("CARGO_PKG_NAME").to_string(),
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                    }),
+                    metrics: metrics.into_values().collect(),
+                }],
+            })
+            .collect(),
+    }
+}
+
+/// decode a request body
+#[cfg(test)]
+pub fn decode(body: &[u8]) -> Result<ExportMetricsServiceRequest, Box<dyn std::error::Error>> {
+    let request = ExportMetricsServiceRequest::decode(body)
+        .map_err(|e| GenericError::new(format!("[decode] protobuf : {}", e)))?;
+    Ok(request)
+}
+
+// utility functions
+
+fn attributes(labels: &BTreeMap<String, String>) -> Vec<KeyValue> {
+    labels
+        .iter()
+        .map(|(k, v)| KeyValue {
+            key: k.clone(),
+            value: Some(AnyValue {
+                string_value: Some(v.clone()),
+            }),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::load::{Parameters, Target};
+    use crate::handlers::receiver;
+
+    fn point(instance: &str, name: &str, cpu: &str, value: f64, monotonic: bool) -> Point {
+        Point {
+            name: name.to_string(),
+            resource: BTreeMap::from([
+                ("instance".to_string(), instance.to_string()),
+                ("job".to_string(), "node".to_string()),
+            ]),
+            labels: BTreeMap::from([("cpu".to_string(), cpu.to_string())]),
+            value,
+            ts: 1700000000.123,
+            monotonic,
+        }
+    }
+
+    fn attribute(attributes: &[KeyValue], key: &str) -> Option<String> {
+        attributes
+            .iter()
+            .find(|x| x.key == key)
+            .and_then(|x| x.value.as_ref())
+            .and_then(|x| x.string_value.clone())
+    }
+
+    #[test]
+    fn points_are_grouped_by_resource_and_metric() {
+        let points = [
+            point("b:9100", "node_load1", "", 0.5, false),
+            point("a:9100", "node_cpu_seconds_total", "0", 10.0, true),
+            point("a:9100", "node_load1", "", 0.25, false),
+            point("a:9100", "node_cpu_seconds_total", "1", 20.0, true),
+        ];
+        let request = encode(&points);
+        assert_eq!(request.resource_metrics.len(), 2);
+        let a = &request.resource_metrics[0];
+        let resource = &a.resource.as_ref().unwrap().attributes;
+        assert_eq!(attribute(resource, "instance").as_deref(), Some("a:9100"));
+        assert_eq!(attribute(resource, "job").as_deref(), Some("node"));
+        let scope = &a.scope_metrics[0];
+        assert_eq!(scope.scope.as_ref().unwrap().name, env!("CARGO_PKG_NAME"));
+        let names: Vec<&str> = scope.metrics.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(names, ["node_cpu_seconds_total", "node_load1"]);
+        // counters are cumulative monotonic sums, one data point per series
+        let cpu = &scope.metrics[0];
+        assert!(cpu.gauge.is_none());
+        let sum = cpu.sum.as_ref().unwrap();
+        assert!(sum.is_monotonic);
+        assert_eq!(
+            sum.aggregation_temporality,
+            AGGREGATION_TEMPORALITY_CUMULATIVE
+        );
+        let cpus: Vec<(Option<String>, Option<f64>)> = sum
+            .data_points
+            .iter()
+            .map(|x| (attribute(&x.attributes, "cpu"), x.as_double))
+            .collect();
+        assert_eq!(
+            cpus,
+            [
+                (Some("0".to_string()), Some(10.0)),
+                (Some("1".to_string()), Some(20.0))
+            ]
+        );
+        assert_eq!(sum.data_points[0].time_unix_nano, 1700000000123000000);
+        let load = &scope.metrics[1];
+        assert!(load.sum.is_none());
+        assert_eq!(
+            load.gauge.as_ref().unwrap().data_points[0].as_double,
+            Some(0.25)
+        );
+        let b = &request.resource_metrics[1].scope_metrics[0].metrics;
+        assert_eq!(b.len(), 1);
+        assert!(b[0].gauge.is_some());
+    }
+
+    #[tokio::test]
+    async fn http_posts_protobuf() {
+        let (address, mut requests) = receiver::serve(vec![]).await;
+        let url = format!("http://{}/v1/metrics", address);
+        let server =
+            ScrapeTarget::new(&Target::from_url(&url), &Parameters::offline(vec![])).unwrap();
+        let points = [point("a:9100", "node_load1", "", 0.5, false)];
+        assert!(send(&server, &points).await.is_ok());
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.line(), "POST /v1/metrics HTTP/1.1");
+        assert_eq!(
+            request.header("content-type"),
+            Some("application/x-protobuf")
+        );
+        assert_eq!(decode(&request.body).unwrap(), encode(&points));
+    }
+}
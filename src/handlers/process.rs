@@ -1,13 +1,17 @@
+use crate::error::scrape::ScrapeError;
+use crate::handlers::exposition::{self, ACCEPT_HEADER, Format, MetricFamily};
+use crate::handlers::target::ScrapeTarget;
+use crate::handlers::{local, offline};
 use custom_logger as log;
-use regex::bytes::Regex;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
 use std::collections::BTreeMap;
 
 pub trait MetricsInterface {
     fn new() -> Self;
-    async fn scrape(&self, node: String) -> Result<Vec<String>, Box<dyn std::error::Error>>;
+    async fn fetch(&self, target: &ScrapeTarget) -> Result<Body, ScrapeError>;
     fn get_all_metrics(
         &mut self,
-        vec_metrics: Vec<String>,
+        families: &[MetricFamily],
     ) -> Result<MetricsData, Box<dyn std::error::Error>>;
 }
 
@@ -15,6 +19,27 @@ pub struct Service {
     pub cpu_state: BTreeMap<isize, f64>,
 }
 
+/// content type of the text format (0.0.4)
+pub const TEXT_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// a raw scrape response, decoded separately so it can be recorded as received
+#[derive(Debug, Clone)]
+pub struct Body {
+    /// empty for stdin and files without a known extension, the format is then sniffed
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+}
+
+impl Body {
+    pub fn decode(&self) -> Result<Vec<MetricFamily>, ScrapeError> {
+        let format = match self.content_type.as_str() {
+            "" => Format::sniff(&self.bytes),
+            x => Format::from_content_type(x),
+        };
+        exposition::decode(&self.bytes, format).map_err(|e| ScrapeError::Parse(e.to_string()))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MetricsData {
     pub cpu: Vec<String>,
@@ -31,35 +56,56 @@ impl MetricsInterface for Service {
         }
     }
 
-    async fn scrape(&self, node: String) -> Result<Vec<String>, Box<dyn std::error::Error>> {
-        let mut all_metrics: Vec<String> = Vec::new();
-        log::trace!("[scrape] server {}", node);
-        let server_endpoint = format!("{}/metrics", node);
-        let client = reqwest::Client::new();
-        let res = client.get(server_endpoint).send().await;
-        match res {
-            Ok(data) => {
-                let data_result = data.bytes().await?;
-                let contents = String::from_utf8(data_result.to_vec())?;
-                all_metrics.push(contents);
-            }
-            Err(e) => {
-                println!("{}", e.to_string())
-            }
-        };
-        Ok(all_metrics.clone())
+    async fn fetch(&self, target: &ScrapeTarget) -> Result<Body, ScrapeError> {
+        log::trace!("[fetch] server {}", target.url);
+        if let Some(result) = offline::read(&target.url) {
+            return result;
+        }
+        if target.url.starts_with(local::LOCAL_URL) {
+            let families = local::collect().await?;
+            return Ok(Body {
+                content_type: TEXT_CONTENT_TYPE.to_string(),
+                bytes: exposition::encode(&families).into_bytes(),
+            });
+        }
+        let server_endpoint = format!("{}{}", target.url, target.metrics_path);
+        let params: Vec<(&String, &String)> = target
+            .spec
+            .params
+            .iter()
+            .flat_map(|(k, values)| values.iter().map(move |v| (k, v)))
+            .collect();
+        let data = target
+            .get(server_endpoint)?
+            .query(&params)
+            .header(ACCEPT, ACCEPT_HEADER)
+            .send()
+            .await
+            .and_then(|x| x.error_for_status())
+            .map_err(ScrapeError::from_reqwest)?;
+        let content_type = data
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        let data_result = data.bytes().await.map_err(ScrapeError::from_reqwest)?;
+        Ok(Body {
+            content_type,
+            bytes: data_result.to_vec(),
+        })
     }
 
     fn get_all_metrics(
         &mut self,
-        vec_metrics: Vec<String>,
+        families: &[MetricFamily],
     ) -> Result<MetricsData, Box<dyn std::error::Error>> {
         let md = MetricsData {
-            cpu: get_cpu_metrics(&mut self.cpu_state, vec_metrics.clone())?,
-            memory: get_memory_metrics(vec_metrics.clone())?,
-            network: get_network_metrics(vec_metrics.clone())?,
-            disk: get_disk_metrics(vec_metrics.clone())?,
-            info: get_info_metrics(vec_metrics)?,
+            cpu: get_cpu_metrics(&mut self.cpu_state, families)?,
+            memory: get_memory_metrics(families)?,
+            network: get_network_metrics(families)?,
+            disk: get_disk_metrics(families)?,
+            info: get_info_metrics(families)?,
         };
         Ok(md)
     }
@@ -69,36 +115,24 @@ impl MetricsInterface for Service {
 
 fn get_cpu_metrics(
     cpu_state: &mut BTreeMap<isize, f64>,
-    vec_metrics: Vec<String>,
+    families: &[MetricFamily],
 ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     let mut hm_filtered_metrics: BTreeMap<isize, f64> = Default::default();
-    for v in vec_metrics.iter() {
-        let lines: Vec<&str> = v.split("\n").collect();
-        for line in lines.iter() {
-            match line {
-                x if x.contains("node_cpu_seconds_total")
-                    && !x.contains("idle")
-                    && !x.contains("iowait")
-                    && !x.contains("steal") =>
-                {
-                    let re = Regex::new("cpu=\"([0-9]+)\",mode=\"[a-z]+\"}\\W([0-9e\\+\\.]+)")?;
-                    for cap in re.captures_iter(line.as_bytes()) {
-                        let s_cpu = String::from_utf8(cap[1].to_vec())?;
-                        let cpu = s_cpu.parse::<isize>()?;
-                        let s_value = String::from_utf8(cap[2].to_vec())?;
-                        let value = s_value.parse::<f64>()?;
-                        let mut lu_value = hm_filtered_metrics.get(&cpu).unwrap_or(&0.0).to_owned();
-                        lu_value = lu_value + value;
-                        hm_filtered_metrics.insert(cpu, lu_value);
-                    }
-                }
-                &_ => {}
-            };
-        }
+    for sample in exposition::samples(families, "node_cpu_seconds_total") {
+        match sample.label("mode") {
+            Some("idle") | Some("iowait") | Some("steal") | None => {}
+            Some(_) => {
+                // aggregated or relabelled series have no usable cpu label
+                let Ok(cpu) = sample.label("cpu").unwrap_or("").parse::<isize>() else {
+                    continue;
+                };
+                *hm_filtered_metrics.entry(cpu).or_insert(0.0) += sample.value;
+            }
+        };
     }
     let mut vec_filtered_metrics = vec![];
     for (k, v) in hm_filtered_metrics.iter() {
-        let current = cpu_state.get(&k).unwrap_or(&0.0);
+        let current = cpu_state.get(k).unwrap_or(&0.0);
         match current {
             x if x == &0.0 => vec_filtered_metrics.push(format!("{} 0.000%", k)),
             &_ => {
@@ -111,34 +145,22 @@ fn get_cpu_metrics(
     Ok(vec_filtered_metrics)
 }
 
-fn get_memory_metrics(vec_metrics: Vec<String>) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+fn get_memory_metrics(
+    families: &[MetricFamily],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     let mut vec_filtered_metrics: Vec<String> = Vec::new();
     let mut total = 0.0;
     let mut avail = 0.0;
-    for v in vec_metrics.iter() {
-        let lines: Vec<&str> = v.split("\n").collect();
-        for line in lines.iter() {
-            match line {
-                x if x.contains("node_memory_MemTotal_bytes") => {
-                    let re = Regex::new("node_memory_MemTotal_bytes\\W([0-9e\\+\\.]+)")?;
-                    for cap in re.captures_iter(line.as_bytes()) {
-                        let s_total = String::from_utf8(cap[1].to_vec())?;
-                        total = s_total.parse::<f64>()?;
-                        vec_filtered_metrics.push(format!("total     {:.2}", total));
-                    }
-                }
-                x if x.contains("node_memory_MemAvailable_bytes") => {
-                    let re = Regex::new("node_memory_MemAvailable_bytes\\W([0-9e\\+\\.]+)")?;
-                    for cap in re.captures_iter(line.as_bytes()) {
-                        let s_avail = String::from_utf8(cap[1].to_vec())?;
-                        avail = s_avail.parse::<f64>()?;
-                        vec_filtered_metrics.push(format!("available {:.2}", avail));
-                    }
-                }
-                &_ => {}
-            };
-        }
-        // final calculations
+    for sample in exposition::samples(families, "node_memory_MemTotal_bytes") {
+        total = sample.value;
+        vec_filtered_metrics.push(format!("total     {:.2}", total));
+    }
+    for sample in exposition::samples(families, "node_memory_MemAvailable_bytes") {
+        avail = sample.value;
+        vec_filtered_metrics.push(format!("available {:.2}", avail));
+    }
+    // final calculations
+    if total > 0.0 {
         vec_filtered_metrics.push(format!(
             "% used    {:.2}",
             ((total - avail) / total) * 100.0
@@ -148,38 +170,16 @@ fn get_memory_metrics(vec_metrics: Vec<String>) -> Result<Vec<String>, Box<dyn s
 }
 
 fn get_network_metrics(
-    vec_metrics: Vec<String>,
+    families: &[MetricFamily],
 ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     let mut hm_filtered_metrics: BTreeMap<String, f64> = BTreeMap::new();
-    for v in vec_metrics.iter() {
-        let lines: Vec<&str> = v.split("\n").collect();
-        for line in lines.iter() {
-            match line {
-                x if x.contains("node_network_receive_bytes_total") => {
-                    let re = Regex::new(
-                        "node_network_receive_bytes_total\\{device=\"([a-zA-Z0-9]+)\"\\}\\W([0-9e\\+\\.]+)",
-                    )?;
-                    for cap in re.captures_iter(line.as_bytes()) {
-                        let s_device = String::from_utf8(cap[1].to_vec())?;
-                        let s_value = String::from_utf8(cap[2].to_vec())?;
-                        let value = s_value.parse::<f64>()?;
-                        hm_filtered_metrics.insert(format!("rx [{:10}]", s_device), value);
-                    }
-                }
-                x if x.contains("node_network_transmit_bytes_total") => {
-                    let re = Regex::new(
-                        "node_network_transmit_bytes_total\\{device=\"([a-zA-Z0-9]+)\"\\}\\W([0-9e\\+\\.]+)",
-                    )?;
-                    for cap in re.captures_iter(line.as_bytes()) {
-                        let s_device = String::from_utf8(cap[1].to_vec())?;
-                        let s_value = String::from_utf8(cap[2].to_vec())?;
-                        let value = s_value.parse::<f64>()?;
-                        hm_filtered_metrics.insert(format!("tx [{:10}]", s_device), value);
-                    }
-                }
-
-                &_ => {}
-            };
+    for (name, prefix) in [
+        ("node_network_receive_bytes_total", "rx"),
+        ("node_network_transmit_bytes_total", "tx"),
+    ] {
+        for sample in exposition::samples(families, name) {
+            let device = sample.label("device").unwrap_or("");
+            hm_filtered_metrics.insert(format!("{} [{:10}]", prefix, device), sample.value);
         }
     }
     let vec_filtered_metrics = hm_filtered_metrics
@@ -189,36 +189,15 @@ fn get_network_metrics(
     Ok(vec_filtered_metrics)
 }
 
-fn get_disk_metrics(vec_metrics: Vec<String>) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+fn get_disk_metrics(families: &[MetricFamily]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     let mut hm_filtered_metrics: BTreeMap<String, f64> = BTreeMap::new();
-    for v in vec_metrics.iter() {
-        let lines: Vec<&str> = v.split("\n").collect();
-        for line in lines.iter() {
-            match line {
-                x if x.contains("node_filesystem_size_bytes") => {
-                    let re = Regex::new(
-                        "node_filesystem_size_bytes\\{device=\"([0-9a-zA-Z/]*)\",device_error=\"[0-9a-zA-Z/]*\",fstype=\"[0-9a-zA-Z/]*\",mountpoint=\"[0-9a-zA-Z/]*\"\\}\\W([0-9e\\+\\.]*)",
-                    )?;
-                    for cap in re.captures_iter(line.as_bytes()) {
-                        let s_device = String::from_utf8(cap[1].to_vec())?;
-                        let s_value = String::from_utf8(cap[2].to_vec())?;
-                        let value = s_value.parse::<f64>()?;
-                        hm_filtered_metrics.insert(format!("total [{:15}]", s_device), value);
-                    }
-                }
-                x if x.contains("node_filesystem_free_bytes") => {
-                    let re = Regex::new(
-                        "node_filesystem_free_bytes\\{device=\"([0-9a-zA-Z/]*)\",device_error=\"[0-9a-zA-Z/]*\",fstype=\"[0-9a-zA-Z/]*\",mountpoint=\"[0-9a-zA-Z/]*\"\\}\\W([0-9e\\+\\.]*)",
-                    )?;
-                    for cap in re.captures_iter(line.as_bytes()) {
-                        let s_device = String::from_utf8(cap[1].to_vec())?;
-                        let s_value = String::from_utf8(cap[2].to_vec())?;
-                        let value = s_value.parse::<f64>()?;
-                        hm_filtered_metrics.insert(format!("free  [{:15}]", s_device), value);
-                    }
-                }
-                &_ => {}
-            };
+    for (name, prefix) in [
+        ("node_filesystem_size_bytes", "total"),
+        ("node_filesystem_free_bytes", "free "),
+    ] {
+        for sample in exposition::samples(families, name) {
+            let device = sample.label("device").unwrap_or("");
+            hm_filtered_metrics.insert(format!("{} [{:15}]", prefix, device), sample.value);
         }
     }
     let vec_filtered_metrics = hm_filtered_metrics
@@ -228,34 +207,59 @@ fn get_disk_metrics(vec_metrics: Vec<String>) -> Result<Vec<String>, Box<dyn std
     Ok(vec_filtered_metrics)
 }
 
-fn get_info_metrics(vec_metrics: Vec<String>) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+fn get_info_metrics(families: &[MetricFamily]) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     let mut result: Vec<String> = vec![];
-    for v in vec_metrics.iter() {
-        let lines: Vec<&str> = v.split("\n").collect();
-        for line in lines.iter() {
-            match line {
-                x if x.starts_with("node_uname_info") => {
-                    let mut res: Vec<String> = line
-                        .replace(",2", "-2")
-                        .replace(",1", "-1")
-                        .split(",")
-                        .map(|x| format!("{}", x))
-                        .collect();
-                    result.append(&mut res);
-                }
-                x if x.starts_with("node_dmi_info") => {
-                    let mut res: Vec<String> = line
-                        .replace(",2", "-2")
-                        .replace(",1", "-2")
-                        .split(",")
-                        .map(|x| format!("{}", x))
-                        .collect();
-                    result.append(&mut res);
-                }
-
-                &_ => {}
-            };
+    for name in ["node_uname_info", "node_dmi_info"] {
+        for sample in exposition::samples(families, name) {
+            result.push(sample.name.clone());
+            let mut res: Vec<String> = sample
+                .labels
+                .iter()
+                .map(|(k, v)| format!("  {}=\"{}\"", k, v))
+                .collect();
+            result.append(&mut res);
         }
     }
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn families(body: &str) -> Vec<MetricFamily> {
+        exposition::parse(body, Format::Prometheus).unwrap()
+    }
+
+    #[test]
+    fn cpu_without_a_numeric_label_is_skipped() {
+        let body = concat!(
+            "node_cpu_seconds_total{cpu=\"0\",mode=\"user\"} 10\n",
+            "node_cpu_seconds_total{cpu=\"0\",mode=\"idle\"} 100\n",
+            "node_cpu_seconds_total{mode=\"user\"} 40\n",
+            "node_cpu_seconds_total{cpu=\"total\",mode=\"system\"} 5\n",
+        );
+        let mut state = BTreeMap::new();
+        let first = get_cpu_metrics(&mut state, &families(body)).unwrap();
+        assert_eq!(first, ["0 0.000%"]);
+        assert_eq!(state.get(&0), Some(&10.0));
+    }
+
+    #[test]
+    fn memory_percentage() {
+        let body = "node_memory_MemTotal_bytes 1000\nnode_memory_MemAvailable_bytes 250\n";
+        let memory = get_memory_metrics(&families(body)).unwrap();
+        assert_eq!(memory.last().map(|x| x.as_str()), Some("% used    75.00"));
+    }
+
+    #[test]
+    fn body_format_is_sniffed_without_a_content_type() {
+        let body = Body {
+            content_type: String::new(),
+            bytes: b"# TYPE up gauge\nup 1 1700000000\n# EOF\n".to_vec(),
+        };
+        let families = body.decode().unwrap();
+        // read as openmetrics, so the timestamp is in seconds
+        assert_eq!(families[0].samples[0].timestamp, Some(1_700_000_000.0));
+    }
+}
@@ -0,0 +1,462 @@
+// module prometheus
+// reads node data from a prometheus server's http api instead of the exporters
+use crate::config::load::{Parameters, PrometheusConfig, Target};
+use crate::error::scrape::ScrapeError;
+use crate::handlers::capture;
+use crate::handlers::discovery::DiscoveryUpdate;
+use crate::handlers::exposition::{MetricFamily, MetricType, Sample};
+use crate::handlers::scraper::ScrapeUpdate;
+use crate::handlers::target::ScrapeTarget;
+use custom_logger as log;
+use serde_derive::Deserialize;
+use std::collections::BTreeMap;
+use tokio::sync::mpsc;
+
+/// series read by the panels, charts and snapshots, `up` gives the node status
+const SERIES: &[&str] = &[
+    "up",
+    "node_cpu_seconds_total",
+    "node_memory_MemTotal_bytes",
+    "node_memory_MemAvailable_bytes",
+    "node_network_receive_bytes_total",
+    "node_network_transmit_bytes_total",
+    "node_disk_read_bytes_total",
+    "node_disk_written_bytes_total",
+    "node_filesystem_size_bytes",
+    "node_filesystem_free_bytes",
+    "node_filesystem_avail_bytes",
+    "node_load1",
+    "node_load5",
+    "node_load15",
+    "node_thermal_zone_temp",
+    "node_hwmon_temp_celsius",
+    "node_uname_info",
+    "node_dmi_info",
+];
+
+#[derive(Deserialize)]
+struct ApiResponse {
+    status: String,
+    #[serde(default)]
+    error: String,
+    data: Option<ApiData>,
+}
+
+#[derive(Deserialize)]
+struct ApiData {
+    result: Vec<ApiSeries>,
+}
+
+/// a vector (`value`) or matrix (`values`) result, points are `[unix seconds, "value"]`
+#[derive(Deserialize)]
+struct ApiSeries {
+    metric: BTreeMap<String, String>,
+    value: Option<(f64, String)>,
+    #[serde(default)]
+    values: Vec<(f64, String)>,
+}
+
+/// families per instance
+type Instances = BTreeMap<String, Vec<MetricFamily>>;
+
+/// a single api point, labels (with `__name__`), timestamp and raw value
+type Point = (BTreeMap<String, String>, f64, String);
+
+/// poll the prometheus api every `scrape_duration`, nodes are sent as discovered targets
+/// and their data as scrape updates (history is backfilled once with `query_range`)
+pub async fn run(
+    config: PrometheusConfig,
+    params: Parameters,
+    tx: mpsc::Sender<ScrapeUpdate>,
+    discovery_tx: mpsc::Sender<DiscoveryUpdate>,
+) {
+    let source = format!("prometheus:{}", config.url);
+    // reuse the scrape target for the http client, timeouts, tls and auth
+    let server = ScrapeTarget::new(&Target::from_url(&config.url), &params)
+        .map_err(|e| format!("{} : {}", config.url, e));
+    let server = match server {
+        Ok(server) => server,
+        Err(err) => {
+            let result = Err(err);
+            let _ = discovery_tx.send(DiscoveryUpdate { source, result }).await;
+            return;
+        }
+    };
+    let names = SERIES.join("|");
+    let query = match config.matchers.trim() {
+        "" => format!("{{__name__=~\"{}\"}}", names),
+        matchers => format!("{{__name__=~\"{}\",{}}}", names, matchers),
+    };
+    let mut instances: Vec<String> = vec![];
+
+    if config.backfill_secs > 0 {
+        let step = config.step_secs.unwrap_or(params.scrape_duration).max(1);
+        match query_range(&server, &query, config.backfill_secs, step).await {
+            Ok(points) => {
+                instances = node_names(points.iter().flat_map(|x| x.keys()));
+                let result = Ok(targets(&instances, &config.url));
+                let update = DiscoveryUpdate {
+                    source: source.clone(),
+                    result,
+                };
+                if discovery_tx.send(update).await.is_err() {
+                    return;
+                }
+                for point in points {
+                    for (node, families) in point {
+                        let result = node_result(families);
+                        capture::record_families(&node, &result);
+                        let update = ScrapeUpdate { node, result };
+                        if tx.send(update).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            // the instant queries below report the error
+            Err(e) => log::debug!("[run] backfill {}", e),
+        }
+    }
+
+    let mut ticker = tokio::time::interval(server.interval);
+    loop {
+        ticker.tick().await;
+        match query_instant(&server, &query).await {
+            Ok(current) => {
+                // sent on every refresh, a successful update also clears a previous error
+                instances = node_names(current.keys());
+                let update = DiscoveryUpdate {
+                    source: source.clone(),
+                    result: Ok(targets(&instances, &config.url)),
+                };
+                if discovery_tx.send(update).await.is_err() {
+                    return;
+                }
+                for (node, families) in current {
+                    let result = node_result(families);
+                    capture::record_families(&node, &result);
+                    let update = ScrapeUpdate { node, result };
+                    if tx.send(update).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(err) => {
+                // keep the node list, every node shows the server error
+                let result = Err(format!("{} : {}", config.url, err));
+                let update = DiscoveryUpdate {
+                    source: source.clone(),
+                    result,
+                };
+                if discovery_tx.send(update).await.is_err() {
+                    return;
+                }
+                for node in instances.iter() {
+                    let result = Err(err.clone());
+                    capture::record_families(node, &result);
+                    let update = ScrapeUpdate {
+                        node: node.clone(),
+                        result,
+                    };
+                    if tx.send(update).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn query_instant(server: &ScrapeTarget, query: &str) -> Result<Instances, ScrapeError> {
+    let data = fetch(server, "/api/v1/query", &[("query", query.to_string())]).await?;
+    let points = data.result.into_iter().filter_map(|series| {
+        let (ts, value) = series.value?;
+        Some((series.metric, ts, value))
+    });
+    Ok(group(points))
+}
+
+/// instances per evaluation step, oldest first
+async fn query_range(
+    server: &ScrapeTarget,
+    query: &str,
+    range: u64,
+    step: u64,
+) -> Result<Vec<Instances>, ScrapeError> {
+    let end = chrono::Utc::now().timestamp();
+    let params = [
+        ("query", query.to_string()),
+        ("start", (end - range as i64).to_string()),
+        ("end", end.to_string()),
+        ("step", step.to_string()),
+    ];
+    let data = fetch(server, "/api/v1/query_range", &params).await?;
+    // every series is evaluated at the same steps, regroup them by timestamp
+    let mut steps: BTreeMap<i64, Vec<Point>> = BTreeMap::new();
+    for series in data.result {
+        for (ts, value) in series.values {
+            steps
+                .entry((ts * 1000.0) as i64)
+                .or_default()
+                .push((series.metric.clone(), ts, value));
+        }
+    }
+    Ok(steps.into_values().map(|x| group(x.into_iter())).collect())
+}
+
+async fn fetch(
+    server: &ScrapeTarget,
+    endpoint: &str,
+    params: &[(&str, String)],
+) -> Result<ApiData, ScrapeError> {
+    let url = format!("{}{}", server.url.trim_end_matches('/'), endpoint);
+    log::trace!("[fetch] {} {:?}", url, params);
+    let response = server
+        .get(url)?
+        .query(params)
+        .send()
+        .await
+        .map_err(ScrapeError::from_reqwest)?;
+    let status = response.status();
+    let body = response.bytes().await.map_err(ScrapeError::from_reqwest)?;
+    // the api returns a json error body for bad queries, prefer it over the bare status
+    let parsed: ApiResponse = match serde_json::from_slice(&body) {
+        Ok(parsed) => parsed,
+        Err(_) if !status.is_success() => {
+            return Err(ScrapeError::from_status(
+                status.as_u16(),
+                status.to_string(),
+            ));
+        }
+        Err(e) => return Err(ScrapeError::Parse(format!("{} : {}", endpoint, e))),
+    };
+    match (parsed.status.as_str(), parsed.data) {
+        ("success", Some(data)) => Ok(data),
+        _ if !status.is_success() => Err(ScrapeError::from_status(status.as_u16(), parsed.error)),
+        _ => Err(ScrapeError::Parse(format!(
+            "{} : {}",
+            endpoint, parsed.error
+        ))),
+    }
+}
+
+// utility functions
+
+/// group api points into metric families per `instance` label
+fn group(points: impl Iterator<Item = Point>) -> Instances {
+    let mut instances: BTreeMap<String, BTreeMap<String, MetricFamily>> = BTreeMap::new();
+    for (mut labels, ts, value) in points {
+        let (Some(name), Some(instance)) = (labels.remove("__name__"), labels.get("instance"))
+        else {
+            continue;
+        };
+        let Ok(value) = value.parse::<f64>() else {
+            continue;
+        };
+        let family = instances
+            .entry(instance.clone())
+            .or_default()
+            .entry(name.clone())
+            .or_insert_with(|| MetricFamily {
+                name: name.clone(),
+                metric_type: metric_type(&name),
+                ..Default::default()
+            });
+        family.samples.push(Sample {
+            name,
+            labels,
+            value,
+            timestamp: Some(ts),
+            exemplar: None,
+        });
+    }
+    // keep instances that export node metrics (or whose exporter is down), other jobs only have `up`
+    instances
+        .into_iter()
+        .filter(|(_, families)| {
+            families.keys().any(|x| x.starts_with("node_"))
+                || families
+                    .get("up")
+                    .map(|x| x.samples.iter().any(|s| s.value == 0.0))
+                    .unwrap_or(false)
+        })
+        .map(|(k, v)| (k, v.into_values().collect()))
+        .collect()
+}
+
+/// sorted unique instance names
+fn node_names<'a>(names: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let mut names: Vec<String> = names.cloned().collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn targets(instances: &[String], via: &str) -> Vec<Target> {
+    instances
+        .iter()
+        .map(|x| {
+            let mut target = Target::from_url(x);
+            target.via = Some(via.to_string());
+            target
+        })
+        .collect()
+}
+
+fn node_result(families: Vec<MetricFamily>) -> Result<Vec<MetricFamily>, ScrapeError> {
+    let up = families
+        .iter()
+        .filter(|x| x.name == "up")
+        .flat_map(|x| x.samples.iter())
+        .all(|x| x.value > 0.0);
+    match up {
+        true => Ok(families),
+        false => Err(ScrapeError::Down(
+            "prometheus reports the exporter as down".to_string(),
+        )),
+    }
+}
+
+/// the api drops the type, counters are told apart by the usual suffixes
+fn metric_type(name: &str) -> MetricType {
+    match ["_total", "_bucket", "_count", "_sum"]
+        .iter()
+        .any(|x| name.ends_with(x))
+    {
+        true => MetricType::Counter,
+        false => MetricType::Gauge,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(labels: &[(&str, &str)], ts: f64, value: &str) -> Point {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        (labels, ts, value.to_string())
+    }
+
+    #[test]
+    fn points_are_grouped_per_instance_with_inferred_types() {
+        let points = vec![
+            point(&[("__name__", "up"), ("instance", "a:9100")], 10.0, "1"),
+            point(
+                &[
+                    ("__name__", "node_cpu_seconds_total"),
+                    ("instance", "a:9100"),
+                    ("cpu", "0"),
+                ],
+                10.0,
+                "12.5",
+            ),
+            point(
+                &[("__name__", "node_load1"), ("instance", "a:9100")],
+                10.0,
+                "0.5",
+            ),
+            point(
+                &[("__name__", "node_load1"), ("instance", "b:9100")],
+                10.0,
+                "NaN",
+            ),
+            point(
+                &[("__name__", "node_load1"), ("instance", "b:9100")],
+                10.0,
+                "x",
+            ),
+            point(&[("__name__", "node_load1")], 10.0, "1"),
+        ];
+        let instances = group(points.into_iter());
+        assert_eq!(instances.keys().collect::<Vec<_>>(), ["a:9100", "b:9100"]);
+        let a = &instances["a:9100"];
+        let cpu = a
+            .iter()
+            .find(|x| x.name == "node_cpu_seconds_total")
+            .unwrap();
+        assert_eq!(cpu.metric_type, MetricType::Counter);
+        assert_eq!(cpu.samples[0].value, 12.5);
+        assert_eq!(cpu.samples[0].timestamp, Some(10.0));
+        assert_eq!(
+            cpu.samples[0].labels.get("cpu").map(|x| x.as_str()),
+            Some("0")
+        );
+        assert!(!cpu.samples[0].labels.contains_key("__name__"));
+        let load = a.iter().find(|x| x.name == "node_load1").unwrap();
+        assert_eq!(load.metric_type, MetricType::Gauge);
+        // the unparsable value is skipped, NaN is a value
+        assert_eq!(instances["b:9100"][0].samples.len(), 1);
+    }
+
+    #[test]
+    fn instances_with_only_up_are_other_jobs_unless_down() {
+        let points = vec![
+            point(
+                &[("__name__", "up"), ("instance", "grafana:3000")],
+                10.0,
+                "1",
+            ),
+            point(&[("__name__", "up"), ("instance", "db:9100")], 10.0, "0"),
+        ];
+        let instances = group(points.into_iter());
+        assert_eq!(instances.keys().collect::<Vec<_>>(), ["db:9100"]);
+    }
+
+    #[test]
+    fn node_result_follows_up() {
+        let points = vec![
+            point(&[("__name__", "up"), ("instance", "a")], 10.0, "1"),
+            point(&[("__name__", "node_load1"), ("instance", "a")], 10.0, "1"),
+            point(&[("__name__", "up"), ("instance", "b")], 10.0, "0"),
+            point(&[("__name__", "node_load1"), ("instance", "c")], 10.0, "1"),
+        ];
+        let mut instances = group(points.into_iter());
+        assert_eq!(
+            node_result(instances.remove("a").unwrap()).unwrap().len(),
+            2
+        );
+        assert!(matches!(
+            node_result(instances.remove("b").unwrap()),
+            Err(ScrapeError::Down(_))
+        ));
+        // no up series, nothing says the exporter is down
+        assert!(node_result(instances.remove("c").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn node_names_are_sorted_and_unique() {
+        let names = ["b:9100", "a:9100", "b:9100"].map(String::from);
+        assert_eq!(node_names(names.iter()), ["a:9100", "b:9100"]);
+    }
+
+    #[test]
+    fn metric_types_from_the_name() {
+        assert_eq!(metric_type("node_forks_total"), MetricType::Counter);
+        assert_eq!(
+            metric_type("http_request_duration_seconds_bucket"),
+            MetricType::Counter
+        );
+        assert_eq!(metric_type("node_memory_MemTotal_bytes"), MetricType::Gauge);
+        assert_eq!(metric_type("up"), MetricType::Gauge);
+    }
+
+    #[test]
+    fn every_series_the_views_read_is_queried() {
+        // string literals naming node series outside the test modules
+        let sources = [
+            include_str!("process.rs"),
+            include_str!("trends.rs"),
+            include_str!("snapshot.rs"),
+        ];
+        for source in sources {
+            let code = source.split("#[cfg(test)]").next().unwrap_or("");
+            for (start, _) in code.match_indices("\"node_") {
+                let name = code[start + 1..].split('"').next().unwrap_or("");
+                assert!(SERIES.contains(&name), "{} is not queried", name);
+            }
+        }
+    }
+}
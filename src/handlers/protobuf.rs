@@ -0,0 +1,564 @@
+// module protobuf
+use crate::handlers::exposition::{Exemplar, MetricFamily, MetricType, Sample};
+use prost::Message;
+use std::collections::BTreeMap;
+
+// io.prometheus.client messages (metrics.proto), only the fields we consume
+
+#[derive(Clone, PartialEq, Message)]
+pub struct LabelPair {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Timestamp {
+    #[prost(int64, tag = "1")]
+    pub seconds: i64,
+    #[prost(int32, tag = "2")]
+    pub nanos: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ProtoExemplar {
+    #[prost(message, repeated, tag = "1")]
+    pub label: Vec<LabelPair>,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+    #[prost(message, optional, tag = "3")]
+    pub timestamp: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Gauge {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Counter {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    #[prost(message, optional, tag = "2")]
+    pub exemplar: Option<ProtoExemplar>,
+    #[prost(message, optional, tag = "3")]
+    pub created_timestamp: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Quantile {
+    #[prost(double, tag = "1")]
+    pub quantile: f64,
+    #[prost(double, tag = "2")]
+    pub value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Summary {
+    #[prost(uint64, tag = "1")]
+    pub sample_count: u64,
+    #[prost(double, tag = "2")]
+    pub sample_sum: f64,
+    #[prost(message, repeated, tag = "3")]
+    pub quantile: Vec<Quantile>,
+    #[prost(message, optional, tag = "4")]
+    pub created_timestamp: Option<Timestamp>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Untyped {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Bucket {
+    #[prost(uint64, tag = "1")]
+    pub cumulative_count: u64,
+    #[prost(double, tag = "4")]
+    pub cumulative_count_float: f64,
+    #[prost(double, tag = "2")]
+    pub upper_bound: f64,
+    #[prost(message, optional, tag = "3")]
+    pub exemplar: Option<ProtoExemplar>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct BucketSpan {
+    #[prost(sint32, tag = "1")]
+    pub offset: i32,
+    #[prost(uint32, tag = "2")]
+    pub length: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Histogram {
+    #[prost(uint64, tag = "1")]
+    pub sample_count: u64,
+    #[prost(double, tag = "4")]
+    pub sample_count_float: f64,
+    #[prost(double, tag = "2")]
+    pub sample_sum: f64,
+    #[prost(message, repeated, tag = "3")]
+    pub bucket: Vec<Bucket>,
+    #[prost(message, optional, tag = "15")]
+    pub created_timestamp: Option<Timestamp>,
+    #[prost(sint32, tag = "5")]
+    pub schema: i32,
+    #[prost(double, tag = "6")]
+    pub zero_threshold: f64,
+    #[prost(uint64, tag = "7")]
+    pub zero_count: u64,
+    #[prost(double, tag = "8")]
+    pub zero_count_float: f64,
+    #[prost(message, repeated, tag = "9")]
+    pub negative_span: Vec<BucketSpan>,
+    #[prost(sint64, repeated, tag = "10")]
+    pub negative_delta: Vec<i64>,
+    #[prost(double, repeated, tag = "11")]
+    pub negative_count: Vec<f64>,
+    #[prost(message, repeated, tag = "12")]
+    pub positive_span: Vec<BucketSpan>,
+    #[prost(sint64, repeated, tag = "13")]
+    pub positive_delta: Vec<i64>,
+    #[prost(double, repeated, tag = "14")]
+    pub positive_count: Vec<f64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Metric {
+    #[prost(message, repeated, tag = "1")]
+    pub label: Vec<LabelPair>,
+    #[prost(message, optional, tag = "2")]
+    pub gauge: Option<Gauge>,
+    #[prost(message, optional, tag = "3")]
+    pub counter: Option<Counter>,
+    #[prost(message, optional, tag = "4")]
+    pub summary: Option<Summary>,
+    #[prost(message, optional, tag = "5")]
+    pub untyped: Option<Untyped>,
+    #[prost(message, optional, tag = "7")]
+    pub histogram: Option<Histogram>,
+    #[prost(int64, tag = "6")]
+    pub timestamp_ms: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ProtoMetricFamily {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub help: String,
+    #[prost(int32, tag = "3")]
+    pub metric_type: i32,
+    #[prost(message, repeated, tag = "4")]
+    pub metric: Vec<Metric>,
+    #[prost(string, tag = "5")]
+    pub unit: String,
+}
+
+/// decode a length delimited stream of metric families into the internal model
+pub fn decode(body: &[u8]) -> Result<Vec<MetricFamily>, Box<dyn std::error::Error>> {
+    let mut buf = body;
+    let mut families = vec![];
+    while !buf.is_empty() {
+        let pmf = ProtoMetricFamily::decode_length_delimited(&mut buf)?;
+        families.push(convert_family(pmf));
+    }
+    Ok(families)
+}
+
+// utility functions
+
+fn convert_family(pmf: ProtoMetricFamily) -> MetricFamily {
+    let metric_type = match pmf.metric_type {
+        0 => MetricType::Counter,
+        1 => MetricType::Gauge,
+        2 => MetricType::Summary,
+        4 => MetricType::Histogram,
+        5 => MetricType::GaugeHistogram,
+        _ => MetricType::Unknown,
+    };
+    let mut samples = vec![];
+    for metric in pmf.metric.iter() {
+        let labels = to_labels(&metric.label);
+        let timestamp = match metric.timestamp_ms {
+            0 => None,
+            ms => Some(ms as f64 / 1000.0),
+        };
+        let named = |suffix: &str| format!("{}{}", pmf.name, suffix);
+        let mut push = |name: String, extra: Option<(&str, String)>, value: f64, exemplar| {
+            let mut labels = labels.clone();
+            if let Some((k, v)) = extra {
+                labels.insert(k.to_string(), v);
+            }
+            samples.push(Sample {
+                name,
+                labels,
+                value,
+                timestamp,
+                exemplar,
+            });
+        };
+        if let Some(counter) = &metric.counter {
+            let exemplar = counter.exemplar.as_ref().map(to_exemplar);
+            push(named(""), None, counter.value, exemplar);
+            if let Some(created) = &counter.created_timestamp {
+                // counter names carry _total, the created series uses the base name
+                let base = pmf.name.strip_suffix("_total").unwrap_or(&pmf.name);
+                push(format!("{}_created", base), None, to_seconds(created), None);
+            }
+        } else if let Some(gauge) = &metric.gauge {
+            push(named(""), None, gauge.value, None);
+        } else if let Some(untyped) = &metric.untyped {
+            push(named(""), None, untyped.value, None);
+        } else if let Some(summary) = &metric.summary {
+            for q in summary.quantile.iter() {
+                push(
+                    named(""),
+                    Some(("quantile", format_bound(q.quantile))),
+                    q.value,
+                    None,
+                );
+            }
+            push(named("_sum"), None, summary.sample_sum, None);
+            push(named("_count"), None, summary.sample_count as f64, None);
+            if let Some(created) = &summary.created_timestamp {
+                push(named("_created"), None, to_seconds(created), None);
+            }
+        } else if let Some(histogram) = &metric.histogram {
+            let (bucket, sum, count) = match metric_type {
+                MetricType::GaugeHistogram => ("_gbucket", "_gsum", "_gcount"),
+                _ => ("_bucket", "_sum", "_count"),
+            };
+            let total = match histogram.sample_count_float {
+                x if x > 0.0 => x,
+                _ => histogram.sample_count as f64,
+            };
+            for (le, value, exemplar) in histogram_buckets(histogram, total) {
+                push(
+                    named(bucket),
+                    Some(("le", format_bound(le))),
+                    value,
+                    exemplar,
+                );
+            }
+            push(named(sum), None, histogram.sample_sum, None);
+            push(named(count), None, total, None);
+            if let Some(created) = &histogram.created_timestamp {
+                push(named("_created"), None, to_seconds(created), None);
+            }
+        }
+    }
+    MetricFamily {
+        name: pmf.name,
+        help: pmf.help,
+        unit: pmf.unit,
+        metric_type,
+        samples,
+    }
+}
+
+/// cumulative buckets, native (sparse) histograms are expanded into `le` buckets
+fn histogram_buckets(histogram: &Histogram, total: f64) -> Vec<(f64, f64, Option<Exemplar>)> {
+    let mut result = vec![];
+    if !histogram.bucket.is_empty() {
+        for b in histogram.bucket.iter() {
+            let count = match b.cumulative_count_float {
+                x if x > 0.0 => x,
+                _ => b.cumulative_count as f64,
+            };
+            result.push((b.upper_bound, count, b.exemplar.as_ref().map(to_exemplar)));
+        }
+    } else if !histogram.positive_span.is_empty() || !histogram.negative_span.is_empty() {
+        let base = 2f64.powf(2f64.powi(-histogram.schema));
+        let negative = native_counts(
+            &histogram.negative_span,
+            &histogram.negative_delta,
+            &histogram.negative_count,
+        );
+        let positive = native_counts(
+            &histogram.positive_span,
+            &histogram.positive_delta,
+            &histogram.positive_count,
+        );
+        let mut cumulative = 0.0;
+        // negative buckets cover [-base^i, -base^(i-1)), walk from the most negative
+        for (index, count) in negative.iter().rev() {
+            cumulative += count;
+            result.push((-base.powi(index - 1), cumulative, None));
+        }
+        let zero = match histogram.zero_count_float {
+            x if x > 0.0 => x,
+            _ => histogram.zero_count as f64,
+        };
+        cumulative += zero;
+        result.push((histogram.zero_threshold, cumulative, None));
+        for (index, count) in positive.iter() {
+            cumulative += count;
+            result.push((base.powi(*index), cumulative, None));
+        }
+    }
+    if result.last().map(|x| x.0 != f64::INFINITY).unwrap_or(true) {
+        result.push((f64::INFINITY, total, None));
+    }
+    result
+}
+
+/// absolute counts per native bucket index
+fn native_counts(spans: &[BucketSpan], deltas: &[i64], counts: &[f64]) -> Vec<(i32, f64)> {
+    let mut result = vec![];
+    let mut index = 0;
+    let mut position = 0;
+    let mut current: i64 = 0;
+    for span in spans.iter() {
+        index += span.offset;
+        for _ in 0..span.length {
+            let value = match counts.get(position) {
+                Some(x) => *x,
+                None => {
+                    current += deltas.get(position).copied().unwrap_or(0);
+                    current as f64
+                }
+            };
+            result.push((index, value));
+            index += 1;
+            position += 1;
+        }
+    }
+    result
+}
+
+fn to_labels(pairs: &[LabelPair]) -> BTreeMap<String, String> {
+    pairs
+        .iter()
+        .map(|x| (x.name.clone(), x.value.clone()))
+        .collect()
+}
+
+fn to_exemplar(exemplar: &ProtoExemplar) -> Exemplar {
+    Exemplar {
+        labels: to_labels(&exemplar.label),
+        value: exemplar.value,
+        timestamp: exemplar.timestamp.as_ref().map(to_seconds),
+    }
+}
+
+fn to_seconds(ts: &Timestamp) -> f64 {
+    ts.seconds as f64 + ts.nanos as f64 / 1e9
+}
+
+fn format_bound(value: f64) -> String {
+    match value {
+        x if x == f64::INFINITY => "+Inf".to_string(),
+        x if x == f64::NEG_INFINITY => "-Inf".to_string(),
+        x => x.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label(name: &str, value: &str) -> LabelPair {
+        LabelPair {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    fn family(name: &str, metric_type: i32, metric: Vec<Metric>) -> ProtoMetricFamily {
+        ProtoMetricFamily {
+            name: name.to_string(),
+            help: "help".to_string(),
+            metric_type,
+            metric,
+            unit: String::new(),
+        }
+    }
+
+    fn encode(families: &[ProtoMetricFamily]) -> Vec<u8> {
+        let mut body = vec![];
+        for family in families.iter() {
+            family.encode_length_delimited(&mut body).unwrap();
+        }
+        body
+    }
+
+    fn values(family: &MetricFamily) -> Vec<(String, Option<String>, f64)> {
+        family
+            .samples
+            .iter()
+            .map(|x| {
+                (
+                    x.name.clone(),
+                    x.label("le").map(|x| x.to_string()),
+                    x.value,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn counters_and_gauges() {
+        let counter = Metric {
+            label: vec![label("mode", "user")],
+            counter: Some(Counter {
+                value: 12.5,
+                exemplar: Some(ProtoExemplar {
+                    label: vec![label("trace_id", "abc")],
+                    value: 0.5,
+                    timestamp: Some(Timestamp {
+                        seconds: 1_700_000_000,
+                        nanos: 500_000_000,
+                    }),
+                }),
+                created_timestamp: Some(Timestamp {
+                    seconds: 1_600_000_000,
+                    nanos: 0,
+                }),
+            }),
+            ..Default::default()
+        };
+        let gauge = Metric {
+            gauge: Some(Gauge { value: 3.0 }),
+            timestamp_ms: 1_700_000_000_250,
+            ..Default::default()
+        };
+        let body = encode(&[
+            family("requests_total", 0, vec![counter]),
+            family("temperature", 1, vec![gauge]),
+        ]);
+        let families = decode(&body).unwrap();
+        assert_eq!(families.len(), 2);
+        let requests = &families[0];
+        assert_eq!(requests.metric_type, MetricType::Counter);
+        assert_eq!(requests.help, "help");
+        assert_eq!(requests.samples[0].label("mode"), Some("user"));
+        assert_eq!(requests.samples[0].value, 12.5);
+        let exemplar = requests.samples[0].exemplar.as_ref().unwrap();
+        assert_eq!(exemplar.timestamp, Some(1_700_000_000.5));
+        assert_eq!(requests.samples[1].name, "requests_created");
+        assert_eq!(requests.samples[1].value, 1_600_000_000.0);
+        assert_eq!(families[1].metric_type, MetricType::Gauge);
+        assert_eq!(families[1].samples[0].timestamp, Some(1_700_000_000.25));
+    }
+
+    #[test]
+    fn summary_samples() {
+        let summary = Metric {
+            summary: Some(Summary {
+                sample_count: 4,
+                sample_sum: 2.0,
+                quantile: vec![Quantile {
+                    quantile: 0.5,
+                    value: 0.4,
+                }],
+                created_timestamp: None,
+            }),
+            ..Default::default()
+        };
+        let families = decode(&encode(&[family("latency", 2, vec![summary])])).unwrap();
+        let samples = &families[0].samples;
+        assert_eq!(samples[0].label("quantile"), Some("0.5"));
+        assert_eq!(samples[1].name, "latency_sum");
+        assert_eq!(samples[2].name, "latency_count");
+        assert_eq!(samples[2].value, 4.0);
+    }
+
+    #[test]
+    fn classic_histogram_gets_an_inf_bucket() {
+        let histogram = Metric {
+            histogram: Some(Histogram {
+                sample_count: 5,
+                sample_sum: 1.5,
+                bucket: vec![Bucket {
+                    cumulative_count: 3,
+                    upper_bound: 0.5,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let families = decode(&encode(&[family("rtt", 4, vec![histogram.clone()])])).unwrap();
+        assert_eq!(
+            values(&families[0]),
+            [
+                ("rtt_bucket".to_string(), Some("0.5".to_string()), 3.0),
+                ("rtt_bucket".to_string(), Some("+Inf".to_string()), 5.0),
+                ("rtt_sum".to_string(), None, 1.5),
+                ("rtt_count".to_string(), None, 5.0),
+            ]
+        );
+        let families = decode(&encode(&[family("queue", 5, vec![histogram])])).unwrap();
+        assert_eq!(families[0].metric_type, MetricType::GaugeHistogram);
+        let names: Vec<&str> = families[0]
+            .samples
+            .iter()
+            .map(|x| x.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "queue_gbucket",
+                "queue_gbucket",
+                "queue_gsum",
+                "queue_gcount"
+            ]
+        );
+    }
+
+    #[test]
+    fn native_histogram_is_expanded() {
+        // schema 0 is base 2, buckets 1 and 2 are (1, 2] and (2, 4], one negative at [-1, -0.5)
+        let histogram = Metric {
+            histogram: Some(Histogram {
+                sample_count: 7,
+                sample_sum: 9.0,
+                schema: 0,
+                zero_threshold: 0.001,
+                zero_count: 1,
+                positive_span: vec![BucketSpan {
+                    offset: 1,
+                    length: 2,
+                }],
+                positive_delta: vec![2, 1],
+                negative_span: vec![BucketSpan {
+                    offset: 0,
+                    length: 1,
+                }],
+                negative_delta: vec![1],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let families = decode(&encode(&[family("size", 4, vec![histogram])])).unwrap();
+        let buckets: Vec<(Option<String>, f64)> = values(&families[0])
+            .into_iter()
+            .filter(|x| x.0 == "size_bucket")
+            .map(|x| (x.1, x.2))
+            .collect();
+        let expected = [
+            ("-0.5", 1.0),
+            ("0.001", 2.0),
+            ("2", 4.0),
+            ("4", 7.0),
+            ("+Inf", 7.0),
+        ];
+        let expected: Vec<(Option<String>, f64)> = expected
+            .iter()
+            .map(|(le, v)| (Some(le.to_string()), *v))
+            .collect();
+        assert_eq!(buckets, expected);
+    }
+
+    #[test]
+    fn truncated_body_is_an_error() {
+        let body = encode(&[family("up", 1, vec![Metric::default()])]);
+        assert!(decode(&body[..body.len() - 1]).is_err());
+        assert!(decode(&[]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,105 @@
+// module receiver
+// stand-in receiver for the output tests, every http request and udp datagram is handed to
+// the test as received (the body still encoded) so it can assert on the headers and content
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+/// an http request as received
+pub struct Request {
+    pub head: String,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// the request line, e.g. `POST /api/v1/write HTTP/1.1`
+    pub fn line(&self) -> &str {
+        self.head.lines().next().unwrap_or("")
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.head
+            .lines()
+            .filter_map(|x| x.split_once(':'))
+            .find(|(k, _)| k.trim().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim())
+    }
+}
+
+/// listen on a free local port and answer each request with the next of `statuses` (204 once
+/// they run out), returns the address and the requests as they arrive
+pub async fn serve(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut statuses = statuses.into_iter();
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let Some(request) = read_request(&mut stream).await else {
+                continue;
+            };
+            // the body is what the sink reports as the error
+            let status = statuses.next().unwrap_or(204);
+            let response = format!(
+                "HTTP/1.1 {} stub\r\nContent-Length: 4\r\nConnection: close\r\n\r\nstub",
+                status
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+            if tx.send(request).is_err() {
+                return;
+            }
+        }
+    });
+    (address, rx)
+}
+
+/// bind a free local udp port, returns the address and the datagrams as they arrive
+pub async fn serve_udp() -> (String, mpsc::UnboundedReceiver<Vec<u8>>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        while let Ok(n) = socket.recv(&mut buf).await {
+            if tx.send(buf[..n].to_vec()).is_err() {
+                return;
+            }
+        }
+    });
+    (address, rx)
+}
+
+// utility functions
+
+async fn read_request(stream: &mut TcpStream) -> Option<Request> {
+    let mut data: Vec<u8> = vec![];
+    let mut buf = vec![0u8; 65536];
+    let head_end = loop {
+        if let Some(pos) = data.windows(4).position(|x| x == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        read(stream, &mut buf, &mut data).await?;
+    };
+    let mut request = Request {
+        head: String::from_utf8_lossy(&data[..head_end]).to_string(),
+        body: vec![],
+    };
+    let length: usize = request.header("content-length")?.parse().ok()?;
+    while data.len() < head_end + length {
+        read(stream, &mut buf, &mut data).await?;
+    }
+    request.body = data[head_end..head_end + length].to_vec();
+    Some(request)
+}
+
+/// none once the peer is gone
+async fn read(stream: &mut TcpStream, buf: &mut [u8], data: &mut Vec<u8>) -> Option<()> {
+    match stream.read(buf).await {
+        Ok(0) | Err(_) => None,
+        Ok(n) => {
+            data.extend_from_slice(&buf[..n]);
+            Some(())
+        }
+    }
+}
@@ -0,0 +1,170 @@
+// module remote_write
+// prometheus remote_write output, protobuf compressed with snappy (protocol version 1.0)
+#[cfg(test)]
+use crate::error::generic::GenericError;
+use crate::handlers::sink::{self, Point, SendError};
+use crate::handlers::target::ScrapeTarget;
+use prost::Message;
+
+const PROTOCOL_VERSION: &str = "0.1.0";
+
+// prometheus.WriteRequest messages (remote.proto, types.proto), only the fields we send
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    /// sorted by name, `__name__` included
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<RemoteSample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RemoteSample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    /// unix milliseconds
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+/// post a batch, one series per point
+pub async fn send(server: &ScrapeTarget, points: &[Point]) -> Result<(), SendError> {
+    let body = snap::raw::Encoder::new()
+        .compress_vec(&encode(points).encode_to_vec())
+        .map_err(|e| SendError::Drop(format!("snappy : {}", e)))?;
+    let req = server
+        .post(server.url.clone())
+        .map_err(|e| SendError::Retry(e.to_string()))?
+        .header(reqwest::header::CONTENT_TYPE, "application/x-protobuf")
+        .header(reqwest::header::CONTENT_ENCODING, "snappy")
+        .header("X-Prometheus-Remote-Write-Version", PROTOCOL_VERSION)
+        .header(
+            reqwest::header::USER_AGENT,
+            format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        )
+        .body(body);
+    sink::check(req).await
+}
+
+pub fn encode(points: &[Point]) -> WriteRequest {
+    let timeseries = points
+        .iter()
+        .map(|point| {
+            let mut labels = point.all_labels();
+            labels.insert("__name__".to_string(), point.name.clone());
+            TimeSeries {
+                labels: labels
+                    .into_iter()
+                    .map(|(name, value)| Label { name, value })
+                    .collect(),
+                samples: vec![RemoteSample {
+                    value: point.value,
+                    timestamp: point.unix_nanos() / 1_000_000,
+                }],
+            }
+        })
+        .collect();
+    WriteRequest { timeseries }
+}
+
+/// decode a request body
+#[cfg(test)]
+pub fn decode(body: &[u8]) -> Result<WriteRequest, Box<dyn std::error::Error>> {
+    let raw = snap::raw::Decoder::new()
+        .decompress_vec(body)
+        .map_err(|e| GenericError::new(format!("[decode] snappy : {}", e)))?;
+    let request = WriteRequest::decode(raw.as_slice())
+        .map_err(|e| GenericError::new(format!("[decode] protobuf : {}", e)))?;
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn write_request_round_trip() {
+        let points = vec![
+            Point {
+                name: "node_load1".to_string(),
+                resource: labels(&[("job", "node"), ("instance", "a:9100")]),
+                labels: labels(&[("instance", "own"), ("cpu", "0")]),
+                value: 0.25,
+                ts: 1700000000.123,
+                monotonic: false,
+            },
+            Point {
+                name: "up".to_string(),
+                resource: labels(&[("job", "node"), ("instance", "a:9100")]),
+                labels: BTreeMap::new(),
+                value: 0.0,
+                ts: 1700000000.0,
+                monotonic: false,
+            },
+        ];
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&encode(&points).encode_to_vec())
+            .unwrap();
+        let request = decode(&body).unwrap();
+        assert_eq!(request.timeseries.len(), 2);
+        let names: Vec<(&str, &str)> = request.timeseries[0]
+            .labels
+            .iter()
+            .map(|x| (x.name.as_str(), x.value.as_str()))
+            .collect();
+        // sorted, the sample's own labels win over the resource
+        assert_eq!(
+            names,
+            [
+                ("__name__", "node_load1"),
+                ("cpu", "0"),
+                ("instance", "own"),
+                ("job", "node")
+            ]
+        );
+        // milliseconds, rounded rather than truncated
+        assert_eq!(
+            request.timeseries[0].samples,
+            [RemoteSample {
+                value: 0.25,
+                timestamp: 1700000000123
+            }]
+        );
+        let up = &request.timeseries[1];
+        assert_eq!(up.labels[0].value, "up");
+        assert_eq!(up.samples[0].value, 0.0);
+        assert_eq!(up.samples[0].timestamp, 1700000000000);
+    }
+
+    #[test]
+    fn invalid_bodies_are_errors() {
+        assert!(decode(b"not snappy").is_err());
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&[0xff, 0xff])
+            .unwrap();
+        assert!(decode(&body).is_err());
+    }
+}
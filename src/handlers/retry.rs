@@ -0,0 +1,87 @@
+// module retry
+use crate::error::scrape::ScrapeError;
+use crate::handlers::capture;
+use crate::handlers::exposition::MetricFamily;
+use crate::handlers::process::{Body, MetricsInterface, Service};
+use crate::handlers::target::ScrapeTarget;
+use custom_logger as log;
+use std::time::Duration;
+
+/// scrape with retries, the final outcome is recorded (when recording) before it is decoded
+pub async fn scrape_with_retry(target: &ScrapeTarget) -> Result<Vec<MetricFamily>, ScrapeError> {
+    let result = fetch_with_retry(target).await;
+    capture::record(&target.url, target.spec.split_by_instance, &result);
+    result?.decode()
+}
+
+/// fetch with retries, exponential backoff and jitter (only transient failures are retried)
+async fn fetch_with_retry(target: &ScrapeTarget) -> Result<Body, ScrapeError> {
+    let svc = Service::new();
+    let mut attempt = 0;
+    loop {
+        let err = match svc.fetch(target).await {
+            Ok(body) => return Ok(body),
+            Err(err) => err,
+        };
+        let transient = match err {
+            ScrapeError::Down(_) | ScrapeError::Timeout(_) => true,
+            ScrapeError::Http(code, _) => code >= 500 || code == 429,
+            ScrapeError::Parse(_) | ScrapeError::Auth(_) | ScrapeError::Config(_) => false,
+        };
+        if !transient || attempt >= target.settings.retries {
+            return Err(err);
+        }
+        let delay = backoff(
+            attempt,
+            target.settings.backoff_base_ms,
+            target.settings.backoff_max_ms,
+        );
+        log::debug!(
+            "[fetch_with_retry] {} attempt {} failed ({}), retrying in {:?}",
+            target.url,
+            attempt + 1,
+            err,
+            delay
+        );
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// exponential backoff capped at max, with "equal jitter" (half fixed, half random)
+fn backoff(attempt: u32, base_ms: u64, max_ms: u64) -> Duration {
+    let delay = base_ms.saturating_mul(1 << attempt.min(16)).min(max_ms);
+    let half = delay / 2;
+    Duration::from_millis(half + fastrand::u64(0..=half))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_within_the_jitter_bounds() {
+        for (attempt, delay) in [
+            (0, 250),
+            (1, 500),
+            (2, 1000),
+            (3, 2000),
+            (4, 2000),
+            (40, 2000),
+        ] {
+            for _ in 0..200 {
+                let ms = backoff(attempt, 250, 2000).as_millis() as u64;
+                assert!(
+                    ms >= delay / 2 && ms <= delay,
+                    "attempt {} : {}ms",
+                    attempt,
+                    ms
+                );
+            }
+        }
+        assert_eq!(backoff(3, 0, 2000), Duration::ZERO);
+        // large settings saturate rather than overflow
+        let ms = backoff(u32::MAX, u64::MAX, u64::MAX).as_millis();
+        assert!(ms >= (u64::MAX / 2) as u128);
+    }
+}
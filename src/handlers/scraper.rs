@@ -0,0 +1,330 @@
+// module scraper
+use crate::config::load::{Parameters, ScrapeMode, Target};
+use crate::error::scrape::ScrapeError;
+use crate::handlers::breaker::CircuitBreaker;
+use crate::handlers::capture::Replay;
+use crate::handlers::discovery::DiscoveryUpdate;
+use crate::handlers::exposition::{self, MetricFamily};
+use crate::handlers::retry::scrape_with_retry;
+use crate::handlers::target::ScrapeTarget;
+use custom_logger as log;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, mpsc, watch};
+use tokio::task::{self, JoinError, JoinSet};
+use tokio::time::MissedTickBehavior;
+
+/// result of a single scrape, sent from the background task to the ui
+#[derive(Debug)]
+pub struct ScrapeUpdate {
+    pub node: String,
+    pub result: Result<Vec<MetricFamily>, ScrapeError>,
+}
+
+/// what a scrape task hands back, the node, whether it succeeded and the instances behind a
+/// federated target
+type Done = (String, bool, Option<Vec<String>>);
+
+/// settings for the background scrape task
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub mode: ScrapeMode,
+    pub targets: Vec<ScrapeTarget>,
+    pub concurrency: usize,
+    /// kept to build targets that are discovered at runtime
+    pub params: Parameters,
+    /// replay a capture instead of scraping
+    pub replay: Option<Replay>,
+}
+
+impl Schedule {
+    pub fn from_params(params: &Parameters) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Schedule {
+            mode: params.mode,
+            targets: ScrapeTarget::from_params(params)?,
+            concurrency: params.concurrency,
+            params: params.clone(),
+            replay: None,
+        })
+    }
+}
+
+/// background scrape loop, each scrape runs in its own task so a slow node never blocks the others
+/// (exits when either the selection sender or the update receiver is dropped)
+pub async fn run(
+    schedule: Schedule,
+    mut selected: watch::Receiver<String>,
+    mut target_list: watch::Receiver<Vec<Target>>,
+    tx: mpsc::Sender<ScrapeUpdate>,
+    discovery_tx: mpsc::Sender<DiscoveryUpdate>,
+) {
+    let semaphore = Arc::new(Semaphore::new(schedule.concurrency.max(1)));
+    let mut targets: HashMap<String, ScrapeTarget> = schedule
+        .targets
+        .iter()
+        .map(|t| (t.url.clone(), t.clone()))
+        .collect();
+    let mut order: Vec<String> = schedule.targets.iter().map(|t| t.url.clone()).collect();
+    let mut breakers: HashMap<String, CircuitBreaker> = schedule
+        .targets
+        .iter()
+        .map(|t| (t.url.clone(), CircuitBreaker::new(&t.settings)))
+        .collect();
+    let mut in_flight: HashSet<String> = HashSet::new();
+    let mut scraped: HashSet<String> = HashSet::new();
+    // instances last seen behind each federated target
+    let mut federated: HashMap<String, Vec<String>> = HashMap::new();
+    let mut tasks: JoinSet<Done> = JoinSet::new();
+    // node of every running task, so a task that panics still frees its node
+    let mut task_nodes: HashMap<task::Id, String> = HashMap::new();
+    // each target has its own interval, the ticker only sets the scheduling granularity
+    let mut next_due: HashMap<String, Instant> = HashMap::new();
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let nodes = tokio::select! {
+            _ = ticker.tick() => {
+                let now = Instant::now();
+                let candidates = match schedule.mode {
+                    ScrapeMode::Fleet => order.clone(),
+                    // a federated target carries many nodes, it is always scraped
+                    ScrapeMode::Single => order
+                        .iter()
+                        .filter(|x| targets.get(*x).map(|t| t.spec.split_by_instance).unwrap_or(false))
+                        .cloned()
+                        .chain([selected.borrow().clone()])
+                        .collect(),
+                };
+                candidates
+                    .into_iter()
+                    .filter(|x| next_due.get(x).map(|due| now >= *due).unwrap_or(true))
+                    .collect()
+            },
+            changed = selected.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let node = selected.borrow_and_update().clone();
+                match schedule.mode {
+                    // fleet data is already being refreshed, only fetch nodes not seen yet
+                    ScrapeMode::Fleet if scraped.contains(&node) => vec![],
+                    _ => vec![node],
+                }
+            },
+            changed = target_list.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let list = target_list.borrow_and_update().clone();
+                let (rebuilt, failed) = rebuild(&targets, &list, &schedule.params);
+                targets = rebuilt;
+                for (node, err) in failed {
+                    let _ = tx.send(ScrapeUpdate { node, result: Err(ScrapeError::Config(err)) }).await;
+                }
+                order = list.iter().map(|t| t.url.clone()).filter(|x| targets.contains_key(x)).collect();
+                breakers.retain(|k, _| targets.contains_key(k));
+                for target in targets.values() {
+                    breakers
+                        .entry(target.url.clone())
+                        .or_insert_with(|| CircuitBreaker::new(&target.settings));
+                }
+                next_due.retain(|k, _| targets.contains_key(k));
+                scraped.retain(|k| targets.contains_key(k));
+                federated.retain(|k, _| targets.contains_key(k));
+                vec![]
+            },
+            Some(done) = tasks.join_next_with_id() => {
+                if let Some((node, ok, instances)) = finished(done, &mut task_nodes) {
+                    in_flight.remove(&node);
+                    if let Some(instances) = instances {
+                        federated.insert(node.clone(), instances);
+                    }
+                    if let Some(breaker) = breakers.get_mut(&node) {
+                        match ok {
+                            true => breaker.record_success(),
+                            false => breaker.record_failure(Instant::now()),
+                        }
+                    }
+                }
+                vec![]
+            },
+        };
+        if tx.is_closed() {
+            break;
+        }
+        for node in nodes {
+            let target = match targets.get(&node) {
+                Some(target) => target.clone(),
+                None => continue,
+            };
+            // never queue a second request for a node that is still in flight
+            if in_flight.contains(&node) {
+                log::trace!("[run] skipping {} (in flight)", node);
+                continue;
+            }
+            if let Some(breaker) = breakers.get_mut(&node)
+                && !breaker.allow(Instant::now())
+            {
+                log::trace!("[run] skipping {} (circuit open)", node);
+                continue;
+            }
+            in_flight.insert(node.clone());
+            scraped.insert(node.clone());
+            next_due.insert(node.clone(), Instant::now() + target.interval);
+            let semaphore = semaphore.clone();
+            let tx = tx.clone();
+            let discovery_tx = discovery_tx.clone();
+            let known = federated.get(&node).cloned().unwrap_or_default();
+            let task_node = node.clone();
+            let task = tasks.spawn(async move {
+                let _permit = semaphore.acquire_owned().await;
+                let result = scrape_with_retry(&target).await;
+                let ok = result.is_ok();
+                if target.spec.split_by_instance {
+                    let (url, labels) = (&target.url, &target.spec.labels);
+                    let instances =
+                        send_split(url, labels, result, known, &tx, &discovery_tx).await;
+                    return (node, ok, Some(instances));
+                }
+                let _ = tx
+                    .send(ScrapeUpdate {
+                        node: node.clone(),
+                        result,
+                    })
+                    .await;
+                (node, ok, None)
+            });
+            task_nodes.insert(task.id(), task_node);
+        }
+    }
+    tasks.abort_all();
+}
+
+/// send a federated scrape as one update per `instance`, the instances are announced as
+/// discovered nodes with the target's labels (returns the instances now behind the target)
+pub async fn send_split(
+    url: &str,
+    labels: &BTreeMap<String, String>,
+    result: Result<Vec<MetricFamily>, ScrapeError>,
+    known: Vec<String>,
+    tx: &mpsc::Sender<ScrapeUpdate>,
+    discovery_tx: &mpsc::Sender<DiscoveryUpdate>,
+) -> Vec<String> {
+    let source = format!("federate:{}", url);
+    match result {
+        Ok(families) => {
+            let nodes = exposition::split_by_label(&families, "instance");
+            let instances: Vec<String> = nodes.keys().cloned().collect();
+            let targets = instances
+                .iter()
+                .map(|x| {
+                    let mut node = Target::from_url(x);
+                    node.labels = labels.clone();
+                    node.via = Some(url.to_string());
+                    node
+                })
+                .collect();
+            let update = DiscoveryUpdate {
+                source,
+                result: Ok(targets),
+            };
+            let _ = discovery_tx.send(update).await;
+            for (node, families) in nodes {
+                let result = Ok(families);
+                let _ = tx.send(ScrapeUpdate { node, result }).await;
+            }
+            instances
+        }
+        Err(err) => {
+            // keep the nodes, each one shows the error of the federated target
+            let update = DiscoveryUpdate {
+                source,
+                result: Err(format!("{} : {}", url, err)),
+            };
+            let _ = discovery_tx.send(update).await;
+            for node in known.iter() {
+                let update = ScrapeUpdate {
+                    node: node.clone(),
+                    result: Err(err.clone()),
+                };
+                let _ = tx.send(update).await;
+            }
+            known
+        }
+    }
+}
+
+// utility functions
+
+/// the outcome of a finished scrape task, a task that panicked counts as a failed scrape of
+/// its node
+fn finished(
+    done: Result<(task::Id, Done), JoinError>,
+    task_nodes: &mut HashMap<task::Id, String>,
+) -> Option<Done> {
+    match done {
+        Ok((id, done)) => {
+            task_nodes.remove(&id);
+            Some(done)
+        }
+        Err(err) => {
+            let node = task_nodes.remove(&err.id())?;
+            log::error!("[run] scrape of {} failed : {}", node, err);
+            Some((node, false, None))
+        }
+    }
+}
+
+/// reuse unchanged targets (and their http clients), build the new or changed ones
+fn rebuild(
+    current: &HashMap<String, ScrapeTarget>,
+    list: &[Target],
+    params: &Parameters,
+) -> (HashMap<String, ScrapeTarget>, Vec<(String, String)>) {
+    let mut targets = HashMap::new();
+    let mut failed = vec![];
+    for spec in list.iter() {
+        match current.get(&spec.url) {
+            Some(existing) if existing.spec == *spec => {
+                targets.insert(spec.url.clone(), existing.clone());
+            }
+            _ => match ScrapeTarget::new(spec, params) {
+                Ok(target) => {
+                    targets.insert(spec.url.clone(), target);
+                }
+                Err(e) => failed.push((spec.url.clone(), e.to_string())),
+            },
+        }
+    }
+    (targets, failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_panicking_scrape_frees_its_node() {
+        let mut tasks: JoinSet<Done> = JoinSet::new();
+        let mut task_nodes = HashMap::new();
+        let task = tasks.spawn(async { panic!("scrape task") });
+        task_nodes.insert(task.id(), "a".to_string());
+        let task = tasks.spawn(async { ("b".to_string(), true, None) });
+        task_nodes.insert(task.id(), "b".to_string());
+        let mut done = vec![];
+        while let Some(result) = tasks.join_next_with_id().await {
+            done.push(finished(result, &mut task_nodes).unwrap());
+        }
+        done.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            done,
+            [
+                ("a".to_string(), false, None),
+                ("b".to_string(), true, None)
+            ]
+        );
+        assert!(task_nodes.is_empty());
+    }
+}
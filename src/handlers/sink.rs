@@ -0,0 +1,584 @@
+// module sink
+// forwards every scrape to the configured outputs (prometheus remote_write, influxdb, otlp)
+// samples are queued per output and sent in batches, a batch that fails with a network error,
+// a 5xx or a 429 stays at the front of the queue and is retried with backoff
+use crate::config::load::{
+    InfluxConfig, OtlpConfig, OutputConfig, Parameters, RemoteWriteConfig, Target,
+};
+use crate::handlers::exposition::{self, MetricFamily, MetricType};
+use crate::handlers::target::ScrapeTarget;
+use crate::handlers::{influx, otlp, remote_write};
+use custom_logger as log;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// a scrape to forward, `families` is empty when the scrape failed (sent as `up` 0)
+#[derive(Debug, Clone)]
+pub struct Scrape {
+    pub node: String,
+    /// target labels, added to series that don't have them
+    pub labels: BTreeMap<String, String>,
+    pub families: Vec<MetricFamily>,
+    pub up: bool,
+    pub ts: f64,
+}
+
+/// a sample ready to send
+#[derive(Debug, Clone)]
+pub struct Point {
+    pub name: String,
+    /// job, instance, target and external labels (the otlp resource)
+    pub resource: BTreeMap<String, String>,
+    /// the sample's own labels
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+    pub ts: f64,
+    /// counters and the counts and sums of histograms and summaries (cumulative sums in otlp)
+    pub monotonic: bool,
+}
+
+impl Point {
+    /// resource and sample labels, the sample's win
+    pub fn all_labels(&self) -> BTreeMap<String, String> {
+        let mut labels = self.resource.clone();
+        labels.extend(self.labels.iter().map(|(k, v)| (k.clone(), v.clone())));
+        labels
+    }
+
+    /// timestamps are kept to the millisecond, f64 nanoseconds would add noise digits
+    pub fn unix_nanos(&self) -> i64 {
+        (self.ts * 1000.0).round() as i64 * 1_000_000
+    }
+}
+
+/// counters shown in the status line
+#[derive(Debug, Clone, Default)]
+pub struct WriteStatus {
+    pub sent: u64,
+    pub dropped: u64,
+    pub pending: usize,
+    /// last failure, cleared by the next batch that goes through
+    pub last_error: Option<String>,
+}
+
+/// an output as the ui sees it
+pub struct Handle {
+    pub name: &'static str,
+    pub tx: mpsc::Sender<Scrape>,
+    pub status: watch::Receiver<WriteStatus>,
+    /// scrapes not handed over because the output was behind
+    pub overflow: u64,
+}
+
+impl Handle {
+    /// start the output task
+    pub fn spawn(output: Output, params: Parameters) -> (Handle, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(256);
+        let (status_tx, status) = watch::channel(WriteStatus::default());
+        let handle = Handle {
+            name: output.name(),
+            tx,
+            status,
+            overflow: 0,
+        };
+        (handle, tokio::spawn(run(output, params, rx, status_tx)))
+    }
+
+    /// queue a scrape without waiting, it is counted when the output has no room for it
+    pub fn forward(&mut self, scrape: Scrape) {
+        if self.tx.try_send(scrape).is_err() {
+            self.overflow += 1;
+        }
+    }
+}
+
+/// outcome of a failed batch
+pub enum SendError {
+    /// kept and retried
+    Retry(String),
+    /// rejected by the receiver (bad request, out of order samples), retrying won't help
+    Drop(String),
+}
+
+/// a configured output
+#[derive(Debug, Clone)]
+pub enum Output {
+    RemoteWrite(RemoteWriteConfig),
+    Influx(InfluxConfig),
+    Otlp(OtlpConfig),
+}
+
+impl Output {
+    /// every output set in the config
+    pub fn from_params(params: &Parameters) -> Vec<Output> {
+        let mut outputs = vec![];
+        if let Some(config) = &params.remote_write {
+            outputs.push(Output::RemoteWrite(config.clone()));
+        }
+        if let Some(config) = &params.influxdb {
+            outputs.push(Output::Influx(config.clone()));
+        }
+        if let Some(config) = &params.otlp {
+            outputs.push(Output::Otlp(config.clone()));
+        }
+        outputs
+    }
+
+    /// shown in the status line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Output::RemoteWrite(_) => "remote write",
+            Output::Influx(_) => "influxdb",
+            Output::Otlp(_) => "otlp",
+        }
+    }
+
+    fn url(&self) -> &str {
+        match self {
+            Output::RemoteWrite(config) => &config.url,
+            Output::Influx(config) => &config.url,
+            Output::Otlp(config) => &config.url,
+        }
+    }
+
+    fn config(&self) -> &OutputConfig {
+        match self {
+            Output::RemoteWrite(config) => &config.output,
+            Output::Influx(config) => &config.output,
+            Output::Otlp(config) => &config.output,
+        }
+    }
+}
+
+/// queue the scrapes arriving on `rx` and send them in batches until the sender is dropped,
+/// what is still queued then gets one last attempt
+pub async fn run(
+    output: Output,
+    params: Parameters,
+    mut rx: mpsc::Receiver<Scrape>,
+    status_tx: watch::Sender<WriteStatus>,
+) {
+    let url = output.url().to_string();
+    let config = output.config().clone();
+    // reuse the scrape target for the http client, timeouts, tls and auth
+    let server = match ScrapeTarget::new(&Target::from_url(&url), &params) {
+        Ok(server) => server,
+        Err(err) => {
+            let msg = format!("{} : {}", url, err);
+            status_tx.send_modify(|x| x.last_error = Some(msg));
+            return;
+        }
+    };
+    let max = config.max_samples_per_send.max(1);
+    let min_backoff = Duration::from_millis(config.min_backoff_ms.max(1));
+    let max_backoff = Duration::from_millis(config.max_backoff_ms).max(min_backoff);
+    let mut queue: VecDeque<Point> = VecDeque::new();
+    let mut status = WriteStatus::default();
+    let mut backoff = min_backoff;
+    let mut retry_at: Option<Instant> = None;
+    let mut deadline =
+        tokio::time::interval(Duration::from_secs(config.batch_send_deadline_secs.max(1)));
+    loop {
+        // a full batch goes out right away, a partial one on the deadline
+        let partial = tokio::select! {
+            scrape = rx.recv() => match scrape {
+                Some(scrape) => {
+                    queue.extend(points(&scrape, &config));
+                    false
+                }
+                None => break,
+            },
+            _ = deadline.tick() => true,
+        };
+        let overflow = queue.len().saturating_sub(config.capacity.max(max));
+        if overflow > 0 {
+            queue.drain(..overflow);
+            status.dropped += overflow as u64;
+        }
+        if retry_at.map(|x| x > Instant::now()).unwrap_or(false) {
+            status.pending = queue.len();
+            status_tx.send_replace(status.clone());
+            continue;
+        }
+        retry_at = None;
+        while queue.len() >= max || (partial && !queue.is_empty()) {
+            let count = queue.len().min(max);
+            let batch: Vec<Point> = queue.iter().take(count).cloned().collect();
+            match send(&output, &server, &batch).await {
+                Ok(()) => {
+                    queue.drain(..count);
+                    status.sent += count as u64;
+                    status.last_error = None;
+                    backoff = min_backoff;
+                }
+                Err(SendError::Drop(msg)) => {
+                    log::debug!("[run] {} : dropped {} samples, {}", url, count, msg);
+                    queue.drain(..count);
+                    status.dropped += count as u64;
+                    status.last_error = Some(msg);
+                }
+                Err(SendError::Retry(msg)) => {
+                    log::debug!("[run] {} : retry in {:?}, {}", url, backoff, msg);
+                    retry_at = Some(Instant::now() + backoff);
+                    backoff = (backoff * 2).min(max_backoff);
+                    status.last_error = Some(msg);
+                    break;
+                }
+            }
+        }
+        status.pending = queue.len();
+        status_tx.send_replace(status.clone());
+    }
+    // the sender is gone (the tui is closing), flush what is left once
+    while !queue.is_empty() {
+        let count = queue.len().min(max);
+        let batch: Vec<Point> = queue.drain(..count).collect();
+        if let Err(SendError::Retry(msg) | SendError::Drop(msg)) =
+            send(&output, &server, &batch).await
+        {
+            log::debug!(
+                "[run] {} : {} samples not sent, {}",
+                url,
+                queue.len() + count,
+                msg
+            );
+            return;
+        }
+    }
+}
+
+/// send a request, network errors, 5xx and 429 are retried, any other failure is dropped
+pub async fn check(req: reqwest::RequestBuilder) -> Result<(), SendError> {
+    let response = req
+        .send()
+        .await
+        .map_err(|e| SendError::Retry(e.to_string()))?;
+    let code = response.status();
+    if code.is_success() {
+        return Ok(());
+    }
+    let text = response.text().await.unwrap_or_default();
+    let msg = format!("http {} {}", code.as_u16(), text.trim());
+    match code.is_server_error() || code == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        true => Err(SendError::Retry(msg)),
+        false => Err(SendError::Drop(msg)),
+    }
+}
+
+// utility functions
+
+async fn send(output: &Output, server: &ScrapeTarget, batch: &[Point]) -> Result<(), SendError> {
+    match output {
+        Output::RemoteWrite(_) => remote_write::send(server, batch).await,
+        Output::Influx(_) => influx::send(server, batch).await,
+        Output::Otlp(_) => otlp::send(server, batch).await,
+    }
+}
+
+/// one point per sample, the resource is job and instance, then the target labels, then the
+/// external labels (the first one set wins), `label_map` renames or drops labels of both
+fn points(scrape: &Scrape, config: &OutputConfig) -> Vec<Point> {
+    let mut resource = BTreeMap::new();
+    resource.insert("job".to_string(), config.job.clone());
+    resource.insert(
+        "instance".to_string(),
+        instance(&scrape.node, &scrape.families),
+    );
+    for (k, v) in scrape.labels.iter().chain(config.external_labels.iter()) {
+        resource.entry(k.clone()).or_insert(v.clone());
+    }
+    let resource = relabel(resource, &config.label_map);
+    let mut result: Vec<Point> = vec![];
+    for family in scrape.families.iter() {
+        for sample in family.samples.iter() {
+            let monotonic = match family.metric_type {
+                MetricType::Counter => !sample.name.ends_with("_created"),
+                MetricType::Histogram | MetricType::Summary => ["_bucket", "_count", "_sum"]
+                    .iter()
+                    .any(|x| sample.name.ends_with(x)),
+                _ => false,
+            };
+            result.push(Point {
+                name: sample.name.clone(),
+                resource: resource.clone(),
+                labels: relabel(sample.labels.clone(), &config.label_map),
+                value: sample.value,
+                ts: sample.timestamp.unwrap_or(scrape.ts),
+                monotonic,
+            });
+        }
+    }
+    // as a prometheus scrape would, unless the source already has it (a prometheus server)
+    if !scrape.families.iter().any(|x| x.name == "up") {
+        result.push(Point {
+            name: "up".to_string(),
+            resource,
+            labels: BTreeMap::new(),
+            value: if scrape.up { 1.0 } else { 0.0 },
+            ts: scrape.ts,
+            monotonic: false,
+        });
+    }
+    result
+}
+
+fn relabel(
+    labels: BTreeMap<String, String>,
+    map: &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    labels
+        .into_iter()
+        .filter_map(|(k, v)| match map.get(&k) {
+            Some(name) if name.is_empty() => None,
+            Some(name) => Some((name.clone(), v)),
+            None => Some((k, v)),
+        })
+        .collect()
+}
+
+/// host:port of the node url (federated instances are already host:port), the host name for
+/// `local://`
+fn instance(node: &str, families: &[MetricFamily]) -> String {
+    match node.split_once("://") {
+        Some((_, host)) if !host.is_empty() => host.trim_end_matches('/').to_string(),
+        _ => exposition::samples(families, "node_uname_info")
+            .find_map(|x| x.label("nodename"))
+            .unwrap_or(node)
+            .to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::exposition::Format;
+    use crate::handlers::receiver;
+
+    fn output_config(json: &str) -> OutputConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn scrape(node: &str, body: &str) -> Scrape {
+        Scrape {
+            node: node.to_string(),
+            labels: BTreeMap::from([("rack".to_string(), "r1".to_string())]),
+            families: exposition::parse(body, Format::Prometheus).unwrap(),
+            up: true,
+            ts: 1700000000.5,
+        }
+    }
+
+    const BODY: &str = "# TYPE node_load1 gauge
+node_load1 0.5
+# TYPE node_network_receive_bytes_total counter
+node_network_receive_bytes_total{device=\"eth0\"} 1024 1700000000250
+";
+
+    /// start a remote_write output against `address`
+    fn remote_write(address: &str, config: OutputConfig) -> (Handle, JoinHandle<()>) {
+        let output = Output::RemoteWrite(RemoteWriteConfig {
+            url: format!("http://{}/api/v1/write", address),
+            output: config,
+        });
+        Handle::spawn(output, Parameters::offline(vec![]))
+    }
+
+    /// wait for a status the check accepts, every status seen on the way is returned
+    async fn wait_for(
+        status: &mut watch::Receiver<WriteStatus>,
+        check: impl Fn(&WriteStatus) -> bool,
+    ) -> Vec<WriteStatus> {
+        let mut seen = vec![];
+        let wait = async {
+            loop {
+                let current = status.borrow_and_update().clone();
+                seen.push(current.clone());
+                if check(&current) {
+                    return;
+                }
+                status.changed().await.unwrap();
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), wait)
+            .await
+            .expect("status not reached");
+        seen
+    }
+
+    #[test]
+    fn points_carry_the_resource_and_an_up_series() {
+        let config =
+            output_config(r#"{"job": "lab", "external_labels": {"site": "a", "rack": "x"}}"#);
+        let sent = points(&scrape("http://host:9100/", BODY), &config);
+        let names: Vec<&str> = sent.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["node_load1", "node_network_receive_bytes_total", "up"]
+        );
+        // target labels win over the external ones
+        let resource: Vec<(&str, &str)> = sent[0]
+            .resource
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(
+            resource,
+            [
+                ("instance", "host:9100"),
+                ("job", "lab"),
+                ("rack", "r1"),
+                ("site", "a")
+            ]
+        );
+        assert!(!sent[0].monotonic);
+        assert!(sent[1].monotonic);
+        // samples keep their own timestamp, the others take the scrape's
+        assert_eq!(sent[0].ts, 1700000000.5);
+        assert_eq!(sent[1].ts, 1700000000.25);
+        assert_eq!(sent[2].value, 1.0);
+
+        let mut down = scrape("local://", "");
+        down.up = false;
+        let up = points(&down, &config);
+        assert_eq!(up.len(), 1);
+        assert_eq!(up[0].value, 0.0);
+        assert_eq!(
+            up[0].resource.get("instance").map(|x| x.as_str()),
+            Some("local://")
+        );
+    }
+
+    #[test]
+    fn label_map_renames_and_drops() {
+        let config = output_config(
+            r#"{"label_map": {"device": "interface", "rack": "", "job": "service"},
+                "external_labels": {"site": "a"}}"#,
+        );
+        let sent = points(&scrape("http://host:9100", BODY), &config);
+        // target, resource and sample labels alike
+        let rx = &sent[1];
+        assert_eq!(
+            rx.labels,
+            BTreeMap::from([("interface".to_string(), "eth0".to_string())])
+        );
+        let resource: Vec<&str> = rx.resource.keys().map(|x| x.as_str()).collect();
+        assert_eq!(resource, ["instance", "service", "site"]);
+        assert!(sent.iter().all(|x| !x.all_labels().contains_key("rack")));
+    }
+
+    #[tokio::test]
+    async fn remote_write_posts_snappy_protobuf() {
+        let (address, mut requests) = receiver::serve(vec![]).await;
+        let (handle, task) = remote_write(&address, output_config("{}"));
+        handle
+            .tx
+            .send(scrape("http://host:9100", BODY))
+            .await
+            .unwrap();
+        // the queued points are flushed once the sender is gone
+        drop(handle);
+        task.await.unwrap();
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.line(), "POST /api/v1/write HTTP/1.1");
+        assert_eq!(
+            request.header("content-type"),
+            Some("application/x-protobuf")
+        );
+        assert_eq!(request.header("content-encoding"), Some("snappy"));
+        assert_eq!(
+            request.header("x-prometheus-remote-write-version"),
+            Some("0.1.0")
+        );
+        let write = remote_write::decode(&request.body).unwrap();
+        let series: Vec<(String, f64, i64)> = write
+            .timeseries
+            .iter()
+            .map(|x| {
+                let labels: Vec<String> = x
+                    .labels
+                    .iter()
+                    .map(|l| format!("{}={}", l.name, l.value))
+                    .collect();
+                (labels.join(","), x.samples[0].value, x.samples[0].timestamp)
+            })
+            .collect();
+        assert_eq!(
+            series,
+            [
+                (
+                    "__name__=node_load1,instance=host:9100,job=node,rack=r1".to_string(),
+                    0.5,
+                    1700000000500
+                ),
+                (
+                    "__name__=node_network_receive_bytes_total,device=eth0,instance=host:9100,job=node,rack=r1"
+                        .to_string(),
+                    1024.0,
+                    1700000000250
+                ),
+                (
+                    "__name__=up,instance=host:9100,job=node,rack=r1".to_string(),
+                    1.0,
+                    1700000000500
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (address, mut requests) = receiver::serve(vec![503]).await;
+        // a full batch goes out at once, the retry waits for the next deadline
+        let config = output_config(
+            r#"{"max_samples_per_send": 3, "batch_send_deadline_secs": 1, "min_backoff_ms": 10}"#,
+        );
+        let (mut handle, task) = remote_write(&address, config);
+        handle
+            .tx
+            .send(scrape("http://host:9100", BODY))
+            .await
+            .unwrap();
+        let seen = wait_for(&mut handle.status, |x| x.sent == 3).await;
+        assert!(seen.iter().any(|x| {
+            x.pending == 3
+                && x.last_error
+                    .as_deref()
+                    .unwrap_or("")
+                    .starts_with("http 503")
+        }));
+        let last = seen.last().unwrap();
+        assert_eq!(
+            (last.dropped, last.pending, last.last_error.clone()),
+            (0, 0, None)
+        );
+        let first = requests.recv().await.unwrap();
+        let second = requests.recv().await.unwrap();
+        assert_eq!(first.body, second.body);
+        drop(handle);
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_dropped() {
+        let (address, mut requests) = receiver::serve(vec![400]).await;
+        let config = output_config(r#"{"max_samples_per_send": 3}"#);
+        let (mut handle, task) = remote_write(&address, config);
+        handle
+            .tx
+            .send(scrape("http://host:9100", BODY))
+            .await
+            .unwrap();
+        let seen = wait_for(&mut handle.status, |x| x.dropped == 3).await;
+        let last = seen.last().unwrap();
+        assert_eq!((last.sent, last.pending), (0, 0));
+        assert_eq!(last.last_error.as_deref(), Some("http 400 stub"));
+        drop(handle);
+        task.await.unwrap();
+        // nothing left to flush
+        requests.recv().await.unwrap();
+        assert!(requests.try_recv().is_err());
+    }
+}
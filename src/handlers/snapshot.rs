@@ -0,0 +1,204 @@
+// module snapshot
+// structured state of a node at a point in time, built from the history store
+use crate::handlers::exposition::{self, MetricFamily};
+use crate::handlers::history::HistoryStore;
+use crate::handlers::trends;
+use serde_derive::Serialize;
+
+/// how far back rates look for the two points they need
+const RATE_WINDOW_SECS: f64 = 600.0;
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct NodeSnapshot {
+    pub node: String,
+    pub name: String,
+    pub status: String,
+    /// unix seconds of the newest scrape at or before the requested time
+    pub timestamp: Option<f64>,
+    pub hostname: Option<String>,
+    pub kernel: Option<String>,
+    pub machine: Option<String>,
+    pub cpu_percent: Option<f64>,
+    pub cpu_cores: usize,
+    pub memory_total_bytes: Option<f64>,
+    pub memory_available_bytes: Option<f64>,
+    pub memory_used_percent: Option<f64>,
+    pub load1: Option<f64>,
+    pub load5: Option<f64>,
+    pub load15: Option<f64>,
+    pub temperature_celsius: Option<f64>,
+    pub network_rx_bytes_per_second: Option<f64>,
+    pub network_tx_bytes_per_second: Option<f64>,
+    pub disk_read_bytes_per_second: Option<f64>,
+    pub disk_write_bytes_per_second: Option<f64>,
+    pub filesystems: Vec<FilesystemSnapshot>,
+    pub interfaces: Vec<InterfaceSnapshot>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct FilesystemSnapshot {
+    pub device: String,
+    pub mountpoint: String,
+    pub fstype: String,
+    pub size_bytes: f64,
+    pub avail_bytes: f64,
+    pub used_percent: f64,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct InterfaceSnapshot {
+    pub device: String,
+    pub rx_bytes_total: f64,
+    pub tx_bytes_total: f64,
+}
+
+impl NodeSnapshot {
+    /// the fullest filesystem, by used percentage
+    pub fn fullest_filesystem(&self) -> Option<&FilesystemSnapshot> {
+        self.filesystems
+            .iter()
+            .max_by(|a, b| a.used_percent.total_cmp(&b.used_percent))
+    }
+}
+
+/// state of `node` as of `ts`, gauges are the last value at or before it and rates use the
+/// two scrapes before it
+pub fn snapshot(
+    store: &HistoryStore,
+    node: &str,
+    name: &str,
+    status: &str,
+    ts: f64,
+) -> NodeSnapshot {
+    let families = store.at(node, ts);
+    let from = ts - RATE_WINDOW_SECS;
+    let network = trends::network(store, node, from, ts);
+    let disk = trends::disk_io(store, node, from, ts);
+    let uname = exposition::samples(&families, "node_uname_info").next();
+    let label = |key: &str| uname.and_then(|x| x.label(key)).map(|x| x.to_string());
+    let memory_total = value(&families, "node_memory_MemTotal_bytes");
+    let memory_available = value(&families, "node_memory_MemAvailable_bytes");
+    let mut cores: Vec<&str> = exposition::samples(&families, "node_cpu_seconds_total")
+        .filter_map(|x| x.label("cpu"))
+        .collect();
+    cores.sort();
+    cores.dedup();
+    NodeSnapshot {
+        node: node.to_string(),
+        name: name.to_string(),
+        status: status.to_string(),
+        timestamp: store.newest_at(node, ts),
+        hostname: label("nodename"),
+        kernel: label("release"),
+        machine: label("machine"),
+        cpu_percent: trends::cpu_total(store, node, from, ts).last(),
+        cpu_cores: cores.len(),
+        memory_total_bytes: memory_total,
+        memory_available_bytes: memory_available,
+        memory_used_percent: match (memory_total, memory_available) {
+            (Some(total), Some(available)) if total > 0.0 => {
+                Some((total - available) / total * 100.0)
+            }
+            _ => None,
+        },
+        load1: value(&families, "node_load1"),
+        load5: value(&families, "node_load5"),
+        load15: value(&families, "node_load15"),
+        // hottest sensor, thermal zones on the pis and hwmon elsewhere
+        temperature_celsius: exposition::samples(&families, "node_thermal_zone_temp")
+            .chain(exposition::samples(&families, "node_hwmon_temp_celsius"))
+            .map(|x| x.value)
+            .reduce(f64::max),
+        network_rx_bytes_per_second: network[0].last(),
+        network_tx_bytes_per_second: network[1].last(),
+        disk_read_bytes_per_second: disk[0].last(),
+        disk_write_bytes_per_second: disk[1].last(),
+        filesystems: filesystems(&families),
+        interfaces: interfaces(&families),
+    }
+}
+
+/// divisor and unit for a byte count, binary prefixes
+pub fn byte_unit(value: f64) -> (f64, &'static str) {
+    match value {
+        x if x >= 1024.0 * 1024.0 * 1024.0 => (1024.0 * 1024.0 * 1024.0, "GiB"),
+        x if x >= 1024.0 * 1024.0 => (1024.0 * 1024.0, "MiB"),
+        x if x >= 1024.0 => (1024.0, "KiB"),
+        _ => (1.0, "B"),
+    }
+}
+
+/// "512 B", "1.5 GiB"
+pub fn format_bytes(value: f64) -> String {
+    match byte_unit(value) {
+        (_, "B") => format!("{:.0} B", value),
+        (divisor, unit) => format!("{:.1} {}", value / divisor, unit),
+    }
+}
+
+/// "512 B/s", "1.2 MiB/s", "-" when there is no rate yet
+pub fn format_rate(value: Option<f64>) -> String {
+    value
+        .map(|x| format!("{}/s", format_bytes(x)))
+        .unwrap_or("-".to_string())
+}
+
+// utility functions
+
+fn value(families: &[MetricFamily], name: &str) -> Option<f64> {
+    exposition::samples(families, name).next().map(|x| x.value)
+}
+
+/// used percentage as df reports it, used over used plus available to non root users
+fn filesystems(families: &[MetricFamily]) -> Vec<FilesystemSnapshot> {
+    let mut result: Vec<FilesystemSnapshot> = vec![];
+    for sample in exposition::samples(families, "node_filesystem_size_bytes") {
+        let mountpoint = sample.label("mountpoint").unwrap_or("");
+        let matching = |name: &str| {
+            exposition::samples(families, name)
+                .find(|x| x.labels == sample.labels)
+                .map(|x| x.value)
+        };
+        let (Some(free), Some(avail)) = (
+            matching("node_filesystem_free_bytes"),
+            matching("node_filesystem_avail_bytes").or(matching("node_filesystem_free_bytes")),
+        ) else {
+            continue;
+        };
+        if sample.value <= 0.0 || result.iter().any(|x| x.mountpoint == mountpoint) {
+            continue;
+        }
+        let used = sample.value - free;
+        result.push(FilesystemSnapshot {
+            device: sample.label("device").unwrap_or("").to_string(),
+            mountpoint: mountpoint.to_string(),
+            fstype: sample.label("fstype").unwrap_or("").to_string(),
+            size_bytes: sample.value,
+            avail_bytes: avail,
+            used_percent: match used + avail {
+                x if x > 0.0 => used / x * 100.0,
+                _ => 0.0,
+            },
+        });
+    }
+    result.sort_by(|a, b| a.mountpoint.cmp(&b.mountpoint));
+    result
+}
+
+fn interfaces(families: &[MetricFamily]) -> Vec<InterfaceSnapshot> {
+    let mut result: Vec<InterfaceSnapshot> = vec![];
+    for sample in exposition::samples(families, "node_network_receive_bytes_total") {
+        let device = sample.label("device").unwrap_or("");
+        let tx = exposition::samples(families, "node_network_transmit_bytes_total")
+            .find(|x| x.label("device") == Some(device))
+            .map(|x| x.value)
+            .unwrap_or(0.0);
+        result.push(InterfaceSnapshot {
+            device: device.to_string(),
+            rx_bytes_total: sample.value,
+            tx_bytes_total: tx,
+        });
+    }
+    result.sort_by(|a, b| a.device.cmp(&b.device));
+    result
+}
@@ -0,0 +1,564 @@
+// module storage
+// append-only, compressed segment files under the data dir so the history survives a restart
+//
+// a segment is `<unix seconds of its first block>.seg`, the magic followed by blocks of
+// `u32 length | deflate(records)`, a record is `u32 length | node | f64 time | u32 length | body`
+// where the body is the openmetrics text format with every sample timestamped, so info,
+// stateset and gauge histogram families keep their type
+//
+// a `.seg` file without the magic (cut short by a crash, or not ours) is renamed to `.bad`
+// and left out rather than stopping the load
+//
+// with rollups configured, segments older than the raw retention are thinned to a scrape per
+// node and rollup interval (`<start>_<interval secs>.seg`), enough to rebuild the rollups on
+// start, each rebuilt bucket then holds that one scrape rather than every sample of its interval
+use crate::config::load::HistoryConfig;
+use crate::error::generic::GenericError;
+use crate::handlers::exposition::{self, Format, MetricFamily};
+use crate::handlers::history::HistoryStore;
+use custom_logger as log;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 4] = b"NMS1";
+const EXTENSION: &str = "seg";
+/// a new segment is started after this long, so expired data goes away a file at a time
+const SEGMENT_SECS: f64 = 600.0;
+
+/// a scrape as stored, the node, the time it was taken and its families
+pub struct Record {
+    pub node: String,
+    pub ts: f64,
+    pub families: Vec<MetricFamily>,
+}
+
+pub struct Storage {
+    dir: PathBuf,
+    /// the longest of the raw and rollup retentions
+    retention_secs: f64,
+    /// (age, interval secs), scrapes older than the age are thinned to one per interval
+    thinning: Vec<(f64, u64)>,
+    /// encoded records waiting for the next flush
+    pending: Vec<u8>,
+    /// start time and path of the segment being appended to
+    current: Option<(f64, PathBuf)>,
+}
+
+impl Storage {
+    /// open (and create) the data dir
+    pub fn open(dir: &str, history: &HistoryConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)
+            .map_err(|e| GenericError::new(format!("[open] {} : {}", dir.display(), e)))?;
+        // a rollup takes over where the raw points (or the previous rollup) end
+        let mut rollups = history.rollups.clone();
+        rollups.retain(|x| x.resolution_secs > 0);
+        rollups.sort_by_key(|x| x.resolution_secs);
+        let mut age = history.retention_secs;
+        let mut thinning = vec![];
+        for rollup in rollups.iter() {
+            thinning.push((age as f64, rollup.resolution_secs));
+            age = age.max(rollup.retention_secs);
+        }
+        Ok(Storage {
+            dir,
+            retention_secs: history.longest_retention_secs() as f64,
+            thinning,
+            pending: vec![],
+            current: None,
+        })
+    }
+
+    /// compact the segments and load what is left into the history, returns the records read
+    pub fn load(&mut self, history: &mut HistoryStore, now: f64) -> io::Result<usize> {
+        self.compact(now)?;
+        let cutoff = now - self.retention_secs;
+        let mut count = 0;
+        for (_, _, path) in self.segments()? {
+            let records = match read_segment(&path) {
+                Ok(records) => records,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    set_aside(&path, &e)?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            for record in records {
+                if record.ts < cutoff {
+                    continue;
+                }
+                history.ingest(&record.node, &record.families, record.ts);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// queue a scrape for the next flush, samples without a timestamp are taken at `now`
+    pub fn append(&mut self, node: &str, families: &[MetricFamily], now: f64) {
+        let mut families = families.to_vec();
+        for sample in families.iter_mut().flat_map(|x| x.samples.iter_mut()) {
+            sample.timestamp.get_or_insert(now);
+        }
+        encode_record(
+            &mut self.pending,
+            node,
+            now,
+            &exposition::encode_openmetrics(&families),
+        );
+    }
+
+    /// write the queued scrapes as one compressed block, a new segment is started (and old
+    /// ones compacted) once the current one is older than `SEGMENT_SECS`
+    pub fn flush(&mut self, now: f64) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let rotate = match &self.current {
+            Some((start, _)) => now - start >= SEGMENT_SECS,
+            None => true,
+        };
+        if rotate {
+            let path = self.dir.join(format!("{}.{}", now as u64, EXTENSION));
+            let mut file = File::create(&path)?;
+            file.write_all(MAGIC)?;
+            self.current = Some((now, path));
+            self.compact(now)?;
+        }
+        let Some((_, path)) = &self.current else {
+            return Ok(());
+        };
+        let block = compress(&self.pending)?;
+        let mut file = OpenOptions::new().append(true).open(path)?;
+        file.write_all(&(block.len() as u32).to_be_bytes())?;
+        file.write_all(&block)?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// delete segments that ended before the retention cutoff, rewrite the one that straddles
+    /// it without its expired samples and thin the ones past the raw retention
+    pub fn compact(&self, now: f64) -> io::Result<()> {
+        let cutoff = now - self.retention_secs;
+        let segments = self.segments()?;
+        let current = self.current.as_ref().map(|x| &x.1);
+        for (index, (start, interval, path)) in segments.iter().enumerate() {
+            // a segment ends where the next one starts
+            let Some((end, _, _)) = segments.get(index + 1) else {
+                continue;
+            };
+            if Some(path) == current {
+                continue;
+            }
+            let thin_to = self
+                .thinning
+                .iter()
+                .filter(|(age, _)| now - end > *age)
+                .map(|x| x.1)
+                .fold(*interval, u64::max);
+            if *end < cutoff {
+                log::debug!("[compact] removing {}", path.display());
+                fs::remove_file(path)?;
+            } else if *start < cutoff || thin_to > *interval {
+                log::debug!("[compact] rewriting {}", path.display());
+                match read_segment(path) {
+                    Ok(records) => rewrite(path, records, start.max(cutoff), thin_to)?,
+                    Err(e) if e.kind() == io::ErrorKind::InvalidData => set_aside(path, &e)?,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// segments sorted by start time, with the interval they were thinned to (0 when not)
+    fn segments(&self) -> io::Result<Vec<(f64, u64, PathBuf)>> {
+        let mut segments: Vec<(f64, u64, PathBuf)> = fs::read_dir(&self.dir)?
+            .filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .filter(|x| x.extension().map(|ext| ext == EXTENSION).unwrap_or(false))
+            .filter_map(|x| {
+                let stem = x.file_stem()?.to_str()?;
+                let (start, interval) = match stem.split_once('_') {
+                    Some((start, interval)) => (start, interval.parse::<u64>().ok()?),
+                    None => (stem, 0),
+                };
+                Some((start.parse::<f64>().ok()?, interval, x))
+            })
+            .collect();
+        segments.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(segments)
+    }
+}
+
+/// every record of a segment, a block cut short (the tool was killed mid write) ends the segment
+pub fn read_segment(path: &Path) -> io::Result<Vec<Record>> {
+    let data = fs::read(path)?;
+    if !data.starts_with(MAGIC) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a segment file", path.display()),
+        ));
+    }
+    let mut records = vec![];
+    let mut pos = MAGIC.len();
+    while let Some(len) = read_u32(&data, pos) {
+        let Some(block) = data.get(pos + 4..pos + 4 + len as usize) else {
+            log::debug!(
+                "[read_segment] {} : truncated block at {}",
+                path.display(),
+                pos
+            );
+            break;
+        };
+        pos += 4 + len as usize;
+        let mut raw = vec![];
+        if let Err(e) = DeflateDecoder::new(block).read_to_end(&mut raw) {
+            log::debug!("[read_segment] {} : {}", path.display(), e);
+            break;
+        }
+        decode_records(&raw, &mut records);
+    }
+    Ok(records)
+}
+
+// utility functions
+
+/// keep the samples at or after `cutoff` and, when `interval` is set, only the last scrape of
+/// a node in each interval, the file is renamed to start at the cutoff
+fn rewrite(path: &Path, mut records: Vec<Record>, cutoff: f64, interval: u64) -> io::Result<()> {
+    if interval > 0 {
+        let mut kept: Vec<Record> = vec![];
+        let mut index: HashMap<(String, i64), usize> = HashMap::new();
+        for record in records.into_iter() {
+            let key = (
+                record.node.clone(),
+                (record.ts / interval as f64).floor() as i64,
+            );
+            match index.get(&key) {
+                Some(i) => kept[*i] = record,
+                None => {
+                    index.insert(key, kept.len());
+                    kept.push(record);
+                }
+            }
+        }
+        records = kept;
+    }
+    let mut raw = vec![];
+    for mut record in records.into_iter() {
+        for family in record.families.iter_mut() {
+            family
+                .samples
+                .retain(|x| x.timestamp.map(|ts| ts >= cutoff).unwrap_or(false));
+        }
+        record.families.retain(|x| !x.samples.is_empty());
+        if !record.families.is_empty() {
+            let body = exposition::encode_openmetrics(&record.families);
+            encode_record(&mut raw, &record.node, record.ts, &body);
+        }
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(MAGIC)?;
+    if !raw.is_empty() {
+        let block = compress(&raw)?;
+        file.write_all(&(block.len() as u32).to_be_bytes())?;
+        file.write_all(&block)?;
+    }
+    file.sync_all()?;
+    let name = match interval {
+        0 => format!("{}.{}", cutoff as u64, EXTENSION),
+        x => format!("{}_{}.{}", cutoff as u64, x, EXTENSION),
+    };
+    let target = path.with_file_name(name);
+    fs::rename(&tmp, &target)?;
+    match target == path {
+        true => Ok(()),
+        false => fs::remove_file(path),
+    }
+}
+
+/// rename an unreadable segment to `.bad` so it is kept for a look but no longer read
+fn set_aside(path: &Path, err: &io::Error) -> io::Result<()> {
+    log::warn!("[set_aside] {} : {}, renamed to .bad", path.display(), err);
+    fs::rename(path, path.with_extension("bad"))
+}
+
+fn compress(raw: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+    encoder.write_all(raw)?;
+    encoder.finish()
+}
+
+fn encode_record(out: &mut Vec<u8>, node: &str, ts: f64, body: &str) {
+    out.extend_from_slice(&(node.len() as u32).to_be_bytes());
+    out.extend_from_slice(node.as_bytes());
+    out.extend_from_slice(&ts.to_be_bytes());
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(body.as_bytes());
+}
+
+fn decode_records(raw: &[u8], records: &mut Vec<Record>) {
+    let mut pos = 0;
+    while pos < raw.len() {
+        let Some(record) = decode_record(raw, &mut pos) else {
+            log::debug!("[decode_records] invalid record at {}", pos);
+            return;
+        };
+        records.push(record);
+    }
+}
+
+fn decode_record(raw: &[u8], pos: &mut usize) -> Option<Record> {
+    let len = read_u32(raw, *pos)? as usize;
+    let node = std::str::from_utf8(raw.get(*pos + 4..*pos + 4 + len)?).ok()?;
+    *pos += 4 + len;
+    let ts = f64::from_be_bytes(raw.get(*pos..*pos + 8)?.try_into().ok()?);
+    *pos += 8;
+    let len = read_u32(raw, *pos)? as usize;
+    let body = std::str::from_utf8(raw.get(*pos + 4..*pos + 4 + len)?).ok()?;
+    *pos += 4 + len;
+    let families = exposition::parse(body, Format::OpenMetrics).ok()?;
+    Some(Record {
+        node: node.to_string(),
+        ts,
+        families,
+    })
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    let bytes = data.get(pos..pos + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::load::RollupConfig;
+    use crate::handlers::exposition::MetricType;
+
+    const TYPED: &str = "# TYPE build info
+build_info{version=\"1.2\"} 1
+# TYPE power stateset
+power{power=\"on\"} 1
+power{power=\"off\"} 0
+# TYPE queue gaugehistogram
+queue_gbucket{le=\"1\"} 2
+queue_gbucket{le=\"+Inf\"} 5
+queue_gcount 5
+queue_gsum 3.5
+# TYPE temp gauge
+# UNIT temp celsius
+temp{zone=\"a b,c\"} 41.5
+# EOF
+";
+
+    /// an empty dir of its own per test
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("storage-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn gauge(value: f64) -> Vec<MetricFamily> {
+        exposition::parse(
+            &format!("# TYPE g gauge\ng {}\n", value),
+            Format::Prometheus,
+        )
+        .unwrap()
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut files: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn records_keep_the_family_types() {
+        let families = exposition::parse(TYPED, Format::OpenMetrics).unwrap();
+        let mut raw = vec![];
+        let mut timestamped = families.clone();
+        for sample in timestamped.iter_mut().flat_map(|x| x.samples.iter_mut()) {
+            sample.timestamp = Some(1700000000.5);
+        }
+        let body = exposition::encode_openmetrics(&timestamped);
+        encode_record(&mut raw, "node a", 1700000000.5, &body);
+        encode_record(
+            &mut raw,
+            "b",
+            1700000001.0,
+            &exposition::encode_openmetrics(&[]),
+        );
+        let mut records = vec![];
+        decode_records(&raw, &mut records);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].node, "node a");
+        assert_eq!(records[0].ts, 1700000000.5);
+        assert_eq!(records[0].families, timestamped);
+        let types: Vec<MetricType> = records[0].families.iter().map(|x| x.metric_type).collect();
+        assert_eq!(
+            types,
+            [
+                MetricType::Info,
+                MetricType::StateSet,
+                MetricType::GaugeHistogram,
+                MetricType::Gauge
+            ]
+        );
+        assert_eq!(records[0].families[3].unit, "celsius");
+        assert_eq!(records[1].node, "b");
+        assert!(records[1].families.is_empty());
+    }
+
+    #[test]
+    fn invalid_records_end_the_block() {
+        let mut raw = vec![];
+        encode_record(
+            &mut raw,
+            "a",
+            1.0,
+            &exposition::encode_openmetrics(&gauge(1.0)),
+        );
+        let good = raw.len();
+        encode_record(
+            &mut raw,
+            "b",
+            2.0,
+            &exposition::encode_openmetrics(&gauge(2.0)),
+        );
+        raw.truncate(good + 10);
+        let mut records = vec![];
+        decode_records(&raw, &mut records);
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn segments_survive_a_restart() {
+        let dir = data_dir("restart");
+        let history = HistoryConfig::default();
+        let mut storage = Storage::open(dir.to_str().unwrap(), &history).unwrap();
+        let typed = exposition::parse(TYPED, Format::OpenMetrics).unwrap();
+        storage.append("a", &typed, 100.0);
+        storage.flush(100.0).unwrap();
+        storage.append("a", &gauge(2.0), 110.0);
+        storage.append("b", &gauge(3.0), 110.0);
+        storage.flush(110.0).unwrap();
+        // a block cut short by a crash loses only itself
+        let segment = dir.join("100.seg");
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&[0, 0, 1, 0, 1, 2]).unwrap();
+        let records = read_segment(&segment).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].families[0].metric_type, MetricType::Info);
+        assert_eq!(records[0].families[0].samples[0].timestamp, Some(100.0));
+
+        let mut store = HistoryStore::new(history.clone());
+        let mut reopened = Storage::open(dir.to_str().unwrap(), &history).unwrap();
+        assert_eq!(reopened.load(&mut store, 120.0).unwrap(), 3);
+        assert_eq!(store.steps("a"), [100.0, 110.0]);
+        assert_eq!(store.series("b", "g").len(), 1);
+        // the series keep the type of their family
+        let at = store.at("a", 100.0);
+        let gbucket = at.iter().find(|x| x.name == "queue_gbucket").unwrap();
+        assert_eq!(gbucket.metric_type, MetricType::GaugeHistogram);
+        assert_eq!(gbucket.samples.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expired_segments_are_removed_or_cut() {
+        let dir = data_dir("expire");
+        let history = HistoryConfig {
+            retention_secs: 100,
+            ..Default::default()
+        };
+        let mut storage = Storage::open(dir.to_str().unwrap(), &history).unwrap();
+        for ts in (0..=1250).step_by(10) {
+            storage.append("a", &gauge(ts as f64), ts as f64);
+            storage.flush(ts as f64).unwrap();
+        }
+        let mut store = HistoryStore::new(history);
+        assert_eq!(storage.load(&mut store, 1250.0).unwrap(), 11);
+        assert_eq!(files(&dir), ["1150.seg", "1200.seg"]);
+        assert_eq!(store.steps("a").first(), Some(&1150.0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn old_segments_are_thinned_to_the_rollup_interval() {
+        let dir = data_dir("thin");
+        let history = HistoryConfig {
+            retention_secs: 1000,
+            rollups: vec![RollupConfig {
+                resolution_secs: 60,
+                retention_secs: 100_000,
+            }],
+            ..Default::default()
+        };
+        let mut storage = Storage::open(dir.to_str().unwrap(), &history).unwrap();
+        for ts in (0..=1200).step_by(10) {
+            storage.append("a", &gauge(ts as f64), ts as f64);
+            storage.flush(ts as f64).unwrap();
+        }
+        let mut store = HistoryStore::new(history);
+        // 10 of the first 60 scrapes are left, the next 61 are within the raw retention
+        assert_eq!(storage.load(&mut store, 2000.0).unwrap(), 71);
+        assert_eq!(files(&dir), ["0_60.seg", "1200.seg", "600.seg"]);
+        let kept: Vec<f64> = read_segment(&dir.join("0_60.seg"))
+            .unwrap()
+            .iter()
+            .map(|x| x.ts)
+            .collect();
+        assert_eq!(
+            kept,
+            [
+                50.0, 110.0, 170.0, 230.0, 290.0, 350.0, 410.0, 470.0, 530.0, 590.0
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn other_files_are_not_segments() {
+        let dir = data_dir("foreign");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("notes.seg"), b"hello").unwrap();
+        fs::write(dir.join("5.seg"), b"hello").unwrap();
+        let storage = Storage::open(dir.to_str().unwrap(), &HistoryConfig::default()).unwrap();
+        let segments = storage.segments().unwrap();
+        assert_eq!(segments.len(), 1);
+        assert!(read_segment(&segments[0].2).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_segments_are_set_aside() {
+        let dir = data_dir("bad");
+        let history = HistoryConfig {
+            retention_secs: 100,
+            ..Default::default()
+        };
+        let mut storage = Storage::open(dir.to_str().unwrap(), &history).unwrap();
+        storage.append("a", &gauge(1.0), 100.0);
+        storage.flush(100.0).unwrap();
+        // a crash between creating a segment and writing to it, and a straddling foreign file
+        fs::write(dir.join("120.seg"), b"").unwrap();
+        fs::write(dir.join("50.seg"), b"hello").unwrap();
+        let mut store = HistoryStore::new(history.clone());
+        let mut reopened = Storage::open(dir.to_str().unwrap(), &history).unwrap();
+        assert_eq!(reopened.load(&mut store, 130.0).unwrap(), 1);
+        assert_eq!(files(&dir), ["100.seg", "120.bad", "50.bad"]);
+        assert_eq!(store.steps("a"), [100.0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
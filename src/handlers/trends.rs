@@ -0,0 +1,302 @@
+// module trends
+// derived time series for the charts (rates and ratios over the history store), read at the
+// resolution the store picks for the range
+use crate::handlers::history::{Aggregate, HistoryStore, Resolution, SeriesKey};
+use std::collections::BTreeMap;
+
+/// cpu modes that count as not busy
+const IDLE_MODES: &[&str] = &["idle", "iowait"];
+
+/// a named line, points are (unix seconds, value)
+#[derive(Debug, Clone, Default)]
+pub struct Trend {
+    pub name: String,
+    pub points: Vec<(f64, f64)>,
+}
+
+impl Trend {
+    fn new(name: &str, points: Vec<(f64, f64)>) -> Self {
+        Trend {
+            name: name.to_string(),
+            points,
+        }
+    }
+
+    pub fn last(&self) -> Option<f64> {
+        self.points.last().map(|x| x.1)
+    }
+}
+
+/// busy percentage per core, from `node_cpu_seconds_total`
+pub fn cpu_per_core(store: &HistoryStore, node: &str, from: f64, to: f64) -> Vec<Trend> {
+    let mut cpus: Vec<String> = store
+        .series(node, "node_cpu_seconds_total")
+        .iter()
+        .filter_map(|(key, _)| key.labels.get("cpu").cloned())
+        .collect();
+    // "10" sorts after "9"
+    cpus.sort_by_key(|x| (x.len(), x.clone()));
+    cpus.dedup();
+    cpus.iter()
+        .map(|cpu| {
+            let on_cpu = |key: &SeriesKey| key.labels.get("cpu") == Some(cpu);
+            let total = sum_by_ts(store, node, "node_cpu_seconds_total", from, to, on_cpu);
+            let busy = sum_by_ts(store, node, "node_cpu_seconds_total", from, to, |key| {
+                on_cpu(key)
+                    && !IDLE_MODES
+                        .contains(&key.labels.get("mode").map(|x| x.as_str()).unwrap_or(""))
+            });
+            Trend::new(&format!("cpu{}", cpu), ratio_of_deltas(&busy, &total))
+        })
+        .collect()
+}
+
+/// busy percentage averaged over every core
+pub fn cpu_total(store: &HistoryStore, node: &str, from: f64, to: f64) -> Trend {
+    let total = sum_by_ts(store, node, "node_cpu_seconds_total", from, to, |_| true);
+    let busy = sum_by_ts(store, node, "node_cpu_seconds_total", from, to, |key| {
+        !IDLE_MODES.contains(&key.labels.get("mode").map(|x| x.as_str()).unwrap_or(""))
+    });
+    Trend::new("cpu", ratio_of_deltas(&busy, &total))
+}
+
+/// used memory (total less available) in bytes, and the total as a second line, read from a
+/// rollup the lowest and highest use within each interval are added
+pub fn memory(store: &HistoryStore, node: &str, from: f64, to: f64) -> Vec<Trend> {
+    let total = sum_by_ts(store, node, "node_memory_MemTotal_bytes", from, to, |_| {
+        true
+    });
+    let used = |aggregate: Aggregate| -> Vec<(f64, f64)> {
+        let available = aggregate_by_ts(
+            store,
+            node,
+            "node_memory_MemAvailable_bytes",
+            aggregate,
+            from,
+            to,
+            |_| true,
+        );
+        available
+            .iter()
+            .filter_map(|(ts, value)| total.get(ts).map(|x| (*ts as f64 / 1000.0, x - value)))
+            .collect()
+    };
+    let mut trends = vec![Trend::new("used", used(Aggregate::Avg))];
+    if store.resolution(node, from, to) != Resolution::Raw {
+        // least available is most used
+        trends.push(Trend::new("used max", used(Aggregate::Min)));
+        trends.push(Trend::new("used min", used(Aggregate::Max)));
+    }
+    let total = total
+        .iter()
+        .map(|(ts, x)| (*ts as f64 / 1000.0, *x))
+        .collect();
+    trends.push(Trend::new("total", total));
+    trends
+}
+
+/// receive and transmit bytes per second over every device except loopback
+pub fn network(store: &HistoryStore, node: &str, from: f64, to: f64) -> Vec<Trend> {
+    let not_lo = |key: &SeriesKey| key.labels.get("device").map(|x| x.as_str()) != Some("lo");
+    let rx = sum_by_ts(
+        store,
+        node,
+        "node_network_receive_bytes_total",
+        from,
+        to,
+        not_lo,
+    );
+    let tx = sum_by_ts(
+        store,
+        node,
+        "node_network_transmit_bytes_total",
+        from,
+        to,
+        not_lo,
+    );
+    vec![Trend::new("rx", rate(&rx)), Trend::new("tx", rate(&tx))]
+}
+
+/// read and written bytes per second over every disk
+pub fn disk_io(store: &HistoryStore, node: &str, from: f64, to: f64) -> Vec<Trend> {
+    let read = sum_by_ts(store, node, "node_disk_read_bytes_total", from, to, |_| {
+        true
+    });
+    let written = sum_by_ts(
+        store,
+        node,
+        "node_disk_written_bytes_total",
+        from,
+        to,
+        |_| true,
+    );
+    vec![
+        Trend::new("read", rate(&read)),
+        Trend::new("write", rate(&written)),
+    ]
+}
+
+// utility functions
+
+/// sum of the matching series per timestamp (in ms), points of one scrape share a timestamp
+fn sum_by_ts(
+    store: &HistoryStore,
+    node: &str,
+    name: &str,
+    from: f64,
+    to: f64,
+    filter: impl Fn(&SeriesKey) -> bool,
+) -> BTreeMap<i64, f64> {
+    aggregate_by_ts(store, node, name, Aggregate::Avg, from, to, filter)
+}
+
+/// as `sum_by_ts`, gauges read from a rollup take `aggregate` of each interval
+fn aggregate_by_ts(
+    store: &HistoryStore,
+    node: &str,
+    name: &str,
+    aggregate: Aggregate,
+    from: f64,
+    to: f64,
+    filter: impl Fn(&SeriesKey) -> bool,
+) -> BTreeMap<i64, f64> {
+    let resolution = store.resolution(node, from, to);
+    let mut sums: BTreeMap<i64, f64> = BTreeMap::new();
+    for (key, points) in store.points(node, name, resolution, aggregate, from, to) {
+        if !filter(key) {
+            continue;
+        }
+        for point in points.iter() {
+            *sums.entry((point.ts * 1000.0) as i64).or_default() += point.value;
+        }
+    }
+    sums
+}
+
+/// per second increase between consecutive points, counter resets are skipped
+fn rate(values: &BTreeMap<i64, f64>) -> Vec<(f64, f64)> {
+    values
+        .iter()
+        .zip(values.iter().skip(1))
+        .filter_map(|((t0, v0), (t1, v1))| {
+            let dt = (t1 - t0) as f64 / 1000.0;
+            match v1 - v0 {
+                x if x >= 0.0 && dt > 0.0 => Some((*t1 as f64 / 1000.0, x / dt)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// percentage of the increase of `part` over the increase of `whole` between consecutive points
+fn ratio_of_deltas(part: &BTreeMap<i64, f64>, whole: &BTreeMap<i64, f64>) -> Vec<(f64, f64)> {
+    let points: Vec<(i64, f64, f64)> = whole
+        .iter()
+        .filter_map(|(ts, w)| part.get(ts).map(|p| (*ts, *p, *w)))
+        .collect();
+    points
+        .iter()
+        .zip(points.iter().skip(1))
+        .filter_map(|((_, p0, w0), (t1, p1, w1))| match w1 - w0 {
+            dw if dw > 0.0 && p1 >= p0 => Some((*t1 as f64 / 1000.0, (p1 - p0) / dw * 100.0)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::load::HistoryConfig;
+    use crate::handlers::exposition::{self, Format};
+
+    fn by_ts(points: &[(i64, f64)]) -> BTreeMap<i64, f64> {
+        points.iter().cloned().collect()
+    }
+
+    #[test]
+    fn rates_skip_counter_resets() {
+        let values = by_ts(&[
+            (0, 100.0),
+            (2000, 300.0),
+            (4000, 50.0),
+            (5000, 80.0),
+            (6000, 80.0),
+        ]);
+        // the drop to 50 is a reset, the next interval counts from there
+        assert_eq!(rate(&values), [(2.0, 100.0), (5.0, 30.0), (6.0, 0.0)]);
+        assert!(rate(&by_ts(&[(0, 1.0)])).is_empty());
+        assert!(rate(&BTreeMap::new()).is_empty());
+    }
+
+    #[test]
+    fn ratios_are_percentages_of_the_increase() {
+        let whole = by_ts(&[
+            (0, 0.0),
+            (1000, 100.0),
+            (2000, 200.0),
+            (3000, 200.0),
+            (4000, 400.0),
+        ]);
+        let part = by_ts(&[
+            (0, 0.0),
+            (1000, 25.0),
+            (2000, 100.0),
+            (3000, 100.0),
+            (4000, 10.0),
+        ]);
+        // no increase of the whole (3s) and a reset of the part (4s) give no point
+        assert_eq!(ratio_of_deltas(&part, &whole), [(1.0, 25.0), (2.0, 75.0)]);
+        // timestamps missing from either side are left out
+        let sparse = by_ts(&[(0, 0.0), (2000, 50.0)]);
+        assert_eq!(ratio_of_deltas(&sparse, &whole), [(2.0, 25.0)]);
+    }
+
+    #[test]
+    fn trends_of_a_node() {
+        let mut store = HistoryStore::new(HistoryConfig::default());
+        let scrape = |cpu0: [f64; 3], cpu1: [f64; 3], rx: [f64; 2]| {
+            let body = format!(
+                concat!(
+                    "# TYPE node_cpu_seconds_total counter\n",
+                    "node_cpu_seconds_total{{cpu=\"0\",mode=\"user\"}} {}\n",
+                    "node_cpu_seconds_total{{cpu=\"0\",mode=\"iowait\"}} {}\n",
+                    "node_cpu_seconds_total{{cpu=\"0\",mode=\"idle\"}} {}\n",
+                    "node_cpu_seconds_total{{cpu=\"1\",mode=\"user\"}} {}\n",
+                    "node_cpu_seconds_total{{cpu=\"1\",mode=\"iowait\"}} {}\n",
+                    "node_cpu_seconds_total{{cpu=\"1\",mode=\"idle\"}} {}\n",
+                    "# TYPE node_network_receive_bytes_total counter\n",
+                    "node_network_receive_bytes_total{{device=\"eth0\"}} {}\n",
+                    "node_network_receive_bytes_total{{device=\"lo\"}} {}\n",
+                ),
+                cpu0[0], cpu0[1], cpu0[2], cpu1[0], cpu1[1], cpu1[2], rx[0], rx[1]
+            );
+            exposition::parse(&body, Format::Prometheus).unwrap()
+        };
+        store.ingest(
+            "n",
+            &scrape([0.0, 0.0, 0.0], [0.0, 0.0, 0.0], [0.0, 0.0]),
+            100.0,
+        );
+        store.ingest(
+            "n",
+            &scrape([5.0, 1.0, 4.0], [1.0, 0.0, 9.0], [1000.0, 500.0]),
+            110.0,
+        );
+
+        let cores = cpu_per_core(&store, "n", 0.0, 200.0);
+        assert_eq!(
+            cores.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(),
+            ["cpu0", "cpu1"]
+        );
+        // iowait counts as idle
+        assert_eq!(cores[0].points, [(110.0, 50.0)]);
+        assert_eq!(cores[1].points, [(110.0, 10.0)]);
+        assert_eq!(cpu_total(&store, "n", 0.0, 200.0).last(), Some(30.0));
+
+        // loopback is left out
+        let network = network(&store, "n", 0.0, 200.0);
+        assert_eq!(network[0].points, [(110.0, 100.0)]);
+        assert!(network[1].points.is_empty());
+    }
+}
@@ -1,5 +1,9 @@
-use crate::cli::schema::Cli;
-use crate::config::load::{ConfigInterface, ImplConfigInterface};
+use crate::cli::schema::{Cli, Command};
+use crate::config::load::{ConfigInterface, ImplConfigInterface, Parameters};
+use crate::handlers::capture::{self, Replay};
+use crate::handlers::scraper::Schedule;
+use crate::handlers::storage::Storage;
+use crate::handlers::{agent, export, offline};
 use crate::ui::render::App;
 use crate::ui::render::run_app;
 use clap::Parser;
@@ -12,6 +16,7 @@ use custom_logger as log;
 use ratatui::Terminal;
 use ratatui::prelude::{Backend, CrosstermBackend};
 use std::io::stdout;
+use std::time::Duration;
 
 mod cli;
 mod config;
@@ -22,7 +27,6 @@ mod ui;
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Cli::parse();
-    let config = args.config;
     let impl_config = ImplConfigInterface {};
 
     // setup logging
@@ -31,8 +35,36 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .init()
         .expect("log should initialize");
 
-    // read and parse config
-    let params = impl_config.read(config);
+    // agent mode serves the local metrics, no config and no tui
+    if let Some(Command::Agent {
+        listen,
+        metrics_path,
+    }) = args.command
+    {
+        log::info!("application : {} agent", env!("CARGO_PKG_NAME"));
+        log::info!("version     : {}", env!("CARGO_PKG_VERSION"));
+        if let Err(err) = agent::serve(&listen, &metrics_path).await {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // read and parse config (saved files alone need none, a replay lists the captured nodes)
+    let mut replay = None;
+    let params = match (&args.command, args.config) {
+        (Some(Command::Replay { file, speed, step }), _) => capture::read(file).map(|entries| {
+            let params = Parameters::offline(capture::targets(&entries));
+            replay = Some(Replay {
+                entries,
+                speed: *speed,
+                step: *step,
+            });
+            params
+        }),
+        (_, Some(config)) => impl_config.read(config),
+        (_, None) => Ok(Parameters::offline(vec![])),
+    };
     if params.is_err() {
         log::error!("{}", params.err().unwrap());
         std::process::exit(1);
@@ -55,11 +87,112 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     log::info!("log-level   : {}", level.to_string().to_lowercase());
     println!();
 
+    // build the scrape targets (http clients) before taking over the terminal
+    let mut params = params.unwrap();
+    if args.from_file.iter().any(|x| x == "-")
+        && let Err(err) = offline::capture_stdin()
+    {
+        log::error!("stdin : {}", err);
+        std::process::exit(1);
+    }
+    params
+        .servers
+        .extend(args.from_file.iter().map(|x| offline::target(x)));
+
+    // export scrapes, writes the snapshot and exits without the tui
+    if let Some(Command::Export {
+        node,
+        format,
+        output,
+        interval,
+    }) = &args.command
+    {
+        if let Some(format) = format {
+            params.export.format = *format;
+        }
+        if let Some(dir) = output {
+            params.export.dir = dir.clone();
+        }
+        let label = node.clone().unwrap_or("all".to_string());
+        let interval = Duration::from_secs(*interval);
+        let result = match export::collect(&params, node.as_deref(), interval).await {
+            Ok(snapshots) => export::write(&snapshots, &params.export, &label),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(path) => log::info!("exported    : {}", path.display()),
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    let schedule = Schedule::from_params(&params);
+    if schedule.is_err() {
+        log::error!("{}", schedule.err().unwrap());
+        std::process::exit(1);
+    }
+    let mut schedule = schedule.unwrap();
+    if args.data_dir.is_some() {
+        params.data_dir = args.data_dir;
+    }
+    if args.record.is_some() {
+        params.record = args.record;
+    }
+    let title = match &args.command {
+        Some(Command::Replay { file, step, .. }) => {
+            // a replay neither records, persists nor forwards what it plays back
+            params.data_dir = None;
+            params.record = None;
+            params.remote_write = None;
+            params.influxdb = None;
+            params.otlp = None;
+            match step {
+                true => format!("replay {} [n for the next scrape]", file),
+                false => format!("replay {}", file),
+            }
+        }
+        _ => "node metrics".to_string(),
+    };
+    schedule.replay = replay;
+    if let Some(path) = &params.record {
+        if let Err(err) = capture::start(path).await {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+        log::info!("recording   : {}", path);
+    }
+    let data_dir = params.data_dir.clone();
+    let history = params.history.clone();
+    let mut app = App::new(title, params);
+
+    // reload the persisted history before taking over the terminal
+    if let Some(dir) = data_dir {
+        let mut storage = match Storage::open(&dir, &history) {
+            Ok(storage) => storage,
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        };
+        let now = chrono::Local::now().timestamp_millis() as f64 / 1000.0;
+        match storage.load(&mut app.history, now) {
+            Ok(count) => log::info!("history     : {} scrapes loaded from {}", count, dir),
+            Err(err) => {
+                log::error!("{} : {}", dir, err);
+                std::process::exit(1);
+            }
+        }
+        app.storage = Some(storage);
+    }
+
     // start tui
     init_error_hooks()?;
     let mut terminal = init_terminal()?;
-    let mut app = App::new("node metrics".to_owned(), params.unwrap());
-    let res = run_app(&mut terminal, &mut app).await;
+    let res = run_app(&mut terminal, &mut app, schedule).await;
+    capture::flush().await;
     restore_terminal()?;
     if let Err(err) = res {
         log::error!("{err:?}");
@@ -0,0 +1,286 @@
+// module charts
+// trend charts for the selected node, drawn from the history store
+use crate::handlers::history::{HistoryStore, Resolution};
+use crate::handlers::snapshot::{byte_unit, format_bytes, format_rate};
+use crate::handlers::trends::{self, Trend};
+use chrono::Local;
+use ratatui::symbols::Marker;
+use ratatui::{prelude::*, widgets::*};
+
+/// line colors, cycled when there are more lines (cores) than colors
+const COLORS: &[Color] = &[
+    Color::LightBlue,
+    Color::LightGreen,
+    Color::LightYellow,
+    Color::LightMagenta,
+    Color::LightCyan,
+    Color::LightRed,
+    Color::Blue,
+    Color::Green,
+];
+
+/// shortest window drawn, so a fresh node doesn't stretch two points across the panel
+const MIN_WINDOW_SECS: f64 = 60.0;
+
+/// a panel, the sparkline tracks `summary` and the chart draws `lines`
+struct Panel {
+    title: &'static str,
+    summary: Trend,
+    lines: Vec<Trend>,
+    /// y axis unit, byte values are scaled to KiB, MiB, ...
+    unit: Unit,
+}
+
+#[derive(Clone, Copy)]
+enum Unit {
+    Percent,
+    Bytes,
+    BytesPerSec,
+}
+
+/// cpu, memory, network and disk charts for `node`, over `window` seconds ending at `to` (all
+/// of the history when unset), read at the resolution the store picks for that range
+pub fn render_charts(
+    frame: &mut Frame,
+    areas: &[Rect],
+    store: &HistoryStore,
+    node: &str,
+    to: f64,
+    window: Option<f64>,
+) {
+    let from = match (window, store.span(node)) {
+        (Some(window), _) => to - window.max(MIN_WINDOW_SECS),
+        (None, Some((first, _))) => first.min(to - MIN_WINDOW_SECS),
+        (None, None) => to - MIN_WINDOW_SECS,
+    };
+    let resolution = match store.resolution(node, from, to) {
+        Resolution::Raw => "raw".to_string(),
+        // counters read the last value of each interval and gauges the average, so only the
+        // interval is named
+        Resolution::Rollup { secs, .. } => format_interval(secs),
+    };
+    let memory = trends::memory(store, node, from, to);
+    let network = trends::network(store, node, from, to);
+    let disk = trends::disk_io(store, node, from, to);
+    let panels = [
+        Panel {
+            title: "cpu",
+            summary: trends::cpu_total(store, node, from, to),
+            lines: trends::cpu_per_core(store, node, from, to),
+            unit: Unit::Percent,
+        },
+        Panel {
+            title: "memory",
+            summary: memory[0].clone(),
+            lines: memory,
+            unit: Unit::Bytes,
+        },
+        Panel {
+            title: "network",
+            summary: sum(&network),
+            lines: network,
+            unit: Unit::BytesPerSec,
+        },
+        Panel {
+            title: "disk io",
+            summary: sum(&disk),
+            lines: disk,
+            unit: Unit::BytesPerSec,
+        },
+    ];
+    for (panel, area) in panels.iter().zip(areas.iter()) {
+        let [top, bottom] =
+            Layout::vertical([Constraint::Length(4), Constraint::Min(6)]).areas(*area);
+        frame.render_widget(sparkline(panel, top), top);
+        render_chart(frame, panel, bottom, from, to, &resolution);
+    }
+}
+
+fn sparkline<'a>(panel: &Panel, area: Rect) -> Sparkline<'a> {
+    let width = area.width.saturating_sub(2) as usize;
+    let points = &panel.summary.points;
+    let recent = &points[points.len().saturating_sub(width)..];
+    // sparklines take integers, keep two decimals of a percentage
+    let data: Vec<u64> = recent
+        .iter()
+        .map(|(_, x)| match panel.unit {
+            Unit::Percent => (x * 100.0) as u64,
+            _ => *x as u64,
+        })
+        .collect();
+    let current = match panel.summary.last() {
+        Some(x) => format_value(x, panel.unit),
+        None => "-".to_string(),
+    };
+    let mut sparkline = Sparkline::default()
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .style(Style::default().fg(Color::White))
+                .title(format!(
+                    "{} [{} {}]",
+                    panel.title, panel.summary.name, current
+                ))
+                .border_type(BorderType::Plain),
+        )
+        .data(&data)
+        .style(Style::default().fg(Color::LightBlue));
+    if let Unit::Percent = panel.unit {
+        sparkline = sparkline.max(100 * 100);
+    }
+    sparkline
+}
+
+fn render_chart(
+    frame: &mut Frame,
+    panel: &Panel,
+    area: Rect,
+    from: f64,
+    to: f64,
+    resolution: &str,
+) {
+    let max = panel
+        .lines
+        .iter()
+        .flat_map(|x| x.points.iter().map(|p| p.1))
+        .fold(0.0, f64::max);
+    let (divisor, unit) = scale(max, panel.unit);
+    let top = match panel.unit {
+        Unit::Percent => 100.0,
+        _ if max > 0.0 => max * 1.1 / divisor,
+        _ => 1.0,
+    };
+    // x is seconds relative to the end of the window, so the labels read as "ago"
+    let data: Vec<Vec<(f64, f64)>> = panel
+        .lines
+        .iter()
+        .map(|line| {
+            line.points
+                .iter()
+                .map(|(ts, x)| (ts - to, x / divisor))
+                .collect()
+        })
+        .collect();
+    let datasets: Vec<Dataset> = panel
+        .lines
+        .iter()
+        .zip(data.iter())
+        .enumerate()
+        .map(|(i, (line, points))| {
+            Dataset::default()
+                .name(line.name.clone())
+                .marker(Marker::Braille)
+                .graph_type(GraphType::Line)
+                .style(Style::default().fg(COLORS[i % COLORS.len()]))
+                .data(points)
+        })
+        .collect();
+    let window = to - from;
+    // labelled against the clock, a window in the past ends at "-5m" instead of "now"
+    let lag = (Local::now().timestamp_millis() as f64 / 1000.0 - to).max(0.0);
+    let chart = Chart::new(datasets)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .style(Style::default().fg(Color::White))
+                .title(format!("{} trend [{}]", panel.title, resolution))
+                .border_type(BorderType::Plain),
+        )
+        .x_axis(
+            Axis::default()
+                .title("time")
+                .style(Style::default().fg(Color::DarkGray))
+                .bounds([-window, 0.0])
+                .labels(vec![
+                    format_ago(window + lag),
+                    format_ago(window / 2.0 + lag),
+                    format_ago(lag),
+                ]),
+        )
+        .y_axis(
+            Axis::default()
+                .title(unit)
+                .style(Style::default().fg(Color::DarkGray))
+                .bounds([0.0, top])
+                .labels(vec![
+                    "0".to_string(),
+                    format!("{:.1}", top / 2.0),
+                    format!("{:.1}", top),
+                ]),
+        )
+        .legend_position(Some(LegendPosition::TopLeft))
+        .hidden_legend_constraints((Constraint::Ratio(1, 2), Constraint::Ratio(1, 2)));
+    frame.render_widget(chart, area);
+}
+
+// utility functions
+
+/// point wise sum of lines sampled at the same timestamps (rx + tx, read + write)
+fn sum(lines: &[Trend]) -> Trend {
+    let mut points = lines.first().map(|x| x.points.clone()).unwrap_or_default();
+    for line in lines.iter().skip(1) {
+        for (point, other) in points.iter_mut().zip(line.points.iter()) {
+            point.1 += other.1;
+        }
+    }
+    Trend {
+        name: "total".to_string(),
+        points,
+    }
+}
+
+/// divisor and axis label for the largest value of a panel
+fn scale(max: f64, unit: Unit) -> (f64, String) {
+    let suffix = match unit {
+        Unit::Percent => return (1.0, "%".to_string()),
+        Unit::Bytes => "",
+        Unit::BytesPerSec => "/s",
+    };
+    let (divisor, prefix) = byte_unit(max);
+    (divisor, format!("{}{}", prefix, suffix))
+}
+
+fn format_value(value: f64, unit: Unit) -> String {
+    match unit {
+        Unit::Percent => format!("{:.1}%", value),
+        Unit::Bytes => format_bytes(value),
+        Unit::BytesPerSec => format_rate(Some(value)),
+    }
+}
+
+/// "15s", "1m", "6h", "7d"
+pub fn format_interval(secs: u64) -> String {
+    match secs {
+        x if x >= 86400 && x % 86400 == 0 => format!("{}d", x / 86400),
+        x if x >= 3600 && x % 3600 == 0 => format!("{}h", x / 3600),
+        x if x >= 60 && x % 60 == 0 => format!("{}m", x / 60),
+        x => format!("{}s", x),
+    }
+}
+
+/// "-5m", "-90s", "-1h30m", "-7d"
+pub fn format_ago(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    match secs {
+        0 => "now".to_string(),
+        x if x < 120 => format!("-{}s", x),
+        x if x < 3600 => format!("-{}m", x / 60),
+        x if x >= 2 * 86400 && x % 86400 < 3600 => format!("-{}d", x / 86400),
+        x if x % 3600 < 60 => format!("-{}h", x / 3600),
+        x => format!("-{}h{}m", x / 3600, x % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ages() {
+        assert_eq!(format_ago(0.2), "now");
+        assert_eq!(format_ago(90.0), "-90s");
+        assert_eq!(format_ago(600.0), "-10m");
+        assert_eq!(format_ago(7200.0), "-2h");
+        assert_eq!(format_ago(5430.0), "-1h30m");
+    }
+}
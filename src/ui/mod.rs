@@ -1 +1,3 @@
+pub mod charts;
+pub mod overview;
 pub mod render;
@@ -0,0 +1,271 @@
+// module overview
+// every listed node on one screen, a row each with its status and the headline numbers
+use crate::handlers::fleet::NodeStatus;
+use crate::handlers::snapshot::{NodeSnapshot, format_rate};
+use ratatui::{prelude::*, widgets::*};
+
+/// cells of the cpu, memory and filesystem gauges
+const GAUGE_WIDTH: usize = 10;
+/// used percentage from which a gauge turns yellow, then red
+const PERCENT_WARN: f64 = 70.0;
+const PERCENT_CRIT: f64 = 90.0;
+/// degrees celsius from which the temperature turns yellow, then red
+const TEMP_WARN: f64 = 70.0;
+const TEMP_CRIT: f64 = 85.0;
+
+/// a row of the overview
+pub struct OverviewRow {
+    pub snapshot: NodeSnapshot,
+    pub status: NodeStatus,
+    pub status_color: Color,
+}
+
+/// one row per node, the selected one highlighted, `title` carries the filter and grouping
+pub fn render_overview(
+    frame: &mut Frame,
+    area: Rect,
+    rows: &[&OverviewRow],
+    selected: Option<usize>,
+    title: &str,
+) {
+    let header = Row::new(vec![
+        "node",
+        "status",
+        "cpu",
+        "memory",
+        "fullest fs",
+        "load",
+        "temp",
+        "network rx / tx",
+    ])
+    .style(Style::default().fg(Color::DarkGray));
+    let table_rows: Vec<Row> = rows.iter().map(|x| row(x)).collect();
+    let widths = [
+        Constraint::Fill(2),
+        Constraint::Length(14),
+        Constraint::Length(GAUGE_WIDTH as u16 + 8),
+        Constraint::Length(GAUGE_WIDTH as u16 + 8),
+        Constraint::Fill(1),
+        Constraint::Length(12),
+        Constraint::Length(7),
+        Constraint::Length(24),
+    ];
+    let table = Table::new(table_rows, widths)
+        .header(header)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .style(Style::default().fg(Color::White))
+                .title(format!("{} [enter for details, o to close]", title))
+                .border_type(BorderType::Plain),
+        )
+        .row_highlight_style(
+            Style::default()
+                .bg(Color::LightBlue)
+                .fg(Color::Black)
+                .add_modifier(Modifier::BOLD),
+        );
+    let mut state = TableState::default().with_selected(selected);
+    frame.render_stateful_widget(table, area, &mut state);
+}
+
+// utility functions
+
+fn row(row: &OverviewRow) -> Row<'static> {
+    let snap = &row.snapshot;
+    // the last values of a node that stopped answering are kept, dimmed
+    let dim = !matches!(row.status, NodeStatus::Up);
+    let color = |value: f64, warn: f64, crit: f64| match dim {
+        true => Color::DarkGray,
+        false => threshold(value, warn, crit),
+    };
+    let glyph = match row.status {
+        NodeStatus::Unknown => "○ ",
+        _ => "● ",
+    };
+    let name = Line::from(vec![
+        Span::styled(glyph, Style::default().fg(row.status_color)),
+        Span::raw(snap.name.clone()),
+    ]);
+    let status = Span::styled(
+        row.status.to_string(),
+        Style::default().fg(row.status_color),
+    );
+    let fs = match snap.fullest_filesystem() {
+        Some(fs) => {
+            let mut line = gauge(Some(fs.used_percent), dim);
+            line.push_span(Span::styled(
+                format!(" {}", fs.mountpoint),
+                Style::default().fg(Color::DarkGray),
+            ));
+            line
+        }
+        None => gauge(None, dim),
+    };
+    // load per core, one runnable task per core is a full machine
+    let load = match (snap.load1, snap.cpu_cores) {
+        (Some(load), 0) => Span::raw(format!("{:.2}", load)),
+        (Some(load), cores) => Span::styled(
+            format!("{:.2} / {}", load, cores),
+            Style::default().fg(color(load / cores as f64 * 100.0, 100.0, 200.0)),
+        ),
+        (None, _) => Span::raw("-"),
+    };
+    let temp = match snap.temperature_celsius {
+        Some(x) => Span::styled(
+            format!("{:.0}°C", x),
+            Style::default().fg(color(x, TEMP_WARN, TEMP_CRIT)),
+        ),
+        None => Span::raw("-"),
+    };
+    let network = format!(
+        "{} / {}",
+        format_rate(snap.network_rx_bytes_per_second),
+        format_rate(snap.network_tx_bytes_per_second)
+    );
+    let style = match dim {
+        true => Style::default().fg(Color::DarkGray),
+        false => Style::default(),
+    };
+    Row::new(vec![
+        Cell::from(name),
+        Cell::from(status),
+        Cell::from(gauge(snap.cpu_percent, dim)),
+        Cell::from(gauge(snap.memory_used_percent, dim)),
+        Cell::from(fs),
+        Cell::from(load),
+        Cell::from(temp),
+        Cell::from(network),
+    ])
+    .style(style)
+}
+
+/// `███████░░░  72%`, coloured by the percentage thresholds unless `dim`
+fn gauge(percent: Option<f64>, dim: bool) -> Line<'static> {
+    let Some(percent) = percent else {
+        return Line::from("-");
+    };
+    let filled = ((percent / 100.0 * GAUGE_WIDTH as f64).round() as usize).min(GAUGE_WIDTH);
+    let color = match dim {
+        true => Color::DarkGray,
+        false => threshold(percent, PERCENT_WARN, PERCENT_CRIT),
+    };
+    Line::from(vec![
+        Span::styled("█".repeat(filled), Style::default().fg(color)),
+        Span::styled(
+            "░".repeat(GAUGE_WIDTH - filled),
+            Style::default().fg(Color::DarkGray),
+        ),
+        Span::styled(format!(" {:>3.0}%", percent), Style::default().fg(color)),
+    ])
+}
+
+fn threshold(value: f64, warn: f64, crit: f64) -> Color {
+    match value {
+        x if x >= crit => Color::Red,
+        x if x >= warn => Color::Yellow,
+        _ => Color::Green,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::snapshot::FilesystemSnapshot;
+    use ratatui::backend::TestBackend;
+
+    fn overview_row(name: &str, status: NodeStatus) -> OverviewRow {
+        OverviewRow {
+            snapshot: NodeSnapshot {
+                name: name.to_string(),
+                cpu_percent: Some(72.0),
+                cpu_cores: 4,
+                load1: Some(2.5),
+                network_rx_bytes_per_second: Some(2048.0),
+                filesystems: vec![FilesystemSnapshot {
+                    mountpoint: "/data".to_string(),
+                    used_percent: 95.0,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            status,
+            status_color: Color::Green,
+        }
+    }
+
+    fn render(rows: &[&OverviewRow]) -> Buffer {
+        let mut terminal = Terminal::new(TestBackend::new(180, 6)).unwrap();
+        terminal
+            .draw(|frame| render_overview(frame, frame.area(), rows, None, "nodes"))
+            .unwrap();
+        terminal.backend().buffer().clone()
+    }
+
+    /// the screen as lines of text
+    fn draw(rows: &[&OverviewRow]) -> Vec<String> {
+        let buffer = render(rows);
+        (0..buffer.area.height)
+            .map(|y| {
+                (0..buffer.area.width)
+                    .map(|x| buffer[(x, y)].symbol())
+                    .collect::<String>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_row_per_node() {
+        let up = overview_row("web", NodeStatus::Up);
+        let unknown = OverviewRow {
+            snapshot: NodeSnapshot {
+                name: "db".to_string(),
+                ..Default::default()
+            },
+            status: NodeStatus::Unknown,
+            status_color: Color::DarkGray,
+        };
+        let lines = draw(&[&up, &unknown]);
+        assert!(lines[0].contains("nodes [enter for details, o to close]"));
+        assert!(lines[1].contains("fullest fs"));
+        let web = &lines[2];
+        assert!(web.contains("● web"));
+        assert!(web.contains("███████░░░  72%"));
+        assert!(web.contains("/data"));
+        assert!(web.contains("2.50 / 4"));
+        assert!(web.contains("2.0 KiB/s / -"));
+        // nothing known yet, a dash for every value (both network rates included)
+        let db = &lines[3];
+        assert!(db.contains("○ db"));
+        assert_eq!(db.split_whitespace().filter(|x| *x == "-").count(), 7);
+    }
+
+    #[test]
+    fn gauges_follow_the_thresholds_unless_dimmed() {
+        let color = |line: Line| line.spans[0].style.fg;
+        assert_eq!(color(gauge(Some(10.0), false)), Some(Color::Green));
+        assert_eq!(color(gauge(Some(70.0), false)), Some(Color::Yellow));
+        assert_eq!(color(gauge(Some(95.0), false)), Some(Color::Red));
+        assert_eq!(color(gauge(Some(95.0), true)), Some(Color::DarkGray));
+        // past 100% the bar stays full
+        assert_eq!(
+            gauge(Some(150.0), false).spans[0].content,
+            "█".repeat(GAUGE_WIDTH)
+        );
+        assert_eq!(gauge(None, false).to_string(), "-");
+    }
+
+    #[test]
+    fn nodes_that_stopped_answering_keep_their_values_dimmed() {
+        let bar_color = |status: NodeStatus| {
+            let row = overview_row("web", status);
+            let buffer = render(&[&row]);
+            let x = (0..buffer.area.width)
+                .find(|x| buffer[(*x, 2)].symbol() == "█")
+                .unwrap();
+            buffer[(x, 2)].fg
+        };
+        assert_eq!(bar_color(NodeStatus::Up), Color::Yellow);
+        assert_eq!(bar_color(NodeStatus::Timeout), Color::DarkGray);
+    }
+}
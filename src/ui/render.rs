@@ -1,11 +1,34 @@
-use crate::config::load::Parameters;
-use crate::handlers::process::{MetricsInterface, Service};
-use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crate::config::load::{ExportConfig, Parameters, Target};
+use crate::handlers::capture;
+use crate::handlers::discovery::{self, DiscoveryUpdate};
+use crate::handlers::export;
+use crate::handlers::fleet::{NodeState, NodeStatus};
+use crate::handlers::history::HistoryStore;
+use crate::handlers::process::{MetricsData, MetricsInterface, Service};
+use crate::handlers::prometheus;
+use crate::handlers::scraper::{self, Schedule};
+use crate::handlers::sink::{self, Output, Scrape};
+use crate::handlers::snapshot::{NodeSnapshot, format_bytes, snapshot};
+use crate::handlers::storage::Storage;
+use crate::ui::charts::{format_ago, render_charts};
+use crate::ui::overview::{OverviewRow, render_overview};
+use chrono::Local;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEventKind};
+use futures::StreamExt;
 use ratatui::layout::Flex;
 use ratatui::widgets::ListState;
 use ratatui::{prelude::*, widgets::*};
-use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
 use std::{env, io};
+use tokio::sync::{mpsc, watch};
+
+/// how often the queued scrapes are written to the data dir
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+/// how long quitting waits for the outputs to send what is still queued
+const OUTPUT_DRAIN: Duration = Duration::from_secs(5);
+/// chart windows `+` and `-` step through (5m, 15m, 1h, 6h, 24h, 7d), past the last is all
+const CHART_WINDOWS: &[f64] = &[300.0, 900.0, 3600.0, 21600.0, 86400.0, 604800.0];
 
 #[derive(Debug, Clone)]
 pub struct StatefulList<T> {
@@ -22,7 +45,7 @@ impl<T> StatefulList<T> {
     }
 
     pub fn next(&mut self) {
-        if self.items.len() > 0 {
+        if !self.items.is_empty() {
             let i = match self.state.selected() {
                 Some(i) => {
                     if i >= self.items.len() - 1 {
@@ -38,7 +61,7 @@ impl<T> StatefulList<T> {
     }
 
     pub fn previous(&mut self) {
-        if self.items.len() > 0 {
+        if !self.items.is_empty() {
             let i = match self.state.selected() {
                 Some(i) => {
                     if i == 0 {
@@ -64,106 +87,551 @@ pub struct App {
     pub network: Vec<String>,
     pub disk: Vec<String>,
     pub info: Vec<String>,
-    pub scrape_duration: u64,
     pub show_popup: bool,
+    /// trend charts instead of the current values
+    pub show_charts: bool,
+    /// seconds the charts cover, none shows all of the history
+    pub chart_window: Option<f64>,
+    /// a row per node instead of the selected node's panels
+    pub show_overview: bool,
+    /// overview row per node, a node's row is rebuilt once it is scraped again
+    pub overview_rows: HashMap<String, OverviewRow>,
+    /// cursor the overview rows were built for
+    pub overview_cursor: Option<f64>,
+    pub states: HashMap<String, NodeState>,
+    /// nodes shown in the list (federated targets are replaced by their instances)
+    pub targets: Vec<Target>,
+    pub filter: String,
+    pub filter_mode: bool,
+    pub group_by: Option<String>,
+    /// targets from the config file, always listed first
+    pub static_targets: Vec<Target>,
+    /// targets per discovery source
+    pub discovered: BTreeMap<String, Vec<Target>>,
+    /// last error per discovery source, cleared by its next successful refresh
+    pub discovery_errors: BTreeMap<String, String>,
+    pub history: HistoryStore,
+    /// timestamp the panels show when looking back, none follows the live data
+    pub cursor: Option<f64>,
+    pub export: ExportConfig,
+    /// path written by the last export, or its error
+    pub last_export: Option<Result<String, String>>,
+    /// on-disk copy of the history, when a data dir is set
+    pub storage: Option<Storage>,
+    /// last error writing the data dir, cleared by the next successful flush
+    pub storage_error: Option<String>,
+    /// outputs every scrape is forwarded to (remote_write, influxdb, otlp)
+    pub outputs: Vec<sink::Handle>,
 }
 
 impl App {
     pub fn new(name: String, params: Parameters) -> Self {
         let title = format!("[ {} ]", name);
+        // with a prometheus source the nodes come from the api, not the config
+        let static_targets = match params.prometheus {
+            Some(_) => vec![],
+            None => params.enabled_targets(),
+        };
+        let targets: Vec<Target> = static_targets
+            .iter()
+            .filter(|x| !x.split_by_instance)
+            .cloned()
+            .collect();
         Self {
             name: title.clone(),
-            nodes: StatefulList::with_items(params.servers),
+            nodes: StatefulList::with_items(targets.iter().map(|x| x.url.clone()).collect()),
             cpu: vec![],
             memory: vec![],
             network: vec![],
             disk: vec![],
             info: vec![],
-            scrape_duration: params.scrape_duration,
             show_popup: false,
+            show_charts: false,
+            chart_window: None,
+            show_overview: false,
+            overview_rows: HashMap::new(),
+            overview_cursor: None,
+            states: HashMap::new(),
+            static_targets,
+            targets,
+            filter: String::new(),
+            filter_mode: false,
+            group_by: None,
+            discovered: BTreeMap::new(),
+            discovery_errors: BTreeMap::new(),
+            history: HistoryStore::new(params.history.clone()),
+            cursor: None,
+            export: params.export.clone(),
+            last_export: None,
+            storage: None,
+            storage_error: None,
+            outputs: vec![],
+        }
+    }
+
+    /// write the scrapes queued since the last flush to the data dir
+    pub fn flush_storage(&mut self) {
+        let Some(storage) = self.storage.as_mut() else {
+            return;
+        };
+        let now = Local::now().timestamp_millis() as f64 / 1000.0;
+        self.storage_error = storage.flush(now).err().map(|e| e.to_string());
+    }
+
+    /// url of the selected node (empty when the filter hides every node)
+    pub fn selected_node(&self) -> String {
+        let selected_id = self.nodes.state.selected().unwrap_or(0);
+        self.nodes
+            .items
+            .get(selected_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn target(&self, url: &str) -> Option<&Target> {
+        self.targets.iter().find(|x| x.url == url)
+    }
+
+    pub fn display_name(&self, url: &str) -> String {
+        match self.target(url) {
+            Some(target) => target.display_name(),
+            None => url.to_string(),
+        }
+    }
+
+    /// rebuild the node list from the filter and grouping, keeping the selection if still visible
+    pub fn apply_view(&mut self) {
+        let current = self.selected_node();
+        let filter = self.filter.to_lowercase();
+        let mut targets: Vec<&Target> = self
+            .targets
+            .iter()
+            .filter(|x| filter.is_empty() || matches_filter(x, &filter))
+            .collect();
+        if let Some(key) = &self.group_by {
+            // stable sort, config order is kept inside each group
+            targets.sort_by_key(|x| x.labels.get(key).cloned().unwrap_or_default());
+        }
+        self.nodes.items = targets.iter().map(|x| x.url.clone()).collect();
+        let index = self
+            .nodes
+            .items
+            .iter()
+            .position(|x| *x == current)
+            .unwrap_or(0);
+        self.nodes.state.select(Some(index));
+        self.show_selected();
+    }
+
+    /// apply the result of a discovery source, a failed refresh keeps the previous targets
+    pub fn set_discovered(&mut self, update: DiscoveryUpdate) {
+        match update.result {
+            Ok(targets) => {
+                self.discovery_errors.remove(&update.source);
+                self.discovered.insert(update.source, targets);
+            }
+            Err(err) => {
+                self.discovery_errors.insert(update.source, err);
+                return;
+            }
         }
+        self.targets = self
+            .all_targets()
+            .into_iter()
+            .filter(|x| !x.split_by_instance)
+            .collect();
+        let urls: HashSet<&String> = self.targets.iter().map(|x| &x.url).collect();
+        self.states.retain(|k, _| urls.contains(k));
+        self.history
+            .retain_nodes(|x| urls.iter().any(|url| *url == x));
+        self.apply_view();
+    }
+
+    /// targets for the scraper, nodes read through another target are left out
+    pub fn scrape_targets(&self) -> Vec<Target> {
+        self.all_targets()
+            .into_iter()
+            .filter(|x| x.via.is_none())
+            .collect()
+    }
+
+    /// static and discovered targets, static ones win over discovered ones with the same url
+    fn all_targets(&self) -> Vec<Target> {
+        let mut seen = HashSet::new();
+        self.static_targets
+            .iter()
+            .chain(self.discovered.values().flatten())
+            .filter(|x| x.enabled && seen.insert(x.url.clone()))
+            .cloned()
+            .collect()
+    }
+
+    /// cycle the grouping through every label key in use (and back to none)
+    pub fn next_group(&mut self) {
+        let mut keys: Vec<String> = self
+            .targets
+            .iter()
+            .flat_map(|x| x.labels.keys().cloned())
+            .collect();
+        keys.sort();
+        keys.dedup();
+        self.group_by = match &self.group_by {
+            None => keys.first().cloned(),
+            Some(current) => keys.iter().skip_while(|x| *x != current).nth(1).cloned(),
+        };
+        self.apply_view();
+    }
+
+    /// the time the panels show, the cursor or the newest data (a sped up replay runs ahead of the clock)
+    pub fn view_time(&self, node: &str) -> f64 {
+        let now = Local::now().timestamp_millis() as f64 / 1000.0;
+        match self.cursor {
+            Some(ts) => ts,
+            None => self.history.span(node).map(|x| x.1.max(now)).unwrap_or(now),
+        }
+    }
+
+    /// state of a node as the panels show it (as of the cursor when looking back)
+    pub fn node_snapshot(&self, node: &str) -> NodeSnapshot {
+        let name = self.display_name(node);
+        let status = self.status(node).to_string();
+        snapshot(&self.history, node, &name, &status, self.view_time(node))
+    }
+
+    pub fn status(&self, node: &str) -> NodeStatus {
+        self.states
+            .get(node)
+            .map(|x| x.status)
+            .unwrap_or(NodeStatus::Unknown)
+    }
+
+    /// build the overview rows of the listed nodes that were scraped (or renamed) since, or
+    /// all of them once the cursor moved
+    pub fn refresh_overview(&mut self) {
+        if self.overview_cursor != self.cursor {
+            self.overview_rows.clear();
+            self.overview_cursor = self.cursor;
+        }
+        let listed: HashSet<String> = self.nodes.items.iter().cloned().collect();
+        self.overview_rows.retain(|node, _| listed.contains(node));
+        for node in listed {
+            let name = self.display_name(&node);
+            if let Some(row) = self.overview_rows.get(&node)
+                && row.snapshot.name == name
+            {
+                continue;
+            }
+            let status = self.status(&node);
+            let row = OverviewRow {
+                snapshot: self.node_snapshot(&node),
+                status,
+                status_color: status_color(status),
+            };
+            self.overview_rows.insert(node, row);
+        }
+    }
+
+    /// write a snapshot of the selected node (or every node) as of the view time
+    pub fn export(&mut self, all: bool) {
+        let selected = self.selected_node();
+        let nodes: Vec<String> = match all {
+            true => self.targets.iter().map(|x| x.url.clone()).collect(),
+            false if selected.is_empty() => vec![],
+            false => vec![selected.clone()],
+        };
+        let snapshots: Vec<NodeSnapshot> = nodes.iter().map(|x| self.node_snapshot(x)).collect();
+        let label = match all {
+            true => "all".to_string(),
+            false => self.display_name(&selected),
+        };
+        let result = export::write(&snapshots, &self.export, &label);
+        self.last_export = Some(
+            result
+                .map(|x| x.display().to_string())
+                .map_err(|e| e.to_string()),
+        );
+    }
+
+    /// move the cursor to the previous stored scrape of the selected node
+    pub fn step_back(&mut self) {
+        let steps = self.history.steps(&self.selected_node());
+        let current = self.cursor.unwrap_or(f64::INFINITY);
+        if let Some(ts) = steps.iter().rev().find(|x| **x < current) {
+            self.cursor = Some(*ts);
+            self.show_selected();
+        }
+    }
+
+    /// move the cursor to the next stored scrape, past the newest one is live again
+    pub fn step_forward(&mut self) {
+        let Some(current) = self.cursor else {
+            return;
+        };
+        let steps = self.history.steps(&self.selected_node());
+        self.cursor = steps.iter().find(|x| **x > current).copied();
+        if self.cursor == steps.last().copied() {
+            self.cursor = None;
+        }
+        self.show_selected();
+    }
+
+    pub fn go_live(&mut self) {
+        self.cursor = None;
+        self.show_selected();
+    }
+
+    /// next shorter chart window, from all of the history the first one shorter than it
+    pub fn zoom_in(&mut self) {
+        let node = self.selected_node();
+        let current = match (self.chart_window, self.history.span(&node)) {
+            (Some(window), _) => window,
+            (None, Some((first, _))) => self.view_time(&node) - first,
+            (None, None) => f64::INFINITY,
+        };
+        let shorter = CHART_WINDOWS.iter().rev().find(|x| **x < current);
+        self.chart_window = Some(*shorter.unwrap_or(&CHART_WINDOWS[0]));
+    }
+
+    /// next longer chart window, all of the history after the last one
+    pub fn zoom_out(&mut self) {
+        if let Some(window) = self.chart_window {
+            self.chart_window = CHART_WINDOWS.iter().find(|x| **x > window).copied();
+        }
+    }
+
+    /// panels of the selected node, as of the cursor when looking back
+    pub fn show_selected(&mut self) {
+        let data = match self.cursor {
+            Some(ts) => self.data_at(ts),
+            None => self
+                .states
+                .get(&self.selected_node())
+                .and_then(|x| x.data.clone()),
+        };
+        match data {
+            Some(data) => {
+                self.cpu = data.cpu;
+                self.memory = data.memory;
+                self.network = data.network;
+                self.disk = data.disk;
+                self.info = data.info;
+            }
+            None => {
+                self.cpu = vec![];
+                self.memory = vec![];
+                self.network = vec![];
+                self.disk = vec![];
+                self.info = vec![];
+            }
+        }
+    }
+
+    /// the panels rebuilt from the history, the scrape before `ts` primes the cpu counters
+    fn data_at(&self, ts: f64) -> Option<MetricsData> {
+        let node = self.selected_node();
+        let mut service = Service::new();
+        let steps = self.history.steps(&node);
+        if let Some(previous) = steps.iter().rev().find(|x| **x < ts) {
+            let _ = service.get_all_metrics(&self.history.at(&node, *previous));
+        }
+        let families = self.history.at(&node, ts);
+        if families.is_empty() {
+            return None;
+        }
+        service.get_all_metrics(&families).ok()
     }
 }
 
 /// run the app (event loop)
-pub async fn run_app<B: Backend>(terminal: &mut Terminal<B>, app: &mut App) -> io::Result<()> {
-    let tick_rate = Duration::from_secs(app.scrape_duration);
-    let mut last_tick = Instant::now();
-    let mut svc = Service::new();
-    let mut changed = false;
-
-    // get all metrics on startup
-    let res_vec_metrics = svc.scrape(app.nodes.items[0].clone()).await;
-    match res_vec_metrics {
-        Ok(metrics) => {
-            let res_data = svc.get_all_metrics(metrics);
-            match res_data {
-                Ok(data) => {
-                    app.cpu = data.cpu;
-                    app.memory = data.memory;
-                    app.network = data.network;
-                    app.disk = data.disk;
-                    app.info = data.info;
-                }
-                Err(_) => {}
+/// scraping runs in a background task, results arrive over a channel so input and redraws never wait on the network
+pub async fn run_app<B: Backend>(
+    terminal: &mut Terminal<B>,
+    app: &mut App,
+    mut schedule: Schedule,
+) -> io::Result<()> {
+    let (tx, mut rx) = mpsc::channel(64);
+    let (selected_tx, selected_rx) = watch::channel(app.selected_node());
+    let (targets_tx, targets_rx) = watch::channel(app.scrape_targets());
+    let (discovery_tx, mut discovery_rx) = mpsc::channel(16);
+    let (step_tx, step_rx) = mpsc::channel(16);
+    let mut discovery_tasks = vec![];
+    let mut output_tasks = vec![];
+    for output in Output::from_params(&schedule.params) {
+        let (handle, task) = sink::Handle::spawn(output, schedule.params.clone());
+        app.outputs.push(handle);
+        output_tasks.push(task);
+    }
+    let scrape_task = match (schedule.replay.take(), schedule.params.prometheus.clone()) {
+        (Some(replay), _) => {
+            let step = replay.step.then_some(step_rx);
+            tokio::spawn(capture::replay(replay, step, tx, discovery_tx))
+        }
+        (None, Some(config)) => {
+            let params = schedule.params.clone();
+            tokio::spawn(prometheus::run(config, params, tx, discovery_tx))
+        }
+        (None, None) => {
+            for config in schedule.params.file_sd_configs.iter() {
+                discovery_tasks.push(tokio::spawn(discovery::run_file_sd(
+                    config.clone(),
+                    discovery_tx.clone(),
+                )));
+            }
+            for config in schedule.params.dns_sd_configs.iter() {
+                discovery_tasks.push(tokio::spawn(discovery::run_dns_sd(
+                    config.clone(),
+                    discovery_tx.clone(),
+                )));
             }
+            tokio::spawn(scraper::run(
+                schedule,
+                selected_rx,
+                targets_rx,
+                tx,
+                discovery_tx,
+            ))
         }
-        Err(_) => {}
-    }
+    };
+    let mut events = EventStream::new();
+    // redraw regularly so the status line ages even when nothing arrives
+    let mut redraw = tokio::time::interval(Duration::from_secs(1));
+    let mut flush = tokio::time::interval(FLUSH_INTERVAL);
 
-    loop {
-        terminal.draw(|f| render_ui(f, app))?;
-        let timeout = tick_rate.saturating_sub(last_tick.elapsed());
-        // event handling
-        if event::poll(timeout)? {
-            if let Event::Key(key) = event::read()? {
-                if key.kind == KeyEventKind::Press {
-                    use KeyCode::*;
+    let res = loop {
+        if let Err(err) = terminal.draw(|f| render_ui(f, app)) {
+            break Err(err);
+        }
+        tokio::select! {
+            event = events.next() => {
+                let key = match event {
+                    Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => key,
+                    Some(Ok(_)) => continue,
+                    Some(Err(err)) => break Err(err),
+                    None => break Ok(()),
+                };
+                use KeyCode::*;
+                if app.filter_mode {
                     match key.code {
-                        Char('q') | Esc => return Ok(()),
-                        Down => {
-                            app.nodes.next();
-                            app.show_popup = false;
-                            changed = true;
+                        Char(c) => app.filter.push(c),
+                        Backspace => {
+                            app.filter.pop();
                         }
-                        Up => {
-                            app.nodes.previous();
-                            app.show_popup = false;
-                            changed = true;
-                        }
-                        Char('p') => {
-                            app.show_popup = !app.show_popup;
+                        Esc => {
+                            app.filter.clear();
+                            app.filter_mode = false;
                         }
+                        Enter => app.filter_mode = false,
                         _ => {}
                     }
+                    app.apply_view();
+                    let _ = selected_tx.send(app.selected_node());
+                    continue;
+                }
+                match key.code {
+                    Char('q') | Esc => break Ok(()),
+                    Char('/') => {
+                        app.filter_mode = true;
+                        app.show_popup = false;
+                    }
+                    Char('g') => {
+                        app.next_group();
+                        let _ = selected_tx.send(app.selected_node());
+                    }
+                    Down => {
+                        app.nodes.next();
+                        app.show_popup = false;
+                        app.show_selected();
+                        let _ = selected_tx.send(app.selected_node());
+                    }
+                    Up => {
+                        app.nodes.previous();
+                        app.show_popup = false;
+                        app.show_selected();
+                        let _ = selected_tx.send(app.selected_node());
+                    }
+                    Char('p') => {
+                        app.show_popup = !app.show_popup;
+                    }
+                    Char('c') => {
+                        app.show_charts = !app.show_charts;
+                    }
+                    Char('o') => {
+                        app.show_overview = !app.show_overview;
+                        app.show_popup = false;
+                    }
+                    Enter if app.show_overview => app.show_overview = false,
+                    Char('+') | Char('=') => app.zoom_in(),
+                    Char('-') => app.zoom_out(),
+                    Char('n') => {
+                        let _ = step_tx.try_send(());
+                    }
+                    Left => app.step_back(),
+                    Right => app.step_forward(),
+                    Char('l') => app.go_live(),
+                    Char('e') => app.export(false),
+                    Char('E') => app.export(true),
+                    _ => {}
                 }
             }
-        }
-
-        if last_tick.elapsed() >= tick_rate || changed {
-            let selected_id = app.nodes.state.selected().unwrap();
-            let node = app.nodes.items[selected_id].to_string();
-            let res_vec_metrics = svc.scrape(node.clone()).await;
-            match res_vec_metrics {
-                Ok(metrics) => {
-                    let res_data = svc.get_all_metrics(metrics);
-                    match res_data {
-                        Ok(data) => {
-                            app.cpu = data.cpu;
-                            app.memory = data.memory;
-                            app.network = data.network;
-                            app.disk = data.disk;
-                            app.info = data.info;
-                        }
-                        Err(_) => {}
+            Some(update) = rx.recv() => {
+                let now = Local::now().timestamp_millis() as f64 / 1000.0;
+                if let Ok(families) = &update.result {
+                    app.history.ingest(&update.node, families, now);
+                    if let Some(storage) = app.storage.as_mut() {
+                        storage.append(&update.node, families, now);
                     }
                 }
-                Err(_) => {}
+                if !app.outputs.is_empty() {
+                    let scrape = Scrape {
+                        node: update.node.clone(),
+                        labels: app
+                            .target(&update.node)
+                            .map(|x| x.labels.clone())
+                            .unwrap_or_default(),
+                        families: update.result.as_ref().cloned().unwrap_or_default(),
+                        up: update.result.is_ok(),
+                        ts: now,
+                    };
+                    for output in app.outputs.iter_mut() {
+                        output.forward(scrape.clone());
+                    }
+                }
+                app.states
+                    .entry(update.node.clone())
+                    .or_insert_with(NodeState::new)
+                    .update(update.result);
+                app.overview_rows.remove(&update.node);
+                // looking back, the panels stay on the cursor
+                if update.node == app.selected_node() && app.cursor.is_none() {
+                    app.show_selected();
+                }
+            }
+            Some(update) = discovery_rx.recv() => {
+                app.set_discovered(update);
+                // discovery refreshes mostly repeat the same list, only rebuild on a change
+                let targets = app.scrape_targets();
+                targets_tx.send_if_modified(|current| {
+                    let modified = *current != targets;
+                    if modified {
+                        *current = targets;
+                    }
+                    modified
+                });
+                let _ = selected_tx.send(app.selected_node());
             }
-            changed = false;
+            _ = redraw.tick() => {}
+            _ = flush.tick() => app.flush_storage(),
         }
-        last_tick = Instant::now();
+    };
+    app.flush_storage();
+    scrape_task.abort();
+    for task in discovery_tasks {
+        task.abort();
     }
+    // closing the channels makes the outputs send what they still have queued
+    app.outputs.clear();
+    let _ = tokio::time::timeout(OUTPUT_DRAIN, futures::future::join_all(output_tasks)).await;
+    res
 }
 
 /// ui rendering
@@ -178,12 +646,29 @@ pub fn render_ui(frame: &mut Frame, app: &mut App) {
                 Constraint::Length(3),
                 Constraint::Min(2),
                 Constraint::Length(3),
+                Constraint::Length(3),
             ]
             .as_ref(),
         )
         .split(size);
 
-    let title = Paragraph::new(app.name.as_str())
+    let mut spans = vec![Span::raw(app.name.clone())];
+    if let Some(ts) = app.cursor {
+        let age = (Local::now().timestamp_millis() as f64 / 1000.0 - ts).max(1.0);
+        let at = chrono::DateTime::from_timestamp_millis((ts * 1000.0) as i64)
+            .map(|x| x.with_timezone(&Local).format("%H:%M:%S").to_string())
+            .unwrap_or_default();
+        spans.push(Span::raw("  "));
+        spans.push(Span::styled(
+            format!(
+                " viewing T{} ({}), ◀ ▶ to move, l for live ",
+                format_ago(age),
+                at
+            ),
+            Style::default().fg(Color::Black).bg(Color::Yellow),
+        ));
+    }
+    let title = Paragraph::new(Line::from(spans))
         .style(
             Style::default()
                 .fg(Color::White)
@@ -213,17 +698,36 @@ pub fn render_ui(frame: &mut Frame, app: &mut App) {
         )
         .split(chunks[1]);
 
-    let (node, cpu, memory, network, disk) = render_complex_view(app);
-    frame.render_stateful_widget(node, body[0], &mut app.nodes.state.clone());
-    frame.render_widget(cpu, body[1]);
-    frame.render_widget(memory, body[2]);
-    frame.render_widget(network, body[3]);
-    frame.render_widget(disk, body[4]);
+    if app.show_overview {
+        app.refresh_overview();
+        let rows: Vec<&OverviewRow> = app
+            .nodes
+            .items
+            .iter()
+            .filter_map(|x| app.overview_rows.get(x))
+            .collect();
+        let selected = app.nodes.state.selected();
+        render_overview(frame, chunks[1], &rows, selected, &nodes_title(app));
+    } else if app.show_charts {
+        let (node, ..) = render_complex_view(app);
+        frame.render_stateful_widget(node, body[0], &mut app.nodes.state.clone());
+        let node = app.selected_node();
+        let to = app.view_time(&node);
+        render_charts(frame, &body[1..], &app.history, &node, to, app.chart_window);
+    } else {
+        let (node, cpu, memory, network, disk) = render_complex_view(app);
+        frame.render_stateful_widget(node, body[0], &mut app.nodes.state.clone());
+        frame.render_widget(cpu, body[1]);
+        frame.render_widget(memory, body[2]);
+        frame.render_widget(network, body[3]);
+        frame.render_widget(disk, body[4]);
+    }
+    frame.render_widget(render_status(app), chunks[2]);
 
     let version = env!["CARGO_PKG_VERSION"];
     let name = env!["CARGO_PKG_NAME"];
     let title = format!(
-        "{} {} 2025 [ use ▲ ▼  to change node, p to toggle node details popup, q to quit ]",
+        "{} {} 2025 [ use ▲ ▼  to change node, / to filter, g to group, o for the overview, c to toggle charts, + - to zoom them, ◀ ▶ to look back, l for live, e / E to export node / all, p to toggle node details popup, q to quit ]",
         name, version
     );
 
@@ -237,12 +741,11 @@ pub fn render_ui(frame: &mut Frame, app: &mut App) {
                 .title("info")
                 .border_type(BorderType::Plain),
         );
-    frame.render_widget(copyright, chunks[2]);
+    frame.render_widget(copyright, chunks[3]);
 
     // prepare popup rendering
     if app.show_popup {
-        let index = app.nodes.state.selected().unwrap();
-        let name = app.nodes.items[index].clone();
+        let name = app.display_name(&app.selected_node());
         let info = format!(
             "\n{}",
             app.info
@@ -272,7 +775,7 @@ fn render_complex_view<'a>(app: &mut App) -> (List<'a>, List<'a>, List<'a>, List
     let nodes = Block::default()
         .borders(Borders::ALL)
         .style(Style::default().fg(Color::White))
-        .title("nodes")
+        .title(nodes_title(app))
         .border_type(BorderType::Plain);
 
     let cpu = Block::default()
@@ -301,13 +804,48 @@ fn render_complex_view<'a>(app: &mut App) -> (List<'a>, List<'a>, List<'a>, List
 
     let mut node_list_items = vec![];
     for item in app.nodes.items.iter() {
-        node_list_items.push(ListItem::new(Line::from(vec![Span::styled(
-            format!(
-                "{}",
-                item.to_string().split("://").nth(1).unwrap_or("error")
-            ),
-            Style::default(),
-        )])));
+        let status = app
+            .states
+            .get(item)
+            .map(|x| x.status)
+            .unwrap_or(NodeStatus::Unknown);
+        let glyph = match status {
+            NodeStatus::Unknown => "○ ",
+            _ => "● ",
+        };
+        let mut spans = vec![Span::styled(
+            glyph,
+            Style::default().fg(status_color(status)),
+        )];
+        if let Some(target) = app.target(item) {
+            if let Some(key) = &app.group_by {
+                let group = target.labels.get(key).map(|x| x.as_str()).unwrap_or("-");
+                spans.push(Span::styled(
+                    format!("{} │ ", group),
+                    Style::default().fg(Color::Cyan),
+                ));
+            }
+            spans.push(Span::styled(target.display_name(), Style::default()));
+            let labels = target
+                .labels
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<String>>()
+                .join(" ");
+            if !labels.is_empty() {
+                spans.push(Span::styled(
+                    format!(" {}", labels),
+                    Style::default().fg(Color::DarkGray),
+                ));
+            }
+        }
+        if !matches!(status, NodeStatus::Up | NodeStatus::Unknown) {
+            spans.push(Span::styled(
+                format!(" [{}]", status),
+                Style::default().fg(status_color(status)),
+            ));
+        }
+        node_list_items.push(ListItem::new(Line::from(spans)));
     }
 
     let mut cpu_list_items = vec![];
@@ -375,6 +913,137 @@ fn render_complex_view<'a>(app: &mut App) -> (List<'a>, List<'a>, List<'a>, List
     (node_list, cpu_list, memory_list, network_list, disk_list)
 }
 
+/// render the scrape status line for the selected node
+fn render_status<'a>(app: &App) -> Paragraph<'a> {
+    let node = app.selected_node();
+    let (status, last_success, last_error) = match app.states.get(&node) {
+        Some(state) => (
+            state.status,
+            state
+                .last_success
+                .map(|x| x.format("%H:%M:%S").to_string())
+                .unwrap_or("never".to_string()),
+            state.last_error.clone().unwrap_or("-".to_string()),
+        ),
+        None => (NodeStatus::Unknown, "never".to_string(), "-".to_string()),
+    };
+    let (series, points, bytes) = app.history.stats();
+    let mut spans = vec![
+        Span::styled(
+            format!("{} ", status),
+            Style::default()
+                .fg(status_color(status))
+                .add_modifier(Modifier::BOLD),
+        ),
+        Span::raw(format!(
+            "| last success {} | last error {} | history {} series, {} points, {}",
+            last_success,
+            last_error,
+            series,
+            points,
+            format_bytes(bytes as f64)
+        )),
+    ];
+    if app.history.dropped_series() > 0 {
+        spans.push(Span::styled(
+            format!(
+                " ({} series dropped, max_series reached)",
+                app.history.dropped_series()
+            ),
+            Style::default().fg(Color::Yellow),
+        ));
+    }
+    match &app.last_export {
+        Some(Ok(path)) => spans.push(Span::styled(
+            format!(" | exported {}", path),
+            Style::default().fg(Color::Green),
+        )),
+        Some(Err(err)) => spans.push(Span::styled(
+            format!(" | export {}", err),
+            Style::default().fg(Color::Yellow),
+        )),
+        None => {}
+    }
+    for output in app.outputs.iter() {
+        let status = output.status.borrow();
+        let dropped = status.dropped + output.overflow;
+        let mut text = format!(
+            " | {} {} sent, {} queued",
+            output.name, status.sent, status.pending
+        );
+        if dropped > 0 {
+            text.push_str(&format!(", {} dropped", dropped));
+        }
+        match &status.last_error {
+            Some(err) => spans.push(Span::styled(
+                format!("{}, {}", text, err),
+                Style::default().fg(Color::Yellow),
+            )),
+            None => spans.push(Span::raw(text)),
+        }
+    }
+    if let Some(err) = &app.storage_error {
+        spans.push(Span::styled(
+            format!(" | data dir {}", err),
+            Style::default().fg(Color::Yellow),
+        ));
+    }
+    if let Some(err) = app.discovery_errors.values().next() {
+        spans.push(Span::styled(
+            format!(" | discovery {}", err),
+            Style::default().fg(Color::Yellow),
+        ));
+    }
+    Paragraph::new(Line::from(spans))
+        .style(Style::default().fg(Color::White))
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .style(Style::default().fg(Color::White))
+                .title(match app.target(&node).and_then(|x| x.via.as_ref()) {
+                    Some(via) => format!("status [{} via {}]", app.display_name(&node), via),
+                    None => format!("status [{}]", app.display_name(&node)),
+                })
+                .border_type(BorderType::Plain),
+        )
+}
+
+/// "nodes" with the grouping and filter in use
+fn nodes_title(app: &App) -> String {
+    let mut title = "nodes".to_string();
+    if let Some(key) = &app.group_by {
+        title = format!("{} [group {}]", title, key);
+    }
+    if app.filter_mode || !app.filter.is_empty() {
+        let cursor = if app.filter_mode { "_" } else { "" };
+        title = format!("{} [/{}{}]", title, app.filter, cursor);
+    }
+    title
+}
+
+/// case insensitive match on name, url and `key=value` labels
+fn matches_filter(target: &Target, filter: &str) -> bool {
+    target.display_name().to_lowercase().contains(filter)
+        || target.url.to_lowercase().contains(filter)
+        || target
+            .labels
+            .iter()
+            .any(|(k, v)| format!("{}={}", k, v).to_lowercase().contains(filter))
+}
+
+fn status_color(status: NodeStatus) -> Color {
+    match status {
+        NodeStatus::Up => Color::Green,
+        NodeStatus::Unknown => Color::DarkGray,
+        NodeStatus::Timeout
+        | NodeStatus::HttpError(_)
+        | NodeStatus::ParseError
+        | NodeStatus::AuthError
+        | NodeStatus::ConfigError => Color::Yellow,
+        NodeStatus::Down => Color::Red,
+    }
+}
+
 /// helper function to create a centered rect using up certain percentage of the available rect `r`
 fn popup_area(area: Rect, percent_x: u16, percent_y: u16) -> Rect {
     let vertical = Layout::vertical([Constraint::Percentage(percent_y)]).flex(Flex::Center);
@@ -383,3 +1052,66 @@ fn popup_area(area: Rect, percent_x: u16, percent_y: u16) -> Rect {
     let [area] = horizontal.areas(area);
     area
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::exposition::{self, Format};
+
+    #[test]
+    fn a_failed_refresh_keeps_the_discovered_targets() {
+        let config =
+            r#"{"name": "test", "description": "", "log_level": "info", "scrape_duration": 5}"#;
+        let mut app = App::new("test".to_string(), serde_json::from_str(config).unwrap());
+        app.set_discovered(DiscoveryUpdate {
+            source: "nodes.json".to_string(),
+            result: Ok(vec![Target::from_url("http://10.0.0.1:9100")]),
+        });
+        assert_eq!(app.targets.len(), 1);
+        app.set_discovered(DiscoveryUpdate {
+            source: "nodes.json".to_string(),
+            result: Err("expected value at line 1 column 2".to_string()),
+        });
+        assert_eq!(app.targets.len(), 1);
+        assert_eq!(app.targets[0].url, "http://10.0.0.1:9100");
+        assert!(app.discovery_errors.contains_key("nodes.json"));
+        // the next good read replaces the targets and clears the error
+        app.set_discovered(DiscoveryUpdate {
+            source: "nodes.json".to_string(),
+            result: Ok(vec![]),
+        });
+        assert!(app.targets.is_empty());
+        assert!(app.discovery_errors.is_empty());
+    }
+
+    #[test]
+    fn stepping_through_the_stored_scrapes() {
+        let config = r#"{"name": "test", "description": "", "log_level": "info", "scrape_duration": 5, "servers": ["http://a:9100"]}"#;
+        let mut app = App::new("test".to_string(), serde_json::from_str(config).unwrap());
+        let node = app.selected_node();
+        for ts in [10.0, 20.0, 30.0] {
+            let body = format!("# TYPE node_load1 gauge\nnode_load1 {}\n", ts);
+            let families = exposition::parse(&body, Format::Prometheus).unwrap();
+            app.history.ingest(&node, &families, ts);
+        }
+        // live, nothing to step forward to
+        app.step_forward();
+        assert_eq!(app.cursor, None);
+        app.step_back();
+        assert_eq!(app.cursor, Some(30.0));
+        app.step_back();
+        app.step_back();
+        assert_eq!(app.cursor, Some(10.0));
+        // the oldest scrape is as far back as it goes
+        app.step_back();
+        assert_eq!(app.cursor, Some(10.0));
+        app.step_forward();
+        assert_eq!(app.cursor, Some(20.0));
+        // past the newest scrape is live again
+        app.step_forward();
+        assert_eq!(app.cursor, None);
+        app.step_back();
+        app.go_live();
+        assert_eq!(app.cursor, None);
+    }
+}